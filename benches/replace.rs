@@ -0,0 +1,253 @@
+// SPDX-FileCopyrightText: 2025 Robin Vobruba <hoijui.quaero@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Benchmarks the replacement of variables in large inputs,
+//! e.g. SQL dumps, which contain long stretches of text without any variables.
+//!
+//! Run with `cargo bench`.
+//!
+//! The `baseline` benchmarks run the replacement as it was done
+//! before the input got tokenized, for comparison.
+//! Streams are read in chunks, like files are by the binary.
+
+// Our variable syntax looks a lot like rust formatting arguments
+#![allow(clippy::literal_string_with_formatting_args)]
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use repvar::replacer::{replace_in_stream, replace_in_string, Settings, Template};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+
+/// Generates an SQL dump like input of about `lines` lines,
+/// with a variable every `every` lines.
+fn sql_dump(lines: usize, every: usize) -> String {
+    let mut dump = String::new();
+    for line in 0..lines {
+        let schema = if line % every == 0 {
+            "${SCHEMA}"
+        } else {
+            "public"
+        };
+        writeln!(
+            dump,
+            "INSERT INTO {schema}.users (id, name, email, bio) VALUES \
+            ({line}, 'User {line}', 'user{line}@example.com', \
+            'Likes long walks, $5 lunches and the occasional {{curly}} brace.');"
+        )
+        .expect("Writing to a String never fails");
+    }
+    dump
+}
+
+/// The replacement as it was done before the input got tokenized:
+/// A state machine going through the input one character at a time,
+/// line by line, which only knows `${KEY}` and `$${KEY}`.
+/// It is kept as it was (including dropping the character after a lone `$`),
+/// as a reference for the speed of [`replace_in_stream`].
+mod baseline {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::io::{self, BufRead, Write};
+
+    enum State {
+        Text,
+        Dollar1,
+        Dollar2,
+        Key,
+    }
+
+    fn replacement(key: &str, vars: &HashMap<String, String>) -> (bool, String) {
+        vars.get(key)
+            .map_or_else(|| (false, format!("${{{key}}}")), |val| (true, val.clone()))
+    }
+
+    fn replace_in_string<'t>(line: &'t str, vars: &HashMap<String, String>) -> Cow<'t, str> {
+        let mut state = State::Text;
+        let mut key = String::with_capacity(64);
+        let mut buff_special = String::with_capacity(5);
+        let mut buff_out = String::with_capacity(line.len() * 3 / 2);
+        let mut replaced = false;
+        for chr in line.chars() {
+            match state {
+                State::Text => {
+                    if chr == '$' {
+                        state = State::Dollar1;
+                        buff_special.push(chr);
+                    } else {
+                        buff_out.push(chr);
+                    }
+                }
+                State::Dollar1 => {
+                    if chr == '$' {
+                        state = State::Dollar2;
+                        buff_special.push(chr);
+                    } else if chr == '{' {
+                        state = State::Key;
+                        buff_special.clear();
+                    } else {
+                        state = State::Text;
+                        buff_out.push_str(&buff_special);
+                        buff_special.clear();
+                    }
+                }
+                State::Dollar2 => {
+                    buff_special.push(chr);
+                    if chr != '$' {
+                        if chr == '{' {
+                            buff_special.remove(0);
+                            replaced = true;
+                        }
+                        state = State::Text;
+                        buff_out.push_str(&buff_special);
+                        buff_special.clear();
+                    }
+                }
+                State::Key => {
+                    if chr == '}' {
+                        let (found, value) = replacement(&key, vars);
+                        replaced = replaced || found;
+                        buff_out.push_str(&value);
+                        key.clear();
+                        state = State::Text;
+                    } else {
+                        key.push(chr);
+                    }
+                }
+            }
+        }
+
+        if replaced {
+            buff_out.push_str(&buff_special);
+            if matches!(state, State::Key) {
+                buff_out.push_str("${");
+            }
+            buff_out.push_str(&key);
+            Cow::Owned(buff_out)
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+
+    pub fn replace_in_stream(
+        reader: &mut impl BufRead,
+        writer: &mut impl Write,
+        vars: &HashMap<String, String>,
+    ) -> io::Result<()> {
+        for line in cli_utils::lines_iterator(reader, false) {
+            writer.write_all(replace_in_string(&line?, vars).as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn vars() -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+    vars.insert("SCHEMA".to_string(), "staging".to_string());
+    vars
+}
+
+fn settings() -> Settings<RandomState> {
+    Settings::builder().vars(vars()).build()
+}
+
+fn bench_replace(crit: &mut Criterion) {
+    let settings = settings();
+    let vars = vars();
+    let mut group = crit.benchmark_group("replace");
+    for (name, every) in [("sparse", 1000), ("dense", 1)] {
+        let input = sql_dump(10_000, every);
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        group.bench_function(format!("string/{name}"), |bench| {
+            bench.iter(|| replace_in_string(black_box(&input), &settings).unwrap());
+        });
+        group.bench_function(format!("stream/{name}"), |bench| {
+            bench.iter(|| {
+                let mut output = Vec::with_capacity(input.len());
+                replace_in_stream(
+                    &mut io::BufReader::new(black_box(input.as_bytes())),
+                    &mut output,
+                    &settings,
+                )
+                .unwrap();
+                output
+            });
+        });
+        group.bench_function(format!("baseline/{name}"), |bench| {
+            bench.iter(|| {
+                let mut output = Vec::with_capacity(input.len());
+                baseline::replace_in_stream(
+                    &mut io::BufReader::new(black_box(input.as_bytes())),
+                    &mut output,
+                    &vars,
+                )
+                .unwrap();
+                output
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_replace_single_line(crit: &mut Criterion) {
+    let settings = settings();
+    let vars = vars();
+    let input = sql_dump(10_000, 1000).replace('\n', " ");
+    let mut group = crit.benchmark_group("replace_single_line");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_function("stream", |bench| {
+        bench.iter(|| {
+            let mut output = Vec::with_capacity(input.len());
+            replace_in_stream(
+                &mut io::BufReader::new(black_box(input.as_bytes())),
+                &mut output,
+                &settings,
+            )
+            .unwrap();
+            output
+        });
+    });
+    group.bench_function("baseline", |bench| {
+        bench.iter(|| {
+            let mut output = Vec::with_capacity(input.len());
+            baseline::replace_in_stream(
+                &mut io::BufReader::new(black_box(input.as_bytes())),
+                &mut output,
+                &vars,
+            )
+            .unwrap();
+            output
+        });
+    });
+    group.finish();
+}
+
+fn bench_template(crit: &mut Criterion) {
+    let settings = settings();
+    let input = "Dear ${NAME:-customer},\n\
+        ${if SCHEMA}your data lives in ${SCHEMA|upper}.\n${end}\
+        Regards, ${SENDER:-the team}\n";
+    let template = Template::new(input, &settings).unwrap();
+    let mut group = crit.benchmark_group("template");
+    group.bench_function("replace_in_string", |bench| {
+        bench.iter(|| replace_in_string(black_box(input), &settings).unwrap());
+    });
+    group.bench_function("render_into", |bench| {
+        let mut output = String::new();
+        bench.iter(|| {
+            output.clear();
+            template.render_into(&mut output, &settings).unwrap();
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_replace,
+    bench_replace_single_line,
+    bench_template
+);
+criterion_main!(benches);
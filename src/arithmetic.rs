@@ -0,0 +1,273 @@
+// SPDX-FileCopyrightText: 2025 Robin Vobruba <hoijui.quaero@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Shell-style integer arithmetic, as in `$((BASE_PORT + 1))`.
+//!
+//! Supported are decimal integers, variables,
+//! `+`, `-`, `*`, `/` and `%` (also as unary `+` and `-`),
+//! the comparisons `==`, `!=`, `<`, `<=`, `>` and `>=`
+//! (which evaluate to 1 if true and 0 otherwise),
+//! and parentheses.
+//! All values are signed 64 bit integers,
+//! and division truncates towards zero.
+
+use std::io;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Syntax(String),
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("Integer overflow")]
+    Overflow,
+    #[error("Expression is nested more than {} levels deep", MAX_DEPTH)]
+    TooDeep,
+    #[error("Value '{value}' of variable '{key}' is not an integer")]
+    NotANumber { key: String, value: String },
+    #[error("Undefined variable '{0}'")]
+    Missing(String),
+    #[error(transparent)]
+    Lookup(#[from] io::Error),
+}
+
+/// Whether `chr` may be the first character of a variable.
+const fn is_key_start(chr: char) -> bool {
+    chr == '_' || chr.is_ascii_alphabetic()
+}
+
+/// Whether `chr` may be part of a variable.
+const fn is_key_char(chr: char) -> bool {
+    chr == '_' || chr.is_ascii_alphanumeric()
+}
+
+/// Returns the variables used in `expr`, in the order they appear in.
+pub fn variables(expr: &str) -> Vec<&str> {
+    expr.split(|chr| !is_key_char(chr))
+        .filter(|word| word.starts_with(is_key_start))
+        .collect()
+}
+
+/// The binary operators, by precedence (lowest first).
+/// Longer operators come before their prefixes, e.g. `<=` before `<`.
+const LEVELS: &[&[&str]] = &[
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// How deep parentheses and unary operators may be nested,
+/// to not overflow the stack on malicious input.
+const MAX_DEPTH: usize = 256;
+
+/// A recursive descent parser, evaluating the expression as it goes.
+struct Parser<'e, F> {
+    expr: &'e str,
+    pos: usize,
+    /// How many unary operations (including parentheses) we are in.
+    depth: usize,
+    lookup: F,
+}
+
+impl<F> Parser<'_, F>
+where
+    F: FnMut(&str) -> io::Result<Option<String>>,
+{
+    fn rest(&self) -> &str {
+        self.expr.get(self.pos..).unwrap_or_default()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consumes one of `ops`, if the (remaining) input starts with it.
+    fn operator(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        self.skip_whitespace();
+        let op = ops.iter().find(|op| self.rest().starts_with(**op))?;
+        self.pos += op.len();
+        Some(op)
+    }
+
+    fn unexpected(&self) -> Error {
+        self.rest().chars().next().map_or_else(
+            || Error::Syntax("Unexpected end of expression".to_owned()),
+            |chr| Error::Syntax(format!("Unexpected '{chr}' at position {}", self.pos + 1)),
+        )
+    }
+
+    /// Parses the binary operations with the operators of `LEVELS[level]`
+    /// (and higher precedence).
+    fn binary(&mut self, level: usize) -> Result<i64, Error> {
+        let Some(ops) = LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = self.operator(ops) {
+            let rhs = self.binary(level + 1)?;
+            lhs = apply(op, lhs, rhs)?;
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<i64, Error> {
+        if self.depth >= MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        self.depth += 1;
+        let value = match self.operator(&["-", "+"]) {
+            Some("-") => self
+                .unary()
+                .and_then(|value| value.checked_neg().ok_or(Error::Overflow)),
+            Some(_) => self.unary(),
+            None => self.primary(),
+        };
+        self.depth -= 1;
+        value
+    }
+
+    fn primary(&mut self) -> Result<i64, Error> {
+        if self.operator(&["("]).is_some() {
+            let value = self.binary(0)?;
+            return self
+                .operator(&[")"])
+                .map(|_| value)
+                .ok_or_else(|| self.unexpected());
+        }
+        let rest = self.rest();
+        let len = rest.find(|chr| !is_key_char(chr)).unwrap_or(rest.len());
+        let word = rest.get(..len).unwrap_or_default();
+        if word.starts_with(|chr: char| chr.is_ascii_digit()) {
+            let value = word
+                .parse()
+                .map_err(|_| Error::Syntax(format!("Invalid number '{word}'")))?;
+            self.pos += len;
+            Ok(value)
+        } else if word.starts_with(is_key_start) {
+            let key = word.to_owned();
+            self.pos += len;
+            let value = (self.lookup)(&key)?.ok_or_else(|| Error::Missing(key.clone()))?;
+            value
+                .trim()
+                .parse()
+                .map_err(|_| Error::NotANumber { key, value })
+        } else {
+            Err(self.unexpected())
+        }
+    }
+}
+
+fn apply(op: &str, lhs: i64, rhs: i64) -> Result<i64, Error> {
+    let value = match op {
+        "==" => Some(i64::from(lhs == rhs)),
+        "!=" => Some(i64::from(lhs != rhs)),
+        "<=" => Some(i64::from(lhs <= rhs)),
+        ">=" => Some(i64::from(lhs >= rhs)),
+        "<" => Some(i64::from(lhs < rhs)),
+        ">" => Some(i64::from(lhs > rhs)),
+        "+" => lhs.checked_add(rhs),
+        "-" => lhs.checked_sub(rhs),
+        "*" => lhs.checked_mul(rhs),
+        "/" | "%" if rhs == 0 => return Err(Error::DivisionByZero),
+        "/" => lhs.checked_div(rhs),
+        "%" => lhs.checked_rem(rhs),
+        _ => unreachable!("Unknown operator '{op}'"),
+    };
+    value.ok_or(Error::Overflow)
+}
+
+/// Evaluates the arithmetic expression `expr`,
+/// using `lookup` to get the values of variables.
+///
+/// # Errors
+///
+/// If the expression is invalid,
+/// a variable is not set (`lookup` returns `None`)
+/// or its value is not an integer,
+/// on division by zero, if the result does not fit into 64 bits,
+/// or if the expression is nested too deeply.
+pub fn evaluate<F>(expr: &str, lookup: F) -> Result<i64, Error>
+where
+    F: FnMut(&str) -> io::Result<Option<String>>,
+{
+    let mut parser = Parser {
+        expr,
+        pos: 0,
+        depth: 0,
+        lookup,
+    };
+    let value = parser.binary(0)?;
+    parser.skip_whitespace();
+    if parser.rest().is_empty() {
+        Ok(value)
+    } else {
+        Err(parser.unexpected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> Result<i64, Error> {
+        evaluate(expr, |key| {
+            Ok(match key {
+                "port" => Some(" 8080 ".to_owned()),
+                "name" => Some("web".to_owned()),
+                _ => None,
+            })
+        })
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), 7);
+        assert_eq!(eval("(1 + 2) * 3").unwrap(), 9);
+        assert_eq!(eval("port+1").unwrap(), 8081);
+        assert_eq!(eval("-7 / 2").unwrap(), -3);
+        assert_eq!(eval("-7 % 2").unwrap(), -1);
+        assert_eq!(eval("10 - 2 - 3").unwrap(), 5);
+        assert_eq!(eval("- -2").unwrap(), 2);
+        assert_eq!(eval("port >= 1024").unwrap(), 1);
+        assert_eq!(eval("1 + 1 == 3").unwrap(), 0);
+        assert_eq!(eval("2 < 3 != 0").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        assert!(matches!(eval("1 / (2 - 2)"), Err(Error::DivisionByZero)));
+        assert!(matches!(eval("1 % 0"), Err(Error::DivisionByZero)));
+        assert!(matches!(eval("name * 2"), Err(Error::NotANumber { .. })));
+        assert!(matches!(eval("other + 1"), Err(Error::Missing(key)) if key == "other"));
+        assert!(matches!(
+            eval("9223372036854775807 + 1"),
+            Err(Error::Overflow)
+        ));
+        assert_eq!(
+            eval("1 +").unwrap_err().to_string(),
+            "Unexpected end of expression"
+        );
+        assert_eq!(
+            eval("(1 + 2").unwrap_err().to_string(),
+            "Unexpected end of expression"
+        );
+        assert_eq!(
+            eval("1 ^ 2").unwrap_err().to_string(),
+            "Unexpected '^' at position 3"
+        );
+        assert_eq!(variables("a + (b_1 * 2) - 3c"), vec!["a", "b_1"]);
+    }
+
+    #[test]
+    fn test_evaluate_depth_limit() {
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(eval(&nested(MAX_DEPTH - 1)).unwrap(), 1);
+        assert!(matches!(eval(&nested(MAX_DEPTH)), Err(Error::TooDeep)));
+        assert!(matches!(eval(&nested(100_000)), Err(Error::TooDeep)));
+        assert!(matches!(eval(&"-".repeat(100_000)), Err(Error::TooDeep)));
+    }
+}
@@ -2,8 +2,15 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use clap::{command, Arg, ArgAction, Command, ValueHint};
+use clap::{
+    builder::{NonEmptyStringValueParser, PossibleValuesParser},
+    command, value_parser, Arg, ArgAction, Command, ValueHint,
+};
 use const_format::formatcp;
+use regex::Regex;
+use repvar::filters;
+use repvar::replacer;
+use std::path::PathBuf;
 
 pub const A_L_VERSION: &str = "version";
 pub const A_S_VERSION: char = 'V';
@@ -23,8 +30,35 @@ pub const A_S_VERBOSE: char = 'v';
 pub const A_L_VERBOSE: &str = "verbose";
 pub const A_S_LIST: char = 'l';
 pub const A_L_LIST: &str = "list";
+pub const A_L_LOCATIONS: &str = "locations";
 pub const A_S_FAIL_ON_MISSING_VALUES: char = 'f';
 pub const A_L_FAIL_ON_MISSING_VALUES: &str = "fail-on-missing-values";
+pub const A_S_BARE: char = 'b';
+pub const A_L_BARE: &str = "bare";
+pub const A_L_SHELL_FORMAT: &str = "shell-format";
+pub const A_L_OPENING: &str = "opening";
+pub const A_L_CLOSING: &str = "closing";
+pub const A_L_ESCAPE: &str = "escape";
+pub const A_L_ESCAPE_RUNS: &str = "escape-runs";
+pub const A_S_RECURSIVE: char = 'r';
+pub const A_L_RECURSIVE: &str = "recursive";
+pub const A_L_MAX_DEPTH: &str = "max-depth";
+pub const A_L_MAX_SIZE: &str = "max-size";
+pub const A_L_ESCAPING: &str = "escaping";
+pub const A_L_INCLUDES: &str = "includes";
+pub const A_L_INCLUDE_PATH: &str = "include-path";
+pub const A_L_UNRESTRICTED_INCLUDES: &str = "unrestricted-includes";
+pub const A_L_COMMANDS: &str = "commands";
+pub const A_L_ARITHMETIC: &str = "arithmetic";
+pub const A_L_COMMAND_TIMEOUT: &str = "command-timeout";
+pub const A_S_STRICT: char = 's';
+pub const A_L_STRICT: &str = "strict";
+pub const A_L_KEY_PATTERN: &str = "key-pattern";
+pub const A_L_BUFFER_SIZE: &str = "buffer-size";
+pub const A_L_LINE_ENDINGS: &str = "line-endings";
+pub const A_L_OUTPUT_DIR: &str = "output-dir";
+pub const A_S_JOBS: char = 'j';
+pub const A_L_JOBS: &str = "jobs";
 
 fn arg_version() -> Arg {
     Arg::new(A_L_VERSION)
@@ -51,10 +85,21 @@ fn arg_quiet() -> Arg {
 fn arg_input() -> Arg {
     Arg::new(A_L_INPUT)
         .help("the input text file to use; '-' for stdin")
+        .long_help(formatcp!(
+            "the input text file to use; '-' for stdin. \
+            May be given multiple times, \
+            in which case the files are rendered concurrently (see --{A_L_JOBS}), \
+            each into --{A_L_OUTPUT_DIR}, \
+            or one after the other into --{A_L_OUTPUT}, in the given order. \
+            Errors are reported in the given order as well, \
+            and make the command fail after all files have been rendered. \
+            Other log messages of different files may be interleaved, \
+            unless --{A_L_JOBS} is 1."
+        ))
         .num_args(1)
         .short(A_S_INPUT)
         .long(A_L_INPUT)
-        .action(ArgAction::Set)
+        .action(ArgAction::Append)
         .value_hint(ValueHint::FilePath)
         .value_name("FILE")
         .default_value("-")
@@ -72,6 +117,41 @@ fn arg_output() -> Arg {
         .default_value("-")
 }
 
+fn arg_output_dir() -> Arg {
+    Arg::new(A_L_OUTPUT_DIR)
+        .help("the directory to write the rendered input files to")
+        .long_help(formatcp!(
+            "the directory to write the rendered input files to, \
+            each to the same path relative to it as the input file has \
+            (without any leading '/' or '..'), \
+            e.g. 'templates/app.conf' to 'DIR/templates/app.conf'. \
+            Missing directories are created."
+        ))
+        .num_args(1)
+        .value_name("DIR")
+        .value_hint(ValueHint::DirPath)
+        .value_parser(value_parser!(PathBuf))
+        .long(A_L_OUTPUT_DIR)
+        .action(ArgAction::Set)
+        .conflicts_with_all([A_L_OUTPUT, A_L_LIST])
+}
+
+fn arg_jobs() -> Arg {
+    Arg::new(A_L_JOBS)
+        .help(formatcp!(
+            "how many input files to render concurrently \
+            if multiple are given with --{A_L_INPUT}; \
+            by default, as many as there are CPUs"
+        ))
+        .num_args(1)
+        .value_name("NUM")
+        .value_hint(ValueHint::Other)
+        .value_parser(value_parser!(u64).range(1..))
+        .short(A_S_JOBS)
+        .long(A_L_JOBS)
+        .action(ArgAction::Set)
+}
+
 fn arg_variable() -> Arg {
     Arg::new(A_L_VARIABLE)
         .help("a variable key-value pair to be used for substitution in the text")
@@ -125,13 +205,33 @@ fn arg_list() -> Arg {
             The variables will appear in the output in the same order as in the input, \
             one per line, \
             and as many time as they appear in the input; \
-            i.e. there will be duplicates.",
+            i.e. there will be duplicates. \
+            For an indirect reference like '${!KEY}', \
+            the value of KEY is listed as well, if it is given.",
         )
         .action(ArgAction::SetTrue)
         .short(A_S_LIST)
         .long(A_L_LIST)
 }
 
+fn arg_locations() -> Arg {
+    Arg::new(A_L_LOCATIONS)
+        .help(formatcp!(
+            "with --{A_L_LIST}, list the location of each variable as well, \
+            as 'FILE:LINE:COLUMN: KEY'"
+        ))
+        .long_help(formatcp!(
+            "with --{A_L_LIST}, list the location of each variable as well, \
+            as 'FILE:LINE:COLUMN: KEY', which many editors can jump to. \
+            LINE and COLUMN start at 1, and the COLUMN is counted in characters. \
+            The variables in included files have the locations within those. \
+            When reading from stdin, 'FILE:' is omitted."
+        ))
+        .action(ArgAction::SetTrue)
+        .long(A_L_LOCATIONS)
+        .requires(A_L_LIST)
+}
+
 fn arg_fail_on_missing_values() -> Arg {
     Arg::new(A_L_FAIL_ON_MISSING_VALUES)
         .help("fail if no value is available for a variable key found in the input text")
@@ -140,6 +240,340 @@ fn arg_fail_on_missing_values() -> Arg {
         .long(A_L_FAIL_ON_MISSING_VALUES)
 }
 
+fn arg_bare() -> Arg {
+    Arg::new(A_L_BARE)
+        .help("also replace variables of the form `$KEY`, like GNU envsubst does")
+        .long_help(
+            "also replace variables of the form `$KEY`, like GNU envsubst does, \
+            in addition to those of the form `${KEY}`. \
+            KEY then consists of ASCII letters, digits and '_', \
+            and does not start with a digit. \
+            `$$KEY` is a quoted `$KEY`.",
+        )
+        .action(ArgAction::SetTrue)
+        .short(A_S_BARE)
+        .long(A_L_BARE)
+}
+
+fn arg_shell_format() -> Arg {
+    Arg::new(A_L_SHELL_FORMAT)
+        .help("only replace the variables that appear in this text, like GNU envsubst does")
+        .long_help(
+            "only replace the variables that appear in this text \
+            (of the form `$KEY` or `${KEY}`), \
+            like the SHELL-FORMAT argument of GNU envsubst. \
+            All other variables are left as they are.",
+        )
+        .num_args(1)
+        .value_name("SHELL-FORMAT")
+        .value_hint(ValueHint::Other)
+        .action(ArgAction::Set)
+}
+
+fn arg_opening() -> Arg {
+    Arg::new(A_L_OPENING)
+        .help("the opening delimiter of variables")
+        .long_help(
+            "the opening delimiter of variables, \
+            e.g. '{{' or '@', \
+            for when '${' is meaningful in the input text.",
+        )
+        .num_args(1)
+        .value_name("DELIMITER")
+        .value_hint(ValueHint::Other)
+        .value_parser(NonEmptyStringValueParser::new())
+        .long(A_L_OPENING)
+        .action(ArgAction::Set)
+        .default_value(replacer::DEFAULT_OPENING)
+}
+
+fn arg_closing() -> Arg {
+    Arg::new(A_L_CLOSING)
+        .help("the closing delimiter of variables")
+        .long_help(
+            "the closing delimiter of variables, \
+            e.g. '}}' or '@'.",
+        )
+        .num_args(1)
+        .value_name("DELIMITER")
+        .value_hint(ValueHint::Other)
+        .value_parser(NonEmptyStringValueParser::new())
+        .long(A_L_CLOSING)
+        .action(ArgAction::Set)
+        .default_value(replacer::DEFAULT_CLOSING)
+}
+
+fn arg_escape() -> Arg {
+    Arg::new(A_L_ESCAPE)
+        .help("the escape sequence, which quotes a directly following opening delimiter")
+        .long_help(
+            "the escape sequence, \
+            which - if it directly precedes an opening delimiter - gets removed, \
+            and the opening delimiter is copied to the output as-is, \
+            e.g. '$${KEY}' -> '${KEY}'. \
+            See --escape-runs for multiple escape sequences before it. \
+            Escape sequences anywhere else are copied as-is. \
+            Use '\\' for shell style escaping ('\\${KEY}' -> '${KEY}'), \
+            and '' to disable escaping.",
+        )
+        .num_args(1)
+        .value_name("SEQUENCE")
+        .value_hint(ValueHint::Other)
+        .long(A_L_ESCAPE)
+        .action(ArgAction::Set)
+        .default_value(replacer::DEFAULT_ESCAPE)
+}
+
+fn arg_escape_runs() -> Arg {
+    Arg::new(A_L_ESCAPE_RUNS)
+        .help("how multiple escape sequences directly before an opening delimiter are read")
+        .long_help(
+            "how multiple escape sequences directly before an opening delimiter are read: \
+            With 'last', only the last one quotes the opening delimiter and gets removed, \
+            e.g. '$$${KEY}' -> '$${KEY}'. \
+            With 'pairs', they are read in pairs, each pair standing for one escape sequence, \
+            and a single remaining one quotes the opening delimiter, \
+            e.g. '$$${KEY}' -> '$value' and '$$$${KEY}' -> '$${KEY}'.",
+        )
+        .num_args(1)
+        .value_name("STYLE")
+        .value_parser(PossibleValuesParser::new(replacer::EscapeRuns::NAMES))
+        .long(A_L_ESCAPE_RUNS)
+        .action(ArgAction::Set)
+        .default_value("last")
+}
+
+fn arg_recursive() -> Arg {
+    Arg::new(A_L_RECURSIVE)
+        .help("also replace variables within the values of variables")
+        .long_help(
+            "also replace variables within the values of variables, \
+            e.g. in URL=${SCHEME}://${HOST}. \
+            Cyclic references are reported as errors.",
+        )
+        .action(ArgAction::SetTrue)
+        .short(A_S_RECURSIVE)
+        .long(A_L_RECURSIVE)
+}
+
+fn arg_max_depth() -> Arg {
+    Arg::new(A_L_MAX_DEPTH)
+        .help(formatcp!(
+            "how deep variables may be nested in values of variables \
+            with --{A_L_RECURSIVE}"
+        ))
+        .num_args(1)
+        .value_name("LEVELS")
+        .value_hint(ValueHint::Other)
+        .value_parser(value_parser!(usize))
+        .long(A_L_MAX_DEPTH)
+        .action(ArgAction::Set)
+        .default_value(formatcp!("{}", replacer::DEFAULT_MAX_DEPTH))
+        .requires(A_L_RECURSIVE)
+}
+
+fn arg_max_size() -> Arg {
+    Arg::new(A_L_MAX_SIZE)
+        .help(formatcp!(
+            "the maximum size of the value of a variable in bytes, \
+            after replacing the variables within it with --{A_L_RECURSIVE}"
+        ))
+        .num_args(1)
+        .value_name("BYTES")
+        .value_hint(ValueHint::Other)
+        .value_parser(value_parser!(usize))
+        .long(A_L_MAX_SIZE)
+        .action(ArgAction::Set)
+        .default_value(formatcp!("{}", replacer::DEFAULT_MAX_SIZE))
+        .requires(A_L_RECURSIVE)
+}
+
+fn arg_escaping() -> Arg {
+    Arg::new(A_L_ESCAPING)
+        .help("escape all values for use in a specific kind of document")
+        .long_help(
+            "escape all values for use in a specific kind of document, \
+            e.g. 'json' for use within double-quoted JSON strings. \
+            Variables that use an escaping filter themselves, \
+            e.g. '${KEY|url}' or '${KEY|raw}', are not affected.",
+        )
+        .num_args(1)
+        .value_name("FILTER")
+        .value_parser(PossibleValuesParser::new(filters::ESCAPING_FILTERS))
+        .long(A_L_ESCAPING)
+        .action(ArgAction::Set)
+}
+
+fn arg_includes() -> Arg {
+    Arg::new(A_L_INCLUDES)
+        .help("insert the content of other files with '${include:path/to/file}'")
+        .long_help(formatcp!(
+            "insert the content of other files with '${{include:path/to/file}}', \
+            with the variables in them replaced as well. \
+            Without this, such an include is an error. \
+            Included files have to be within the directory of the input file \
+            (the current directory when reading from stdin) \
+            or one given with --{A_L_INCLUDE_PATH}, \
+            and their paths must neither be absolute nor contain '..', \
+            unless --{A_L_UNRESTRICTED_INCLUDES} is given."
+        ))
+        .action(ArgAction::SetTrue)
+        .long(A_L_INCLUDES)
+}
+
+fn arg_include_path() -> Arg {
+    Arg::new(A_L_INCLUDE_PATH)
+        .help("a directory to search for included files")
+        .long_help(formatcp!(
+            "a directory to search for included files, \
+            e.g. 'header.txt' in '${{include: header.txt}}', \
+            if they are not found relative to the including file. \
+            May be given multiple times; \
+            the directories are searched in the given order. \
+            Requires --{A_L_INCLUDES}."
+        ))
+        .num_args(1)
+        .value_name("DIR")
+        .value_hint(ValueHint::DirPath)
+        .value_parser(value_parser!(PathBuf))
+        .long(A_L_INCLUDE_PATH)
+        .action(ArgAction::Append)
+        .requires(A_L_INCLUDES)
+}
+
+fn arg_unrestricted_includes() -> Arg {
+    Arg::new(A_L_UNRESTRICTED_INCLUDES)
+        .help("allow to include any file, e.g. by absolute paths or paths containing '..'")
+        .long_help(formatcp!(
+            "allow to include any file, e.g. by absolute paths or paths containing '..', \
+            not only those within the directory of the input file \
+            or one given with --{A_L_INCLUDE_PATH}. \
+            Only use this with trusted inputs."
+        ))
+        .action(ArgAction::SetTrue)
+        .long(A_L_UNRESTRICTED_INCLUDES)
+        .requires(A_L_INCLUDES)
+}
+
+fn arg_commands() -> Arg {
+    Arg::new(A_L_COMMANDS)
+        .help("replace `$(command)` with the output of running the command")
+        .long_help(
+            "replace `$(command)` with the output of running the command \
+            with the system shell ('sh -c' or 'cmd /C'), \
+            without trailing newlines, \
+            e.g. `$(git rev-parse HEAD)`. \
+            Fails if the command exits with a non-zero status. \
+            `$$(command)` is a quoted `$(command)`. \
+            Only use this with trusted input!",
+        )
+        .action(ArgAction::SetTrue)
+        .long(A_L_COMMANDS)
+}
+
+fn arg_command_timeout() -> Arg {
+    Arg::new(A_L_COMMAND_TIMEOUT)
+        .help(formatcp!(
+            "how many seconds a command may run with --{A_L_COMMANDS}, \
+            before it is killed and replacing fails"
+        ))
+        .num_args(1)
+        .value_name("SECONDS")
+        .value_hint(ValueHint::Other)
+        .value_parser(value_parser!(u64))
+        .long(A_L_COMMAND_TIMEOUT)
+        .action(ArgAction::Set)
+        .default_value(formatcp!("{}", replacer::DEFAULT_COMMAND_TIMEOUT.as_secs()))
+        .requires(A_L_COMMANDS)
+}
+
+fn arg_arithmetic() -> Arg {
+    Arg::new(A_L_ARITHMETIC)
+        .help("replace `$((expr))` with the result of the integer arithmetic expression")
+        .long_help(
+            "replace `$((expr))` with the result of the integer arithmetic expression, \
+            like in POSIX shells, e.g. `$((BASE_PORT + 1))`. \
+            Supported are +, -, *, /, %, comparisons (==, !=, <, <=, >, >=) \
+            and parentheses. \
+            `$$((expr))` is a quoted `$((expr))`.",
+        )
+        .action(ArgAction::SetTrue)
+        .long(A_L_ARITHMETIC)
+}
+
+fn arg_strict() -> Arg {
+    Arg::new(A_L_STRICT)
+        .help("fail on malformed variables, stating their line and column")
+        .long_help(formatcp!(
+            "fail on malformed variables, stating their line and column, \
+            instead of copying them to the output as-is. \
+            Malformed are unterminated variables, e.g. '${{KEY', \
+            arithmetic expansions and command substitutions, if enabled, \
+            and those whose key does not match --{A_L_KEY_PATTERN}, e.g. '${{KEY NAME}}'. \
+            An output file is only written if there were no errors at all."
+        ))
+        .action(ArgAction::SetTrue)
+        .short(A_S_STRICT)
+        .long(A_L_STRICT)
+}
+
+/// Parses a regex that has to match a whole key.
+fn parse_key_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{pattern})$"))
+}
+
+fn arg_key_pattern() -> Arg {
+    Arg::new(A_L_KEY_PATTERN)
+        .help(formatcp!(
+            "the regex keys have to match with --{A_L_STRICT}; \
+            by default, the POSIX shell variable names"
+        ))
+        .num_args(1)
+        .value_name("REGEX")
+        .value_hint(ValueHint::Other)
+        .value_parser(parse_key_pattern)
+        .long(A_L_KEY_PATTERN)
+        .action(ArgAction::Set)
+        .default_value(replacer::DEFAULT_KEY_PATTERN)
+        .requires(A_L_STRICT)
+}
+
+fn arg_buffer_size() -> Arg {
+    Arg::new(A_L_BUFFER_SIZE)
+        .help("about how much of the input is kept in memory at most, in bytes")
+        .long_help(
+            "about how much of the input is kept in memory at most, in bytes. \
+            Variables and blocks (e.g. '${if KEY}...${end}') may span multiple lines, \
+            but have to fit into it; \
+            an unterminated variable that does not, is copied to the output as-is.",
+        )
+        .num_args(1)
+        .value_name("BYTES")
+        .value_hint(ValueHint::Other)
+        .value_parser(value_parser!(usize))
+        .long(A_L_BUFFER_SIZE)
+        .action(ArgAction::Set)
+        .default_value(formatcp!("{}", replacer::DEFAULT_BUFFER_SIZE))
+}
+
+fn arg_line_endings() -> Arg {
+    Arg::new(A_L_LINE_ENDINGS)
+        .help("how to write line endings to the output")
+        .long_help(
+            "how to write line endings to the output: \
+            'keep' them exactly as they are in the input, \
+            including a missing one at the end, \
+            or convert all of them ('\\n', '\\r\\n' and lone '\\r') \
+            to 'lf' ('\\n') or 'crlf' ('\\r\\n').",
+        )
+        .num_args(1)
+        .value_name("STYLE")
+        .value_parser(PossibleValuesParser::new(replacer::LineEndings::NAMES))
+        .long(A_L_LINE_ENDINGS)
+        .action(ArgAction::Set)
+        .default_value("keep")
+}
+
 pub fn args_matcher() -> Command {
     command!()
         .about(
@@ -153,10 +587,33 @@ pub fn args_matcher() -> Command {
         .arg(arg_quiet())
         .arg(arg_input())
         .arg(arg_output())
+        .arg(arg_output_dir())
+        .arg(arg_jobs())
         .arg(arg_variable())
         .arg(arg_variables_file())
         .arg(arg_environment())
         .arg(arg_verbose())
         .arg(arg_list())
+        .arg(arg_locations())
         .arg(arg_fail_on_missing_values())
+        .arg(arg_bare())
+        .arg(arg_shell_format())
+        .arg(arg_opening())
+        .arg(arg_closing())
+        .arg(arg_escape())
+        .arg(arg_escape_runs())
+        .arg(arg_recursive())
+        .arg(arg_max_depth())
+        .arg(arg_max_size())
+        .arg(arg_escaping())
+        .arg(arg_includes())
+        .arg(arg_include_path())
+        .arg(arg_unrestricted_includes())
+        .arg(arg_arithmetic())
+        .arg(arg_commands())
+        .arg(arg_command_timeout())
+        .arg(arg_strict())
+        .arg(arg_key_pattern())
+        .arg(arg_buffer_size())
+        .arg(arg_line_endings())
 }
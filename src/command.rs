@@ -0,0 +1,110 @@
+// SPDX-FileCopyrightText: 2025 Robin Vobruba <hoijui.quaero@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Command substitution, as in `$(git rev-parse HEAD)`.
+
+use std::io::{self, Read};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use wait_timeout::ChildExt;
+
+/// Creates a command that runs `command` with the system shell.
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+}
+
+/// Reads all of `pipe` in a separate thread,
+/// so the command does not block on a full pipe.
+fn read_in_background(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut content = vec![];
+        if let Some(mut reader) = pipe {
+            // A read error only means we get less output
+            let _ = reader.read_to_end(&mut content);
+        }
+        content
+    })
+}
+
+/// Runs `command` with the system shell (`sh -c` or `cmd /C`),
+/// and returns what it wrote to stdout,
+/// without trailing newlines.
+///
+/// # Errors
+///
+/// If the command could not be started,
+/// did not finish within `timeout`,
+/// exited with a non-zero status,
+/// or wrote something other than UTF-8 to stdout.
+pub fn run(command: &str, timeout: Duration) -> io::Result<String> {
+    tracing::debug!("COMMAND: {command}");
+    let mut child = shell(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| io::Error::new(err.kind(), format!("Command '{command}': {err}")))?;
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let Some(status) = child.wait_timeout(timeout)? else {
+        child.kill()?;
+        child.wait()?;
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "Command '{command}' timed out after {} seconds",
+                timeout.as_secs_f64()
+            ),
+        ));
+    };
+    let join_error = |_| io::Error::other(format!("Command '{command}': failed to read output"));
+    let output_bytes = stdout.join().map_err(join_error)?;
+    let error_bytes = stderr.join().map_err(join_error)?;
+    if !status.success() {
+        let error_output = String::from_utf8_lossy(&error_bytes);
+        let errors = error_output.trim();
+        let details = if errors.is_empty() {
+            String::new()
+        } else {
+            format!(": {errors}")
+        };
+        return Err(io::Error::other(format!(
+            "Command '{command}' failed with {status}{details}"
+        )));
+    }
+    let output = String::from_utf8(output_bytes).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Command '{command}': {err}"),
+        )
+    })?;
+    Ok(output.trim_end_matches(['\n', '\r']).to_owned())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn test_run() {
+        assert_eq!(run("printf 'a\\nb\\n\\n'", TIMEOUT).unwrap(), "a\nb");
+        assert_eq!(run("true", TIMEOUT).unwrap(), "");
+        let err = run("echo oops >&2; exit 3", TIMEOUT).unwrap_err();
+        assert!(err.to_string().contains("exit status: 3: oops"));
+        let err = run("sleep 5", Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,421 @@
+// SPDX-FileCopyrightText: 2025 Robin Vobruba <hoijui.quaero@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Filters transform the value of a variable before it gets inserted,
+//! e.g. `${KEY|trim|upper}`.
+//!
+//! Filters may take arguments, separated by ':',
+//! e.g. `${KEY|replace:old:new}`.
+//!
+//! Escaping filters (e.g. `json` or `shell`) make a value safe
+//! to be inserted into a specific kind of document.
+
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Unknown filter '{0}'")]
+    Unknown(String),
+    #[error("Expected {expected} argument(s), but got {actual}")]
+    Arguments { expected: usize, actual: usize },
+    #[error("Invalid argument '{0}'")]
+    InvalidArgument(String),
+    #[error("{0}")]
+    Failed(String),
+}
+
+/// A filter function.
+/// It gets the value and the arguments,
+/// and returns the transformed value.
+pub type Filter = dyn Fn(&str, &[&str]) -> Result<String, Error> + Send + Sync;
+
+/// A set of named filters.
+///
+/// The default contains all the built-in filters;
+/// see [`Filters::builtin`].
+pub struct Filters {
+    filters: HashMap<String, Box<Filter>>,
+    /// The names of the filters that escape a value
+    /// for a specific kind of document
+    escaping: HashSet<String>,
+}
+
+/// The names of the built-in escaping filters;
+/// see [`Filters::builtin`].
+pub const ESCAPING_FILTERS: &[&str] = &[
+    "json", "yaml", "xml", "html", "shell", "url", "regex", "raw",
+];
+
+impl Default for Filters {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+/// Ensures that exactly `expected` arguments were given.
+///
+/// # Errors
+///
+/// If the number of arguments is not `expected`.
+pub const fn check_args(args: &[&str], expected: usize) -> Result<(), Error> {
+    if args.len() == expected {
+        Ok(())
+    } else {
+        Err(Error::Arguments {
+            expected,
+            actual: args.len(),
+        })
+    }
+}
+
+fn capitalize(value: &str) -> String {
+    let mut chars = value.chars();
+    chars.next().map_or_else(String::new, |first| {
+        first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect()
+    })
+}
+
+fn truncate(value: &str, args: &[&str]) -> Result<String, Error> {
+    check_args(args, 1)?;
+    let len_str = args.first().copied().unwrap_or_default();
+    let len = len_str
+        .parse::<usize>()
+        .map_err(|_err| Error::InvalidArgument(len_str.to_owned()))?;
+    Ok(value.chars().take(len).collect())
+}
+
+fn base64_decode(value: &str) -> Result<String, Error> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .map_err(|err| Error::Failed(err.to_string()))?;
+    String::from_utf8(bytes).map_err(|err| Error::Failed(err.to_string()))
+}
+
+/// Appends the formatted `args` to `out`.
+fn push_fmt(out: &mut String, args: fmt::Arguments) {
+    // Writing to a String can not fail
+    let _ = out.write_fmt(args);
+}
+
+/// Escapes `value` for use within a double-quoted JSON string.
+/// As JSON is a subset of YAML,
+/// this also works for double-quoted YAML strings.
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    for chr in value.chars() {
+        match chr {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\u{8}' => escaped.push_str("\\b"),
+            '\u{c}' => escaped.push_str("\\f"),
+            chr if chr.is_control() => {
+                push_fmt(&mut escaped, format_args!("\\u{:04x}", u32::from(chr)));
+            }
+            chr => escaped.push(chr),
+        }
+    }
+    escaped
+}
+
+/// Escapes `value` for use in XML or HTML text and attribute values.
+fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for chr in value.chars() {
+        match chr {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            chr => escaped.push(chr),
+        }
+    }
+    escaped
+}
+
+/// Quotes `value` as a single POSIX shell word,
+/// e.g. `it's` -> `'it'\''s'`.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Percent-encodes all bytes of `value`
+/// except for the unreserved characters of RFC 3986.
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            push_fmt(&mut encoded, format_args!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+fn sha256(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            push_fmt(&mut hex, format_args!("{byte:02x}"));
+            hex
+        })
+}
+
+impl Filters {
+    /// Creates a set without any filters.
+    #[must_use]
+    pub fn empty() -> Self {
+        Self {
+            filters: HashMap::new(),
+            escaping: HashSet::new(),
+        }
+    }
+
+    /// Creates a set with all the built-in filters:
+    ///
+    /// - `upper`, `lower`: changes the case of all characters
+    /// - `capitalize`: first character upper-, the rest lower-case
+    /// - `trim`, `trim_start`, `trim_end`: removes leading and/or trailing whitespace
+    /// - `replace:FROM:TO`: replaces all occurrences of `FROM` with `TO`
+    /// - `truncate:LENGTH`: keeps only the first `LENGTH` characters
+    /// - `default:VALUE`: uses `VALUE` if the value is empty
+    /// - `base64_encode`, `base64_decode`: standard base64 with padding
+    /// - `sha256`: the hex-encoded SHA-256 hash
+    ///
+    /// and the escaping filters:
+    ///
+    /// - `json`, `yaml`: for use within a double-quoted string
+    /// - `xml`, `html`: for use in text and (quoted) attribute values
+    /// - `shell`: a single-quoted shell word, including the quotes
+    /// - `url`: percent-encoding, e.g. for query parameters
+    /// - `regex`: matches the value literally
+    /// - `raw`: leaves the value as-is,
+    ///   which prevents [`Settings::escaping`](crate::replacer::Settings) from being applied
+    ///
+    /// ```rust
+    /// # use repvar::filters::Filters;
+    /// let filters = Filters::builtin();
+    /// assert_eq!(filters.apply("upper", " Hello ", &[]).unwrap(), " HELLO ");
+    /// assert_eq!(filters.apply("replace", "Hello", &["l", "L"]).unwrap(), "HeLLo");
+    /// assert_eq!(filters.apply("truncate", "Hello", &["2"]).unwrap(), "He");
+    /// assert!(filters.apply("no_such_filter", "Hello", &[]).is_err());
+    /// assert_eq!(filters.apply("json", "say \"hi\"", &[]).unwrap(), r#"say \"hi\""#);
+    /// assert_eq!(filters.apply("shell", "it's", &[]).unwrap(), r"'it'\''s'");
+    /// ```
+    #[must_use]
+    pub fn builtin() -> Self {
+        let mut filters = Self::empty();
+        filters
+            .register("upper", |value, args| {
+                check_args(args, 0)?;
+                Ok(value.to_uppercase())
+            })
+            .register("lower", |value, args| {
+                check_args(args, 0)?;
+                Ok(value.to_lowercase())
+            })
+            .register("capitalize", |value, args| {
+                check_args(args, 0)?;
+                Ok(capitalize(value))
+            })
+            .register("trim", |value, args| {
+                check_args(args, 0)?;
+                Ok(value.trim().to_owned())
+            })
+            .register("trim_start", |value, args| {
+                check_args(args, 0)?;
+                Ok(value.trim_start().to_owned())
+            })
+            .register("trim_end", |value, args| {
+                check_args(args, 0)?;
+                Ok(value.trim_end().to_owned())
+            })
+            .register("replace", |value, args| {
+                check_args(args, 2)?;
+                let (from, to) = (args.first(), args.get(1));
+                Ok(value.replace(
+                    from.copied().unwrap_or_default(),
+                    to.copied().unwrap_or_default(),
+                ))
+            })
+            .register("truncate", truncate)
+            .register("default", |value, args| {
+                check_args(args, 1)?;
+                Ok(if value.is_empty() {
+                    args.first().copied().unwrap_or_default().to_owned()
+                } else {
+                    value.to_owned()
+                })
+            })
+            .register("base64_encode", |value, args| {
+                check_args(args, 0)?;
+                Ok(base64::engine::general_purpose::STANDARD.encode(value))
+            })
+            .register("base64_decode", |value, args| {
+                check_args(args, 0)?;
+                base64_decode(value)
+            })
+            .register("sha256", |value, args| {
+                check_args(args, 0)?;
+                Ok(sha256(value))
+            });
+        for (name, escape) in [
+            ("json", json_escape as fn(&str) -> String),
+            ("yaml", json_escape),
+            ("xml", xml_escape),
+            ("html", xml_escape),
+            ("shell", shell_quote),
+            ("url", url_encode),
+            ("regex", regex::escape),
+            ("raw", str::to_owned),
+        ] {
+            filters.register_escaping(name, move |value, args| {
+                check_args(args, 0)?;
+                Ok(escape(value))
+            });
+        }
+        filters
+    }
+
+    /// Adds a filter, replacing any previous one with the same name.
+    ///
+    /// ```rust
+    /// # use repvar::filters::{check_args, Filters};
+    /// let mut filters = Filters::builtin();
+    /// filters.register("reverse", |value, args| {
+    ///     check_args(args, 0)?;
+    ///     Ok(value.chars().rev().collect())
+    /// });
+    /// assert_eq!(filters.apply("reverse", "Hello", &[]).unwrap(), "olleH");
+    /// ```
+    pub fn register<F>(&mut self, name: impl Into<String>, filter: F) -> &mut Self
+    where
+        F: Fn(&str, &[&str]) -> Result<String, Error> + Send + Sync + 'static,
+    {
+        let name_owned = name.into();
+        self.escaping.remove(&name_owned);
+        self.filters.insert(name_owned, Box::new(filter));
+        self
+    }
+
+    /// Adds an escaping filter, replacing any previous one with the same name.
+    ///
+    /// Escaping filters make a value safe to be inserted
+    /// into a specific kind of document.
+    /// If a variable uses one of them,
+    /// [`Settings::escaping`](crate::replacer::Settings) is not applied to it.
+    pub fn register_escaping<F>(&mut self, name: impl Into<String>, filter: F) -> &mut Self
+    where
+        F: Fn(&str, &[&str]) -> Result<String, Error> + Send + Sync + 'static,
+    {
+        let name_owned = name.into();
+        self.register(name_owned.clone(), filter);
+        self.escaping.insert(name_owned);
+        self
+    }
+
+    /// Whether the filter called `name` is an escaping filter.
+    #[must_use]
+    pub fn is_escaping(&self, name: &str) -> bool {
+        self.escaping.contains(name)
+    }
+
+    /// Applies the filter called `name` to `value`.
+    ///
+    /// # Errors
+    ///
+    /// If there is no filter called `name`,
+    /// or if the filter failed,
+    /// for example because of wrong arguments.
+    pub fn apply(&self, name: &str, value: &str, args: &[&str]) -> Result<String, Error> {
+        let filter = self
+            .filters
+            .get(name)
+            .ok_or_else(|| Error::Unknown(name.to_owned()))?;
+        filter(value, args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin() {
+        let filters = Filters::builtin();
+        let apply = |name, value, args: &[&str]| filters.apply(name, value, args).unwrap();
+        assert_eq!(apply("lower", "HeLLo", &[]), "hello");
+        assert_eq!(apply("capitalize", "hELLO wORLD", &[]), "Hello world");
+        assert_eq!(apply("capitalize", "", &[]), "");
+        assert_eq!(apply("trim_start", " a ", &[]), "a ");
+        assert_eq!(apply("trim_end", " a ", &[]), " a");
+        assert_eq!(apply("truncate", "äöü", &["2"]), "äö");
+        assert_eq!(apply("default", "", &["x"]), "x");
+        assert_eq!(apply("default", "a", &["x"]), "a");
+        assert_eq!(apply("base64_encode", "Hello", &[]), "SGVsbG8=");
+        assert_eq!(apply("base64_decode", "SGVsbG8=", &[]), "Hello");
+        assert_eq!(
+            apply("sha256", "Hello", &[]),
+            "185f8db32271fe25f561a6fc938b2e264306ec304eda518007d1764826381969"
+        );
+    }
+
+    #[test]
+    fn test_builtin_escaping() {
+        let filters = Filters::builtin();
+        let apply = |name, value, args: &[&str]| filters.apply(name, value, args).unwrap();
+        assert_eq!(
+            apply("json", "a\"b\\c\nd\u{1}", &[]),
+            "a\\\"b\\\\c\\nd\\u0001"
+        );
+        assert_eq!(apply("yaml", "key: \"x\"", &[]), "key: \\\"x\\\"");
+        assert_eq!(
+            apply("xml", "<a href=\"x\">Tom & Jerry's</a>", &[]),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+        assert_eq!(
+            apply("shell", "my file's name", &[]),
+            "'my file'\\''s name'"
+        );
+        assert_eq!(apply("shell", "", &[]), "''");
+        assert_eq!(apply("url", "a b/c?d=ä~", &[]), "a%20b%2Fc%3Fd%3D%C3%A4~");
+        assert_eq!(apply("regex", "1.5*[x]", &[]), "1\\.5\\*\\[x\\]");
+        assert_eq!(apply("raw", "<\"'>", &[]), "<\"'>");
+        for name in ESCAPING_FILTERS {
+            assert!(filters.is_escaping(name));
+        }
+        assert!(!filters.is_escaping("upper"));
+    }
+
+    #[test]
+    fn test_builtin_errors() {
+        let filters = Filters::builtin();
+        assert!(matches!(
+            filters.apply("truncate", "Hello", &["x"]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            filters.apply("replace", "Hello", &["x"]),
+            Err(Error::Arguments {
+                expected: 2,
+                actual: 1
+            })
+        ));
+        assert!(matches!(
+            filters.apply("base64_decode", "not base64!", &[]),
+            Err(Error::Failed(_))
+        ));
+    }
+}
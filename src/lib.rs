@@ -2,7 +2,12 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+mod arithmetic;
+mod command;
+pub mod filters;
 pub mod key_value;
+mod lossless;
+mod pattern;
 pub mod replacer;
 pub mod tools;
 
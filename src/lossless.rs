@@ -0,0 +1,134 @@
+// SPDX-FileCopyrightText: 2025 Robin Vobruba <hoijui.quaero@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Lossless conversion of arbitrary bytes into strings and back,
+//! so input that is not valid UTF-8
+//! (e.g. Latin-1, or with embedded binary blobs)
+//! can be processed like any other string.
+//!
+//! Each byte that is not part of a valid UTF-8 sequence
+//! is represented by one of the 128 characters `U+10FF80` to `U+10FFFF`,
+//! at the end of the Supplementary Private Use Area-B.
+//! These characters themselves are represented byte by byte as well,
+//! so they survive the round trip too.
+//! The same goes for text inserted into decoded text,
+//! e.g. the values of variables,
+//! which therefore has to be decoded (from its UTF-8 bytes) as well.
+
+use std::borrow::Cow;
+use std::str;
+
+/// The characters representing bytes start at this one minus 0x80,
+/// as ASCII bytes (`0x00` to `0x7F`) are always valid UTF-8.
+const BYTE_CHARS_BASE: u32 = 0x10_FF00;
+
+/// Returns the character representing `byte`,
+/// which has to be 0x80 or larger.
+fn byte_char(byte: u8) -> char {
+    char::from_u32(BYTE_CHARS_BASE + u32::from(byte)).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+/// Returns the byte represented by `chr`, if any.
+fn char_byte(chr: char) -> Option<u8> {
+    u32::from(chr)
+        .checked_sub(BYTE_CHARS_BASE)
+        .and_then(|byte| u8::try_from(byte).ok())
+        .filter(|byte| !byte.is_ascii())
+}
+
+/// Whether `text` contains characters that represent bytes.
+fn has_byte_chars(text: &str) -> bool {
+    // All of them are encoded as 0xF4 0x8F 0xBE/0xBF ...
+    memchr::memchr(0xF4, text.as_bytes()).is_some()
+        && text.chars().any(|chr| char_byte(chr).is_some())
+}
+
+/// Converts `input` into a string,
+/// representing the bytes that are not valid UTF-8
+/// by special characters; see the module documentation.
+pub fn decode(input: &[u8]) -> Cow<'_, str> {
+    if let Ok(text) = str::from_utf8(input) {
+        if !has_byte_chars(text) {
+            return Cow::Borrowed(text);
+        }
+    }
+    let mut decoded = String::with_capacity(input.len());
+    for chunk in input.utf8_chunks() {
+        for chr in chunk.valid().chars() {
+            if char_byte(chr).is_some() {
+                decoded.extend(chr.encode_utf8(&mut [0; 4]).bytes().map(byte_char));
+            } else {
+                decoded.push(chr);
+            }
+        }
+        decoded.extend(chunk.invalid().iter().copied().map(byte_char));
+    }
+    Cow::Owned(decoded)
+}
+
+/// Converts `text` back into the bytes it was decoded from;
+/// see [`decode`].
+pub fn encode(text: &str) -> Cow<'_, [u8]> {
+    if !has_byte_chars(text) {
+        return Cow::Borrowed(text.as_bytes());
+    }
+    let mut encoded = Vec::with_capacity(text.len());
+    for chr in text.chars() {
+        match char_byte(chr) {
+            Some(byte) => encoded.push(byte),
+            None => encoded.extend_from_slice(chr.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Cow::Owned(encoded)
+}
+
+/// Returns the length of the incomplete UTF-8 sequence
+/// at the end of `input`,
+/// which might get completed by the bytes that follow.
+pub fn incomplete_len(input: &[u8]) -> usize {
+    let start = input.len().saturating_sub(3);
+    (start..input.len())
+        .filter_map(|idx| input.get(idx..))
+        .find(|tail| {
+            matches!(str::from_utf8(tail),
+                Err(err) if err.valid_up_to() == 0 && err.error_len().is_none())
+        })
+        .map_or(0, <[u8]>::len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let inputs: [&[u8]; 5] = [
+            b"plain ${KEY}",
+            b"caf\xe9 ${KEY} \xff\xfe\x00",
+            "ä \u{10FF80}\u{10FFFF} \u{10FF7F}".as_bytes(),
+            b"\xf4\x8f\xbe",
+            b"\xc3",
+        ];
+        for input in inputs {
+            let decoded = decode(input);
+            assert_eq!(encode(&decoded), input);
+        }
+        assert!(matches!(
+            decode(b"plain \xc3\xa4"),
+            Cow::Borrowed("plain ä")
+        ));
+        assert_eq!(decode(b"caf\xe9!"), "caf\u{10FFE9}!");
+    }
+
+    #[test]
+    fn test_incomplete_len() {
+        assert_eq!(incomplete_len(b"abc"), 0);
+        assert_eq!(incomplete_len(b"ab\xc3"), 1);
+        assert_eq!(incomplete_len(b"ab\xc3\xa4"), 0);
+        assert_eq!(incomplete_len(b"ab\xf4\x8f\xbe"), 3);
+        assert_eq!(incomplete_len(b"ab\xe9"), 1);
+        assert_eq!(incomplete_len(b"ab\xe9x"), 0);
+        assert_eq!(incomplete_len(b"ab\xff"), 0);
+    }
+}
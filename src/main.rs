@@ -4,7 +4,7 @@
 
 mod cli;
 
-use clap::crate_name;
+use clap::{crate_name, ArgMatches};
 use cli_utils::BoxResult;
 use repvar::key_value;
 use repvar::replacer;
@@ -12,8 +12,15 @@ use repvar::settings;
 use repvar::tools;
 
 use cli_utils::logging;
-use replacer::Settings;
-use std::collections::HashMap;
+use regex::Regex;
+use replacer::{EscapeRuns, Includes, LineEndings, Settings, Syntax};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::BuildHasher;
+use std::io::{self, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
 use tracing_subscriber::filter::LevelFilter;
 
 #[allow(clippy::print_stdout)]
@@ -25,6 +32,268 @@ fn print_version_and_exit(quiet: bool) {
     std::process::exit(0);
 }
 
+fn syntax(args: &ArgMatches) -> BoxResult<Syntax> {
+    let opening = args
+        .get_one::<String>(cli::A_L_OPENING)
+        .map_or(replacer::DEFAULT_OPENING, String::as_str);
+    let closing = args
+        .get_one::<String>(cli::A_L_CLOSING)
+        .map_or(replacer::DEFAULT_CLOSING, String::as_str);
+    let escape = args
+        .get_one::<String>(cli::A_L_ESCAPE)
+        .map_or(replacer::DEFAULT_ESCAPE, String::as_str);
+    let escape_runs = args
+        .get_one::<String>(cli::A_L_ESCAPE_RUNS)
+        .map(|name| name.parse::<EscapeRuns>())
+        .transpose()?
+        .unwrap_or_default();
+    Ok(Syntax::builder()
+        .opening(opening)
+        .closing(closing)
+        .escape(escape)
+        .escape_runs(escape_runs)
+        .bare_vars(args.get_flag(cli::A_L_BARE))
+        .commands(args.get_flag(cli::A_L_COMMANDS))
+        .arithmetic(args.get_flag(cli::A_L_ARITHMETIC))
+        .build())
+}
+
+fn includes(args: &ArgMatches) -> Includes {
+    if args.get_flag(cli::A_L_UNRESTRICTED_INCLUDES) {
+        Includes::Unrestricted
+    } else if args.get_flag(cli::A_L_INCLUDES) {
+        Includes::Restricted
+    } else {
+        Includes::Disabled
+    }
+}
+
+fn mode(args: &ArgMatches) -> Mode {
+    if !args.get_flag(cli::A_L_LIST) {
+        Mode::Render
+    } else if args.get_flag(cli::A_L_LOCATIONS) {
+        Mode::ListLocations
+    } else {
+        Mode::List
+    }
+}
+
+fn collect_vars(args: &ArgMatches) -> BoxResult<HashMap<String, String>> {
+    let mut vars = HashMap::new();
+
+    // enlist environment variables
+    if args.get_flag(cli::A_L_ENVIRONMENT) {
+        tools::append_env(&mut vars);
+    }
+    // enlist variables from files
+    if let Some(var_files) = args.get_many::<String>(cli::A_L_VARIABLES_FILE) {
+        for var_file in var_files {
+            let mut reader = cli_utils::create_input_reader(Some(var_file))?;
+            vars.extend(key_value::parse_vars_file_reader(&mut reader)?);
+        }
+    }
+    // enlist variables provided on the CLI
+    if let Some(variables) = args.get_many::<String>(cli::A_L_VARIABLE) {
+        for key_value in variables {
+            let pair = key_value::Pair::parse(key_value)?;
+            vars.insert(pair.key.to_owned(), pair.value.to_owned());
+        }
+    }
+
+    Ok(vars)
+}
+
+/// Returns the path `source` is rendered to within `out_dir`,
+/// which is the same relative path,
+/// without any leading '/' or '..'.
+fn output_path(out_dir: &Path, source: &str) -> PathBuf {
+    let relative: PathBuf = Path::new(source)
+        .components()
+        .filter(|comp| matches!(comp, Component::Normal(_)))
+        .collect();
+    out_dir.join(relative)
+}
+
+/// Adds `path` to the error message of `err`.
+fn with_path(err: &io::Error, path: &Path) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {err}", path.display()))
+}
+
+/// What to do with the inputs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Replace the variables in them
+    Render,
+    /// List the variables found in them
+    List,
+    /// List the variables found in them, with their locations
+    ListLocations,
+}
+
+/// Lists the variables found in `source`,
+/// with their locations if `mode` is [`Mode::ListLocations`].
+fn list_vars<S: BuildHasher>(
+    source: &str,
+    mode: Mode,
+    settings: &Settings<S>,
+) -> io::Result<Vec<String>> {
+    if mode == Mode::ListLocations {
+        Ok(
+            replacer::extract_placeholders_from_file(Some(source), settings)?
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        )
+    } else {
+        replacer::extract_from_file_with(Some(source), settings)
+    }
+}
+
+/// Renders one of multiple inputs into a file within `out_dir`,
+/// or else into a temporary file, which is returned,
+/// respectively writes the variables found in it there (see [`Mode`]).
+fn render_one<S: BuildHasher>(
+    source: &str,
+    out_dir: Option<&Path>,
+    mode: Mode,
+    settings: &Settings<S>,
+) -> io::Result<Option<fs::File>> {
+    let source_name = Some(source).filter(|src| !cli_utils::denotes_std_stream(Some(src)));
+    let open = || {
+        cli_utils::create_input_reader(Some(source))
+            .map_err(|err| with_path(&err, Path::new(source)))
+    };
+    if let (Mode::Render, Some(dir)) = (mode, out_dir) {
+        if source_name.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stdin can not be rendered into an output directory",
+            ));
+        }
+        let destination = output_path(dir, source);
+        tracing::debug!("OUTPUT: {}", destination.display());
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).map_err(|err| with_path(&err, parent))?;
+        }
+        if settings.is_strict() {
+            let mut writer = tools::StagedFile::create(&destination)
+                .map_err(|err| with_path(&err, &destination))?;
+            replacer::replace_in_named_stream(&mut open()?, &mut writer, settings, source_name)?;
+            writer.commit()?;
+            return Ok(None);
+        }
+        let mut writer = io::BufWriter::new(
+            fs::File::create(&destination).map_err(|err| with_path(&err, &destination))?,
+        );
+        replacer::replace_in_named_stream(&mut open()?, &mut writer, settings, source_name)?;
+        writer.flush()?;
+        return Ok(None);
+    }
+    // Spilling the output to disk keeps memory usage low,
+    // no matter how large the inputs are
+    let mut writer = io::BufWriter::new(tempfile::tempfile()?);
+    if mode == Mode::Render {
+        replacer::replace_in_named_stream(&mut open()?, &mut writer, settings, source_name)?;
+    } else {
+        for key in list_vars(source, mode, settings)? {
+            writeln!(writer, "{key}")?;
+        }
+    }
+    let mut spill = writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    spill.rewind()?;
+    Ok(Some(spill))
+}
+
+/// Renders the inputs, or lists the variables found in them.
+/// Multiple inputs are rendered concurrently,
+/// each into a file within `out_dir`, if given,
+/// or else into `destination`,
+/// each as soon as it and all the inputs before it are rendered.
+/// Errors are logged in the order of the inputs,
+/// and result in a single, combined error at the end.
+/// Other log records are not reordered,
+/// so with multiple jobs, those of different inputs may be interleaved.
+/// In strict mode, a `destination` file is only written
+/// if all the inputs were rendered.
+fn render<S: BuildHasher + Sync>(
+    sources: &[String],
+    destination: Option<&str>,
+    out_dir: Option<&Path>,
+    jobs: Option<usize>,
+    mode: Mode,
+    settings: &Settings<S>,
+) -> BoxResult<()> {
+    if let ([source], None) = (sources, out_dir) {
+        if mode == Mode::Render {
+            replacer::replace_in_file(Some(source), destination, settings)?;
+        } else {
+            tools::write_to_file(list_vars(source, mode, settings)?, destination)?;
+        }
+        return Ok(());
+    }
+
+    let out_file = destination.filter(|dst| !cli_utils::denotes_std_stream(Some(dst)));
+    let mut staged = match (settings.is_strict(), out_dir, out_file) {
+        (true, None, Some(path)) => Some(tools::StagedFile::create(path)?),
+        _ => None,
+    };
+    let mut plain = if out_dir.is_none() && staged.is_none() {
+        Some(cli_utils::create_output_writer(destination)?)
+    } else {
+        None
+    };
+    let mut writer: Option<&mut dyn Write> = staged
+        .as_mut()
+        .map_or(plain.as_deref_mut(), |file| Some(file));
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs.unwrap_or_default())
+        .build()?;
+    let (results, receiver) = mpsc::channel();
+    let failed = pool.in_place_scope_fifo(|scope| {
+        for (idx, source) in sources.iter().enumerate() {
+            let sender = results.clone();
+            scope.spawn_fifo(move |_| {
+                // Fails only if writing the output failed before
+                let _ = sender.send((idx, render_one(source, out_dir, mode, settings)));
+            });
+        }
+        drop(results);
+
+        // The inputs that were rendered before all the ones before them
+        let mut done = HashMap::new();
+        let mut next = 0;
+        let mut failed = 0_usize;
+        for (idx, result) in receiver {
+            done.insert(idx, result);
+            while let Some(result) = done.remove(&next) {
+                next += 1;
+                match result {
+                    Ok(rendered) => {
+                        if let (Some(out), Some(mut spill)) = (writer.as_mut(), rendered) {
+                            io::copy(&mut spill, out)?;
+                            out.flush()?;
+                        }
+                    }
+                    Err(err) => {
+                        tracing::error!("{err}");
+                        failed += 1;
+                    }
+                }
+            }
+        }
+        Ok::<_, io::Error>(failed)
+    })?;
+    if failed > 0 {
+        return Err(format!("Failed to render {failed} of {} input files", sources.len()).into());
+    }
+    if let Some(file) = staged {
+        file.commit()?;
+    }
+    Ok(())
+}
+
 fn main() -> BoxResult<()> {
     let log_reload_handle = logging::setup(crate_name!())?;
     let args = cli::args_matcher().get_matches();
@@ -45,44 +314,91 @@ fn main() -> BoxResult<()> {
     };
     logging::set_log_level_tracing(&log_reload_handle, log_level)?;
 
-    let list = args.get_flag(cli::A_L_LIST);
-    let src = args.get_one::<String>(cli::A_L_INPUT).cloned();
+    let mode = mode(&args);
+    let sources: Vec<String> = args
+        .get_many::<String>(cli::A_L_INPUT)
+        .map(|sources| sources.cloned().collect())
+        .unwrap_or_default();
     let dst = args.get_one::<String>(cli::A_L_OUTPUT).cloned();
+    let out_dir = args.get_one::<PathBuf>(cli::A_L_OUTPUT_DIR).cloned();
+    let jobs = args
+        .get_one::<u64>(cli::A_L_JOBS)
+        .map(|jobs| usize::try_from(*jobs))
+        .transpose()?;
+    let syntax = syntax(&args)?;
+    let vars = collect_vars(&args)?;
 
-    if list {
-        let detected_vars = replacer::extract_from_file(src.as_deref())?;
-        tools::write_to_file(detected_vars, dst.as_deref())?;
-    } else {
-        let mut vars = HashMap::new();
-
-        // enlist environment variables
-        if args.get_flag(cli::A_L_ENVIRONMENT) {
-            tools::append_env(&mut vars);
-        }
-        // enlist variables from files
-        if let Some(var_files) = args.get_many::<String>(cli::A_L_VARIABLES_FILE) {
-            for var_file in var_files {
-                let mut reader = cli_utils::create_input_reader(Some(var_file))?;
-                vars.extend(key_value::parse_vars_file_reader(&mut reader)?);
-            }
-        }
-        // enlist variables provided on the CLI
-        if let Some(variables) = args.get_many::<String>(cli::A_L_VARIABLE) {
-            for key_value in variables {
-                let pair = key_value::Pair::parse(key_value)?;
-                vars.insert(pair.key.to_owned(), pair.value.to_owned());
-            }
-        }
-
-        let fail_on_missing = args.get_flag(cli::A_L_FAIL_ON_MISSING_VALUES);
+    let fail_on_missing = args.get_flag(cli::A_L_FAIL_ON_MISSING_VALUES);
 
-        let settings = settings! {
-            vars: vars,
-            fail_on_missing: fail_on_missing
-        };
+    let only_keys = args
+        .get_one::<String>(cli::A_L_SHELL_FORMAT)
+        .map(|shell_format| {
+            // Like GNU envsubst, we always recognize `$KEY` in SHELL-FORMAT
+            let format_syntax = Syntax::builder().bare_vars(true).build();
+            replacer::extract_from_string_with(shell_format, &format_syntax)
+                .into_iter()
+                .map(str::to_owned)
+                .collect::<HashSet<_>>()
+        });
+    let recursive = args.get_flag(cli::A_L_RECURSIVE);
+    let max_depth = args
+        .get_one::<usize>(cli::A_L_MAX_DEPTH)
+        .copied()
+        .unwrap_or(replacer::DEFAULT_MAX_DEPTH);
+    let max_size = args
+        .get_one::<usize>(cli::A_L_MAX_SIZE)
+        .copied()
+        .unwrap_or(replacer::DEFAULT_MAX_SIZE);
+    let escaping = args.get_one::<String>(cli::A_L_ESCAPING).cloned();
+    let includes = includes(&args);
+    let include_paths = args
+        .get_many::<PathBuf>(cli::A_L_INCLUDE_PATH)
+        .map(|dirs| dirs.cloned().collect())
+        .unwrap_or_default();
+    let strict = args.get_flag(cli::A_L_STRICT);
+    let key_pattern = args
+        .get_one::<Regex>(cli::A_L_KEY_PATTERN)
+        .cloned()
+        .map_or_else(|| Regex::new(replacer::DEFAULT_KEY_PATTERN), Ok)?;
+    let command_timeout = args
+        .get_one::<u64>(cli::A_L_COMMAND_TIMEOUT)
+        .map_or(replacer::DEFAULT_COMMAND_TIMEOUT, |secs| {
+            Duration::from_secs(*secs)
+        });
+    let buffer_size = args
+        .get_one::<usize>(cli::A_L_BUFFER_SIZE)
+        .copied()
+        .unwrap_or(replacer::DEFAULT_BUFFER_SIZE);
+    let line_endings = args
+        .get_one::<String>(cli::A_L_LINE_ENDINGS)
+        .map(|name| name.parse::<LineEndings>())
+        .transpose()?
+        .unwrap_or_default();
 
-        replacer::replace_in_file(src.as_deref(), dst.as_deref(), &settings)?;
-    }
+    let settings = settings! {
+        vars: vars,
+        fail_on_missing: fail_on_missing,
+        syntax: syntax,
+        only_keys: only_keys,
+        recursive: recursive,
+        max_depth: max_depth,
+        max_size: max_size,
+        escaping: escaping,
+        includes: includes,
+        include_paths: include_paths,
+        command_timeout: command_timeout,
+        strict: strict,
+        key_pattern: key_pattern,
+        buffer_size: buffer_size,
+        line_endings: line_endings
+    };
 
-    Ok(())
+    render(
+        &sources,
+        dst.as_deref(),
+        out_dir.as_deref(),
+        jobs,
+        mode,
+        &settings,
+    )
 }
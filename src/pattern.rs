@@ -0,0 +1,208 @@
+// SPDX-FileCopyrightText: 2025 Robin Vobruba <hoijui.quaero@gmail.com>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Shell-style glob patterns,
+//! as used in `${KEY#pattern}`, `${KEY%pattern}` and `${KEY/pattern/replacement}`.
+//!
+//! `*` matches any string, `?` any single character,
+//! `[abc]`, `[a-z]` and `[!abc]` a character (not) in the set,
+//! and `\` quotes the following character.
+
+use regex::{NoExpand, Regex};
+use std::borrow::Cow;
+use std::iter;
+
+pub struct Pattern {
+    /// Matches the whole input only
+    full: Regex,
+    /// Matches anywhere in the input
+    partial: Regex,
+    empty: bool,
+}
+
+/// Translates the bracket expression at the start of `glob`
+/// (just after the `[`) into a regex character class.
+/// Returns the class and the length of the bracket expression in `glob`,
+/// or `None` if it is not terminated.
+fn translate_class(glob: &str) -> Option<(String, usize)> {
+    let mut class = String::from("[");
+    let mut chars = glob.char_indices().peekable();
+    if let Some((_, '!' | '^')) = chars.peek() {
+        class.push('^');
+        chars.next();
+    }
+    let mut first = true;
+    while let Some((idx, chr)) = chars.next() {
+        match chr {
+            ']' if !first => {
+                class.push(']');
+                return Some((class, idx + 1));
+            }
+            '\\' => {
+                let (_, quoted) = chars.next()?;
+                class.push_str(&regex::escape(quoted.encode_utf8(&mut [0; 4])));
+            }
+            '[' | ']' | '&' | '~' => {
+                class.push('\\');
+                class.push(chr);
+            }
+            _ => class.push(chr),
+        }
+        first = false;
+    }
+    None
+}
+
+/// Translates a glob pattern into the equivalent regex.
+fn translate(glob: &str) -> String {
+    let mut regex = String::from("(?s)(?:");
+    let mut idx = 0;
+    while let Some(rest) = glob.get(idx..) {
+        let mut chars = rest.chars();
+        let Some(chr) = chars.next() else {
+            break;
+        };
+        idx += chr.len_utf8();
+        match chr {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                if let Some((class, len)) = glob.get(idx..).and_then(translate_class) {
+                    regex.push_str(&class);
+                    idx += len;
+                } else {
+                    regex.push_str(r"\[");
+                }
+            }
+            '\\' => {
+                if let Some(quoted) = chars.next() {
+                    regex.push_str(&regex::escape(quoted.encode_utf8(&mut [0; 4])));
+                    idx += quoted.len_utf8();
+                } else {
+                    regex.push_str(r"\\");
+                }
+            }
+            _ => regex.push_str(&regex::escape(chr.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push(')');
+    regex
+}
+
+/// Returns all the indices in `value` that are at a character boundary,
+/// including the end.
+fn boundaries(value: &str) -> impl DoubleEndedIterator<Item = usize> + '_ {
+    value
+        .char_indices()
+        .map(|(idx, _)| idx)
+        .chain(iter::once(value.len()))
+}
+
+impl Pattern {
+    /// Compiles a glob pattern.
+    ///
+    /// # Errors
+    ///
+    /// If the pattern contains an invalid character set,
+    /// e.g. `[z-a]`.
+    pub fn new(glob: &str) -> Result<Self, regex::Error> {
+        let regex = translate(glob);
+        Ok(Self {
+            full: Regex::new(&format!("^{regex}$"))?,
+            partial: Regex::new(&regex)?,
+            empty: glob.is_empty(),
+        })
+    }
+
+    /// Whether this pattern matches the whole of `value`.
+    pub fn is_match(&self, value: &str) -> bool {
+        self.full.is_match(value)
+    }
+
+    fn matches(&self, value: Option<&str>) -> bool {
+        value.is_some_and(|part| self.full.is_match(part))
+    }
+
+    /// Removes the shortest (or longest) prefix matching this pattern
+    /// from `value`, if any.
+    pub fn remove_prefix<'v>(&self, value: &'v str, longest: bool) -> &'v str {
+        let end = if longest {
+            boundaries(value)
+                .rev()
+                .find(|&end| self.matches(value.get(..end)))
+        } else {
+            boundaries(value).find(|&end| self.matches(value.get(..end)))
+        };
+        end.and_then(|idx| value.get(idx..)).unwrap_or(value)
+    }
+
+    /// Removes the shortest (or longest) suffix matching this pattern
+    /// from `value`, if any.
+    pub fn remove_suffix<'v>(&self, value: &'v str, longest: bool) -> &'v str {
+        let start = if longest {
+            boundaries(value).find(|&start| self.matches(value.get(start..)))
+        } else {
+            boundaries(value)
+                .rev()
+                .find(|&start| self.matches(value.get(start..)))
+        };
+        start.and_then(|idx| value.get(..idx)).unwrap_or(value)
+    }
+
+    /// Replaces the first (or all) longest match(es) of this pattern
+    /// in `value` with `replacement`.
+    pub fn replace<'v>(&self, value: &'v str, replacement: &str, all: bool) -> Cow<'v, str> {
+        if self.empty {
+            Cow::Borrowed(value)
+        } else if all {
+            self.partial.replace_all(value, NoExpand(replacement))
+        } else {
+            self.partial.replace(value, NoExpand(replacement))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_prefix_suffix() {
+        let pattern = Pattern::new("*.").unwrap();
+        assert_eq!(pattern.remove_prefix("a.b.c", false), "b.c");
+        assert_eq!(pattern.remove_prefix("a.b.c", true), "c");
+        let pattern = Pattern::new(".*").unwrap();
+        assert_eq!(pattern.remove_suffix("a.b.c", false), "a.b");
+        assert_eq!(pattern.remove_suffix("a.b.c", true), "a");
+        let pattern = Pattern::new("[vV]").unwrap();
+        assert_eq!(pattern.remove_prefix("v1.2", false), "1.2");
+        assert_eq!(pattern.remove_prefix("1.2", false), "1.2");
+    }
+
+    #[test]
+    fn test_replace() {
+        let pattern = Pattern::new("o?").unwrap();
+        assert_eq!(pattern.replace("foo bar boo", "0", false), "f0 bar boo");
+        assert_eq!(pattern.replace("foo bar boo", "$0", true), "f$0 bar b$0");
+        let pattern = Pattern::new(r"[!a-z]\*").unwrap();
+        assert_eq!(pattern.replace("a* 1* b*", "x", true), "a* x b*");
+        assert_eq!(Pattern::new("").unwrap().replace("abc", "x", true), "abc");
+        assert_eq!(
+            Pattern::new("[]").unwrap().replace("a[]b", "x", true),
+            "axb"
+        );
+    }
+
+    #[test]
+    fn test_quoted_in_class() {
+        let pattern = Pattern::new(r"[\d]").unwrap();
+        assert!(pattern.is_match("d"));
+        assert!(!pattern.is_match("1"));
+        let pattern = Pattern::new(r"[\w\-]").unwrap();
+        assert!(pattern.is_match("w"));
+        assert!(pattern.is_match("-"));
+        assert!(!pattern.is_match("a"));
+        assert!(!pattern.is_match("_"));
+    }
+}
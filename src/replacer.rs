@@ -4,413 +4,5952 @@
 
 #![allow(clippy::shadow_reuse)]
 
+use crate::arithmetic;
+use crate::command;
+use crate::filters::{self, Filters};
+use crate::lossless;
+use crate::pattern::Pattern;
+use crate::tools::StagedFile;
+use regex::Regex;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
 use std::io::{self, BufRead, Write};
+use std::iter;
+use std::mem;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 use typed_builder::TypedBuilder;
 
-fn replacement<S: ::std::hash::BuildHasher>(
+/// What to do with the value of a variable,
+/// as in `${KEY<operator><word>}`.
+///
+/// The words are either `&str`, as found in the input,
+/// or [`Parsed`], ready to be evaluated.
+enum Operator<W> {
+    /// `${KEY}`:
+    /// The plain value.
+    Value,
+    /// `${KEY:-word}` or `${KEY-word}`:
+    /// The value if set, `word` otherwise.
+    /// With the colon, an empty value counts as not set.
+    Default { colon: bool, word: W },
+    /// `${KEY:?message}` or `${KEY?message}`:
+    /// The value if set, an error with `message` otherwise,
+    /// regardless of [`Settings::fail_on_missing`].
+    /// With the colon, an empty value counts as not set.
+    Required { colon: bool, message: W },
+    /// `${KEY:+word}` or `${KEY+word}`:
+    /// `word` if the value is set, the empty string otherwise.
+    /// With the colon, an empty value counts as not set.
+    Alternative { colon: bool, word: W },
+    /// A transformation of the value, like in bash,
+    /// e.g. `${KEY#prefix}`.
+    Transform(Transform<W>),
+}
+
+/// A bash-style transformation of the value of a variable.
+///
+/// Patterns are shell-style globs (see [`Pattern`]),
+/// and all parts may contain variables.
+enum Transform<W> {
+    /// `${KEY#pattern}` or `${KEY##pattern}`:
+    /// Removes the shortest (or longest) prefix matching `pattern`.
+    RemovePrefix { longest: bool, pattern: W },
+    /// `${KEY%pattern}` or `${KEY%%pattern}`:
+    /// Removes the shortest (or longest) suffix matching `pattern`.
+    RemoveSuffix { longest: bool, pattern: W },
+    /// `${KEY/pattern/replacement}` or `${KEY//pattern/replacement}`:
+    /// Replaces the first (or all) longest match(es) of `pattern`.
+    Replace {
+        all: bool,
+        pattern: W,
+        replacement: W,
+    },
+    /// `${KEY^pattern}`, `${KEY^^pattern}`, `${KEY,pattern}` or `${KEY,,pattern}`:
+    /// Converts the first (or all) character(s) matching `pattern`
+    /// to upper- (`^`) or lower-case (`,`);
+    /// an empty pattern matches any character.
+    Case { upper: bool, all: bool, pattern: W },
+    /// `${#KEY}`:
+    /// The length of the value in characters.
+    Length,
+    /// `${KEY:offset}` or `${KEY:offset:length}`:
+    /// The part of the value starting at character `offset`,
+    /// which counts from the end if negative, e.g. `${KEY: -2}`.
+    /// A negative `length` counts from the end as well.
+    Substring { offset: W, length: Option<W> },
+}
+
+impl<W> Operator<W> {
+    /// Converts the words, e.g. parses them.
+    fn map<V>(self, mut convert: impl FnMut(W) -> V) -> Operator<V> {
+        match self {
+            Self::Value => Operator::Value,
+            Self::Default { colon, word } => Operator::Default {
+                colon,
+                word: convert(word),
+            },
+            Self::Required { colon, message } => Operator::Required {
+                colon,
+                message: convert(message),
+            },
+            Self::Alternative { colon, word } => Operator::Alternative {
+                colon,
+                word: convert(word),
+            },
+            Self::Transform(transform) => Operator::Transform(transform.map(convert)),
+        }
+    }
+}
+
+impl<W> Transform<W> {
+    /// Converts the words, e.g. parses them.
+    fn map<V>(self, mut convert: impl FnMut(W) -> V) -> Transform<V> {
+        match self {
+            Self::RemovePrefix { longest, pattern } => Transform::RemovePrefix {
+                longest,
+                pattern: convert(pattern),
+            },
+            Self::RemoveSuffix { longest, pattern } => Transform::RemoveSuffix {
+                longest,
+                pattern: convert(pattern),
+            },
+            Self::Replace {
+                all,
+                pattern,
+                replacement,
+            } => Transform::Replace {
+                all,
+                pattern: convert(pattern),
+                replacement: convert(replacement),
+            },
+            Self::Case {
+                upper,
+                all,
+                pattern,
+            } => Transform::Case {
+                upper,
+                all,
+                pattern: convert(pattern),
+            },
+            Self::Length => Transform::Length,
+            Self::Substring { offset, length } => Transform::Substring {
+                offset: convert(offset),
+                length: length.map(convert),
+            },
+        }
+    }
+}
+
+/// The parsed content of a variable,
+/// i.e. what is in between `${` and `}`.
+struct Expression<'t> {
+    key: &'t str,
+    /// The whole content (without filters),
+    /// if it was split into `key` and an operator not starting with ':',
+    /// e.g. `my-key` in `${my-key}`;
+    /// if a variable with this key is set, it is used instead
+    whole: Option<&'t str>,
+    /// Whether the value of `key` is the key of the variable to use,
+    /// as in `${!KEY}`
+    indirect: bool,
+    operator: Operator<&'t str>,
+    /// The filters to apply to the value, e.g. `trim|upper`;
+    /// empty if there are none
+    filters: &'t str,
+}
+
+impl<'t> Expression<'t> {
+    /// Parses the content of a variable, e.g. `KEY:-default`.
+    ///
+    /// If the content does not contain any (known) operator,
+    /// it is used as key as a whole.
+    /// The key may contain variables itself, e.g. `DB_URL_${STAGE}`.
+    /// Everything after the first '|' is a chain of filters,
+    /// e.g. `KEY:-default|trim|upper`.
+    fn parse(body: &'t str, syntax: &Syntax) -> Self {
+        if body.bytes().all(|byte| is_bare_key_char(char::from(byte))) {
+            // Just a key, as in most variables
+            return Self {
+                key: body,
+                whole: None,
+                indirect: false,
+                operator: Operator::Value,
+                filters: "",
+            };
+        }
+        let (body, filters) = find_outside_vars(body, syntax, &['|']).map_or((body, ""), |idx| {
+            let (body, filters) = body.split_at(idx);
+            (body, filters.get(1..).unwrap_or_default())
+        });
+        if let Some(key) = body.strip_prefix('#').filter(|key| !key.is_empty()) {
+            return Self {
+                key,
+                whole: None,
+                indirect: false,
+                operator: Operator::Transform(Transform::Length),
+                filters,
+            };
+        }
+        let (indirect, body) = body
+            .strip_prefix('!')
+            .filter(|key| !key.is_empty())
+            .map_or((false, body), |key| (true, key));
+        let plain = Self {
+            key: body,
+            whole: None,
+            indirect,
+            operator: Operator::Value,
+            filters,
+        };
+        let Some(op_start) =
+            find_outside_vars(body, syntax, &[':', '-', '?', '+', '#', '%', '/', '^', ','])
+        else {
+            return plain;
+        };
+        let (key, rest) = body.split_at(op_start);
+        // Except for ':', the operator characters may be part of a key,
+        // e.g. `${config.db-host}`, unless it is an identifier.
+        let (key, rest) = if rest.starts_with(':') || is_identifier(key, syntax) {
+            (key, rest)
+        } else if let Some(colon) = find_outside_vars(body, syntax, &[':']) {
+            body.split_at(colon)
+        } else {
+            return plain;
+        };
+        let Some(operator) = Self::parse_operator(rest, syntax) else {
+            return plain;
+        };
+        Self {
+            key,
+            whole: Some(body).filter(|_| !rest.starts_with(':')),
+            indirect,
+            operator,
+            filters,
+        }
+    }
+
+    /// Parses the operator part of the content of a variable,
+    /// e.g. `:-default` or `#prefix`.
+    fn parse_operator(rest: &'t str, syntax: &Syntax) -> Option<Operator<&'t str>> {
+        let mut rest_chars = rest.chars();
+        let op = rest_chars.next()?;
+        let after_op = rest_chars.as_str();
+        let (colon, posix_op) = match op {
+            ':' => match after_op.chars().next() {
+                Some(chr @ ('-' | '?' | '+')) => (true, chr),
+                Some(chr)
+                    if chr.is_ascii_digit()
+                        || chr == ' '
+                        || chr == '('
+                        || after_op.starts_with(&syntax.opening) =>
+                {
+                    let (offset, length) = split_once_outside_vars(after_op, syntax, ':');
+                    return Some(Operator::Transform(Transform::Substring { offset, length }));
+                }
+                _ => return None,
+            },
+            '#' | '%' => {
+                let (longest, pattern) = after_op
+                    .strip_prefix(op)
+                    .map_or((false, after_op), |pattern| (true, pattern));
+                return Some(Operator::Transform(if op == '#' {
+                    Transform::RemovePrefix { longest, pattern }
+                } else {
+                    Transform::RemoveSuffix { longest, pattern }
+                }));
+            }
+            '^' | ',' => {
+                let (all, pattern) = after_op
+                    .strip_prefix(op)
+                    .map_or((false, after_op), |pattern| (true, pattern));
+                return Some(Operator::Transform(Transform::Case {
+                    upper: op == '^',
+                    all,
+                    pattern,
+                }));
+            }
+            '/' => {
+                let (all, rest) = after_op
+                    .strip_prefix('/')
+                    .map_or((false, after_op), |rest| (true, rest));
+                let (pattern, replacement) = split_pattern(rest, syntax);
+                return Some(Operator::Transform(Transform::Replace {
+                    all,
+                    pattern,
+                    replacement: replacement.unwrap_or_default(),
+                }));
+            }
+            _ => (false, op),
+        };
+        let word = if colon {
+            after_op.get(1..).unwrap_or_default()
+        } else {
+            after_op
+        };
+        match posix_op {
+            '-' => Some(Operator::Default { colon, word }),
+            '?' => Some(Operator::Required {
+                colon,
+                message: word,
+            }),
+            '+' => Some(Operator::Alternative { colon, word }),
+            _ => None,
+        }
+    }
+
+    /// Returns the texts that may contain further variables,
+    /// e.g. the `word` in `${KEY:-word}`.
+    fn words(&self) -> Vec<&'t str> {
+        match self.operator {
+            Operator::Value | Operator::Transform(Transform::Length) => vec![],
+            Operator::Default { word, .. } | Operator::Alternative { word, .. } => vec![word],
+            Operator::Required { message, .. } => vec![message],
+            Operator::Transform(
+                Transform::RemovePrefix { pattern, .. }
+                | Transform::RemoveSuffix { pattern, .. }
+                | Transform::Case { pattern, .. },
+            ) => vec![pattern],
+            Operator::Transform(Transform::Replace {
+                pattern,
+                replacement,
+                ..
+            }) => vec![pattern, replacement],
+            Operator::Transform(Transform::Substring { offset, length }) => {
+                let mut words = vec![offset];
+                words.extend(length);
+                words
+            }
+        }
+    }
+}
+
+/// A variable, with all the texts in it parsed,
+/// so it can be evaluated many times without parsing it again,
+/// e.g. within a loop or a [`Template`].
+struct Var<'t> {
+    key: Parsed<'t>,
+    /// See [`Expression::whole`]
+    whole: Option<Parsed<'t>>,
+    /// See [`Expression::indirect`]
+    indirect: bool,
+    operator: Operator<Parsed<'t>>,
+    filters: Vec<Filter<'t>>,
+}
+
+impl<'t> Var<'t> {
+    /// Parses the content of a variable, e.g. `KEY:-default|trim`;
+    /// see [`Expression::parse`].
+    fn parse(body: &'t str, syntax: &Syntax) -> Self {
+        let expr = Expression::parse(body, syntax);
+        let parse = |text| Parsed::new(text, syntax);
+        let filters = if expr.filters.is_empty() {
+            vec![]
+        } else {
+            split_outside_vars(expr.filters, syntax, '|')
+                .into_iter()
+                .map(|filter| {
+                    let mut parts = split_outside_vars(filter, syntax, ':').into_iter();
+                    let name = parts.next().unwrap_or_default().trim();
+                    Filter {
+                        name,
+                        args: parts.map(parse).collect(),
+                    }
+                })
+                .collect()
+        };
+        Self {
+            key: parse(expr.key),
+            whole: expr.whole.map(parse),
+            indirect: expr.indirect,
+            operator: expr.operator.map(parse),
+            filters,
+        }
+    }
+}
+
+/// A filter applied to the value of a variable, e.g. `replace:a:b`.
+struct Filter<'t> {
+    name: &'t str,
+    args: Vec<Parsed<'t>>,
+}
+
+/// A text that may contain variables, e.g. the `word` in `${KEY:-word}`,
+/// parsed once, so it can be evaluated many times.
+struct Parsed<'t> {
+    raw: &'t str,
+    /// The nodes of `raw`, or why it could not be parsed,
+    /// which is only an error once it gets evaluated;
+    /// empty if there is nothing to replace in `raw`
+    nodes: Result<Vec<Node<'t>>, String>,
+}
+
+impl<'t> Parsed<'t> {
+    fn new(raw: &'t str, syntax: &Syntax) -> Self {
+        let nodes = if syntax.next_start(raw, 0) == raw.len() {
+            // Plain text, as most keys are
+            Ok(vec![])
+        } else {
+            parse_nodes(raw, syntax).map_err(|err| err.to_string())
+        };
+        Self { raw, nodes }
+    }
+
+    /// Replaces the variables in the text; see [`replace_in_context`].
+    fn expand<S: ::std::hash::BuildHasher>(
+        &self,
+        settings: &Settings<S>,
+        ctx: &mut Context,
+    ) -> io::Result<Cow<'t, str>> {
+        let nodes = self
+            .nodes
+            .as_ref()
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg.as_str()))?;
+        if let [] | [Node::Text(_)] = nodes.as_slice() {
+            // Nothing to replace
+            return Ok(Cow::Borrowed(self.raw));
+        }
+        let mut buff_out = String::with_capacity(self.raw.len() * 3 / 2);
+        if render(nodes, &mut buff_out, settings, ctx, None)? {
+            Ok(Cow::Owned(buff_out))
+        } else {
+            Ok(Cow::Borrowed(self.raw))
+        }
+    }
+}
+
+/// Whether `key` is an identifier, i.e. consists of ASCII letters, digits and '_',
+/// and possibly variables, e.g. `DB_URL_${STAGE}`.
+fn is_identifier(key: &str, syntax: &Syntax) -> bool {
+    if key.is_empty() {
+        return false;
+    }
+    if key.bytes().all(|byte| is_bare_key_char(char::from(byte))) {
+        return true;
+    }
+    syntax.next_start(key, 0) < key.len()
+        && tokenize(key, syntax).into_iter().all(|token| match token {
+            Token::Text(text) => text.chars().all(is_bare_key_char),
+            Token::Var { .. } => true,
+            Token::Quoted(_) | Token::Command(_) | Token::Arithmetic { .. } => false,
+        })
+}
+
+/// The state of a variable, as distinguished by POSIX shells.
+#[derive(Clone, Copy)]
+enum Value {
+    /// There is no entry for the key in [`Settings::vars`].
+    Unset,
+    /// The key maps to the empty string.
+    Empty,
+    /// The key maps to a non-empty string.
+    Set,
+}
+
+impl Value {
+    fn of(value: Option<&str>) -> Self {
+        match value {
+            None => Self::Unset,
+            Some("") => Self::Empty,
+            Some(_) => Self::Set,
+        }
+    }
+
+    /// Whether the variable counts as set.
+    /// If `colon` is `true` (as in `${KEY:-word}`),
+    /// an empty value counts as not set.
+    const fn is_set(self, colon: bool) -> bool {
+        match self {
+            Self::Unset => false,
+            Self::Empty => !colon,
+            Self::Set => true,
+        }
+    }
+}
+
+/// The state of the replacement of a piece of text.
+#[derive(Default)]
+struct Context {
+    /// The keys of the variables whose values are currently being expanded,
+    /// outermost first, if [`Settings::recursive`] is enabled
+    chain: Vec<String>,
+    /// The loop variables of the loops currently being rendered,
+    /// outermost first, e.g. `item` in `${for item in LIST}`
+    locals: Vec<(String, String)>,
+    /// The files currently being processed, outermost first,
+    /// i.e. the input file (if known) followed by the included ones
+    files: Vec<PathBuf>,
+    /// The already expanded values of [`Settings::vars`] by key,
+    /// if [`Settings::recursive`] is enabled.
+    /// As they might refer to loop variables or include files,
+    /// this is cleared whenever [`Self::locals`] or [`Self::files`] change.
+    expanded: HashMap<String, String>,
+    /// Whether the text was decoded from bytes (see [`lossless`]),
+    /// so the values inserted into it have to be decoded as well,
+    /// for them to be encoded back into the same bytes.
+    lossless: bool,
+}
+
+impl Context {
+    /// Converts a `value` from outside of the text,
+    /// e.g. of a variable or the output of a command,
+    /// to be inserted into the text; see [`Self::lossless`].
+    fn outside<'v>(&self, value: &'v str) -> Cow<'v, str> {
+        if self.lossless {
+            lossless::decode(value.as_bytes())
+        } else {
+            Cow::Borrowed(value)
+        }
+    }
+}
+
+/// Looks up the value of the variable `key`.
+///
+/// Loop variables (see [`Context::locals`]) take precedence
+/// over [`Settings::vars`].
+///
+/// If [`Settings::recursive`] is enabled,
+/// variables within the value get replaced as well,
+/// and the expanded value is kept in [`Context::expanded`],
+/// so each variable is expanded only once.
+fn lookup<'s, S: ::std::hash::BuildHasher>(
+    key: &str,
+    settings: &'s Settings<S>,
+    ctx: &mut Context,
+) -> io::Result<Option<Cow<'s, str>>> {
+    if let Some((_, value)) = ctx.locals.iter().rev().find(|(local, _)| local == key) {
+        return Ok(Some(Cow::Owned(value.clone())));
+    }
+    let Some(value) = settings.vars.get(key) else {
+        return Ok(None);
+    };
+    if !settings.recursive {
+        return Ok(Some(ctx.outside(value)));
+    }
+    if let Some(expanded) = ctx.expanded.get(key) {
+        return Ok(Some(Cow::Owned(expanded.clone())));
+    }
+    let describe_chain = |chain: &[String]| {
+        let mut keys = chain.to_vec();
+        keys.push(key.to_owned());
+        keys.join(" -> ")
+    };
+    if ctx.chain.iter().any(|expanding| expanding == key) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Cyclic variable reference: {}", describe_chain(&ctx.chain)),
+        ));
+    }
+    if ctx.chain.len() >= settings.max_depth {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Variable references nested more than {} levels deep: {}",
+                settings.max_depth,
+                describe_chain(&ctx.chain)
+            ),
+        ));
+    }
+    ctx.chain.push(key.to_owned());
+    let expanded = match ctx.outside(value) {
+        Cow::Borrowed(borrowed) => replace_in_context(borrowed, settings, ctx),
+        Cow::Owned(decoded) => replace_in_context(&decoded, settings, ctx)
+            .map(|replaced| Cow::Owned(replaced.into_owned())),
+    };
+    ctx.chain.pop();
+    let expanded = expanded?;
+    if expanded.len() > settings.max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Value of variable '{key}' is larger than {} bytes after expansion",
+                settings.max_size
+            ),
+        ));
+    }
+    ctx.expanded
+        .insert(key.to_owned(), expanded.clone().into_owned());
+    Ok(Some(expanded))
+}
+
+/// Whether the variable `key` is set,
+/// either as a loop variable or in [`Settings::vars`].
+fn is_set<S: ::std::hash::BuildHasher>(key: &str, settings: &Settings<S>, ctx: &Context) -> bool {
+    ctx.locals.iter().any(|(local, _)| local == key) || settings.vars.contains_key(key)
+}
+
+/// Evaluates a variable, e.g. `${KEY}` or `${KEY:-default}`.
+///
+/// Returns `None` if the variable is to be left as-is in the output.
+///
+/// `escaping` is the name of the escaping filter to apply to the value,
+/// unless the variable uses an escaping filter itself.
+fn replacement<'v, S: ::std::hash::BuildHasher>(
+    var: &Var<'v>,
+    settings: &'v Settings<S>,
+    ctx: &mut Context,
+    escaping: Option<&str>,
+) -> io::Result<Option<Cow<'v, str>>> {
+    // A set variable with the whole content as key, e.g. `${my-key}`,
+    // takes precedence over `my` with the default value `key`
+    let whole = var
+        .whole
+        .as_ref()
+        .map(|whole| whole.expand(settings, ctx))
+        .transpose()?
+        .filter(|whole| is_set(whole, settings, ctx));
+    let (key, operator) = match whole {
+        Some(whole) => (whole, &Operator::Value),
+        // This resolves variables within the key, e.g. `DB_URL_${STAGE}`
+        None => (var.key.expand(settings, ctx)?, &var.operator),
+    };
+    if let Some(only_keys) = &settings.only_keys {
+        if !only_keys.contains(key.as_ref()) {
+            return Ok(None);
+        }
+    }
+    let key = if var.indirect {
+        // The value of the variable is the key to use, e.g. `${!KEY}`
+        let Some(target) = lookup(&key, settings, ctx)? else {
+            return missing(&key, settings);
+        };
+        Cow::Owned(target.into_owned())
+    } else {
+        key
+    };
+    if settings.strict && !is_valid_key(&key, settings) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid key '{key}'"),
+        ));
+    }
+    let value = lookup(&key, settings, ctx)?;
+    let Some(replaced) = apply_operator(operator, &key, value, settings, ctx)? else {
+        return Ok(None);
+    };
+    apply_filters(replaced, &var.filters, &key, settings, ctx, escaping).map(Some)
+}
+
+/// Applies a chain of filters (e.g. `trim|replace:a:b`) to `value`,
+/// followed by the `escaping` filter,
+/// if none of the filters in the chain is an escaping one.
+fn apply_filters<'v, S: ::std::hash::BuildHasher>(
+    value: Cow<'v, str>,
+    filters: &[Filter],
+    key: &str,
+    settings: &Settings<S>,
+    ctx: &mut Context,
+    escaping: Option<&str>,
+) -> io::Result<Cow<'v, str>> {
+    let filter_error = |name: &str, err: filters::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Variable '{key}', filter '{name}': {err}"),
+        )
+    };
+    let mut value = value;
+    let mut escaped = false;
+    for filter in filters {
+        let args = filter
+            .args
+            .iter()
+            .map(|arg| arg.expand(settings, ctx))
+            .collect::<io::Result<Vec<_>>>()?;
+        let args = args.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+        value = settings
+            .filters
+            .apply(filter.name, &value, &args)
+            .map_err(|err| filter_error(filter.name, err))
+            .map(|filtered| keep_unchanged(value, filtered))?;
+        escaped |= settings.filters.is_escaping(filter.name);
+    }
+    if let Some(escaping) = escaping.filter(|_| !escaped) {
+        value = settings
+            .filters
+            .apply(escaping, &value, &[])
+            .map_err(|err| filter_error(escaping, err))
+            .map(|filtered| keep_unchanged(value, filtered))?;
+    }
+    Ok(value)
+}
+
+/// Returns `value` if `changed` equals it,
+/// which keeps it borrowed, if it was.
+fn keep_unchanged(value: Cow<'_, str>, changed: String) -> Cow<'_, str> {
+    if *value == changed {
+        value
+    } else {
+        Cow::Owned(changed)
+    }
+}
+
+/// Evaluates the operator of a variable, e.g. `:-default`.
+///
+/// Returns `None` if the variable is to be left as-is in the output.
+fn apply_operator<'v, S: ::std::hash::BuildHasher>(
+    operator: &Operator<Parsed<'v>>,
     key: &str,
+    value: Option<Cow<'v, str>>,
     settings: &Settings<S>,
-) -> io::Result<(bool, String)> {
-    settings.vars.get(key).map_or_else(
-        || {
-            if settings.fail_on_missing {
+    ctx: &mut Context,
+) -> io::Result<Option<Cow<'v, str>>> {
+    let is_set = |colon| Value::of(value.as_deref()).is_set(colon);
+    match *operator {
+        Operator::Value => value.map_or_else(|| missing(key, settings), |val| Ok(Some(val))),
+        Operator::Default { colon, ref word } => {
+            if is_set(colon) {
+                Ok(value)
+            } else {
+                word.expand(settings, ctx).map(Some)
+            }
+        }
+        Operator::Required { colon, ref message } => {
+            if is_set(colon) {
+                Ok(value)
+            } else {
+                let message = message.expand(settings, ctx)?;
+                let message = if message.is_empty() {
+                    if colon {
+                        "parameter null or not set"
+                    } else {
+                        "parameter not set"
+                    }
+                } else {
+                    &message
+                };
                 Err(io::Error::new(
                     io::ErrorKind::NotFound,
-                    format!("Undefined variable '{key}'"),
+                    format!("{key}: {message}"),
                 ))
+            }
+        }
+        Operator::Alternative { colon, ref word } => {
+            if is_set(colon) {
+                word.expand(settings, ctx).map(Some)
             } else {
-                Ok((false, format!("${{{key}}}")))
+                Ok(Some(Cow::Borrowed("")))
+            }
+        }
+        Operator::Transform(ref transform) => match value {
+            None => missing(key, settings),
+            Some(Cow::Borrowed(val)) => {
+                apply_transform(transform, key, val, settings, ctx).map(Some)
+            }
+            Some(Cow::Owned(val)) => {
+                let transformed = apply_transform(transform, key, &val, settings, ctx)?;
+                Ok(Some(match transformed {
+                    // Only a part of the value is left, e.g. `${KEY#prefix}`,
+                    // but only if it got shorter
+                    Cow::Borrowed(part) if part.len() < val.len() => Cow::Owned(part.to_owned()),
+                    Cow::Borrowed(_) => Cow::Owned(val),
+                    Cow::Owned(changed) => Cow::Owned(changed),
+                }))
             }
         },
-        |val| Ok((true, val.to_string())),
-    )
+    }
 }
 
-enum ReplState {
-    Text,
-    Dollar1,
-    Dollar2,
-    Key,
+/// Handles a variable without a value,
+/// which is either left as-is in the output,
+/// or an error if [`Settings::fail_on_missing`] is enabled.
+fn missing<T, S: ::std::hash::BuildHasher>(
+    key: &str,
+    settings: &Settings<S>,
+) -> io::Result<Option<T>> {
+    if settings.fail_on_missing {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Undefined variable '{key}'"),
+        ))
+    } else {
+        Ok(None)
+    }
 }
 
-#[derive(TypedBuilder)]
-pub struct Settings<S: ::std::hash::BuildHasher> {
-    vars: HashMap<String, String, S>,
-    #[builder(default = false)]
-    fail_on_missing: bool,
+/// Applies a bash-style transformation to the `value` of the variable `key`.
+fn apply_transform<'v, S: ::std::hash::BuildHasher>(
+    transform: &Transform<Parsed>,
+    key: &str,
+    value: &'v str,
+    settings: &Settings<S>,
+    ctx: &mut Context,
+) -> io::Result<Cow<'v, str>> {
+    let mut compile = |pattern: &Parsed| {
+        let pattern = pattern.expand(settings, ctx)?;
+        Pattern::new(&pattern).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Variable '{key}': Invalid pattern '{pattern}': {err}"),
+            )
+        })
+    };
+    match *transform {
+        Transform::RemovePrefix {
+            longest,
+            ref pattern,
+        } => Ok(Cow::Borrowed(
+            compile(pattern)?.remove_prefix(value, longest),
+        )),
+        Transform::RemoveSuffix {
+            longest,
+            ref pattern,
+        } => Ok(Cow::Borrowed(
+            compile(pattern)?.remove_suffix(value, longest),
+        )),
+        Transform::Replace {
+            all,
+            ref pattern,
+            ref replacement,
+        } => {
+            let pattern = compile(pattern)?;
+            let replacement = replacement.expand(settings, ctx)?;
+            Ok(pattern.replace(value, &replacement, all))
+        }
+        Transform::Case {
+            upper,
+            all,
+            ref pattern,
+        } => {
+            let pattern = if pattern.raw.is_empty() {
+                None
+            } else {
+                Some(compile(pattern)?)
+            };
+            let changed = change_case(value, upper, all, pattern.as_ref());
+            Ok(keep_unchanged(Cow::Borrowed(value), changed))
+        }
+        Transform::Length => Ok(Cow::Owned(value.chars().count().to_string())),
+        Transform::Substring {
+            ref offset,
+            ref length,
+        } => {
+            let offset = parse_number(offset, "offset", key, settings, ctx)?;
+            let length = length
+                .as_ref()
+                .map(|length| parse_number(length, "length", key, settings, ctx))
+                .transpose()?;
+            Ok(Cow::Borrowed(substring(value, offset, length)))
+        }
+    }
 }
 
-/// Settings builder macro.
+/// Converts the first (or all) character(s) of `value` matching `pattern`
+/// to upper- or lower-case.
+fn change_case(value: &str, upper: bool, all: bool, pattern: Option<&Pattern>) -> String {
+    let mut changed = String::with_capacity(value.len());
+    for (idx, chr) in value.chars().enumerate() {
+        let matches = pattern.is_none_or(|pattern| pattern.is_match(chr.encode_utf8(&mut [0; 4])));
+        if (all || idx == 0) && matches {
+            if upper {
+                changed.extend(chr.to_uppercase());
+            } else {
+                changed.extend(chr.to_lowercase());
+            }
+        } else {
+            changed.push(chr);
+        }
+    }
+    changed
+}
+
+/// Parses a (possibly negative) number, e.g. the offset in `${KEY:offset}`,
+/// which may be enclosed in parentheses, e.g. `(-2)`.
+fn parse_number<S: ::std::hash::BuildHasher>(
+    text: &Parsed,
+    what: &str,
+    key: &str,
+    settings: &Settings<S>,
+    ctx: &mut Context,
+) -> io::Result<i64> {
+    let text = text.expand(settings, ctx)?;
+    let trimmed = text.trim();
+    let number = trimmed
+        .strip_prefix('(')
+        .and_then(|inner| inner.strip_suffix(')'))
+        .unwrap_or(trimmed)
+        .trim();
+    number.parse().map_err(|_err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Variable '{key}': Invalid {what} '{text}'"),
+        )
+    })
+}
+
+/// Returns the characters of `value` starting at `offset`,
+/// at most `length` of them;
+/// negative values count from the end, like in bash.
+fn substring(value: &str, offset: i64, length: Option<i64>) -> &str {
+    let len = i64::try_from(value.chars().count()).unwrap_or(i64::MAX);
+    let start = if offset < 0 {
+        len.saturating_add(offset)
+    } else {
+        offset.min(len)
+    };
+    if start < 0 {
+        return "";
+    }
+    let end = match length {
+        None => len,
+        Some(length) if length < 0 => len.saturating_add(length),
+        Some(length) => start.saturating_add(length).min(len),
+    };
+    let skip = usize::try_from(start).unwrap_or_default();
+    let take = usize::try_from(end.saturating_sub(start)).unwrap_or_default();
+    // The byte index of the character at `idx`
+    let boundary = |idx| {
+        value
+            .char_indices()
+            .nth(idx)
+            .map_or(value.len(), |(pos, _)| pos)
+    };
+    let first = boundary(skip);
+    value
+        .get(first..boundary(skip + take).max(first))
+        .unwrap_or_default()
+}
+
+/// A piece of input text, as recognized by [`tokenize`].
+#[derive(Clone, Copy)]
+enum Token<'t> {
+    /// Text to be copied to the output as-is.
+    Text(&'t str),
+    /// A quoted variable start, e.g. `$${`,
+    /// with the escape sequence (`$`) already removed.
+    Quoted(&'t str),
+    /// A variable, e.g. `${KEY}`, `${KEY:-${OTHER}}` or `$KEY`.
+    Var {
+        /// The whole variable, including the delimiters, e.g. `${KEY}` or `$KEY`
+        raw: &'t str,
+        /// The content of the variable, e.g. `KEY`
+        body: &'t str,
+    },
+    /// A command substitution, e.g. `$(git rev-parse HEAD)`,
+    /// with only the command itself, e.g. `git rev-parse HEAD`.
+    Command(&'t str),
+    /// An arithmetic expansion, e.g. `$((BASE_PORT + 1))`.
+    Arithmetic {
+        /// The whole expansion, e.g. `$((BASE_PORT + 1))`
+        raw: &'t str,
+        /// The expression, e.g. `BASE_PORT + 1`
+        expr: &'t str,
+    },
+}
+
+/// Whether `chr` may be the first character of a bare variable (`$KEY`).
+const fn is_bare_key_start(chr: char) -> bool {
+    chr == '_' || chr.is_ascii_alphabetic()
+}
+
+/// Whether `chr` may be part of a bare variable (`$KEY`).
+const fn is_bare_key_char(chr: char) -> bool {
+    chr == '_' || chr.is_ascii_alphanumeric()
+}
+
+/// Collects the tokens found by [`tokenize`].
+struct TokenCollector<'t> {
+    input: &'t str,
+    tokens: Vec<Token<'t>>,
+    /// Start of the text that was not yet added to `tokens`
+    text_start: usize,
+}
+
+impl<'t> TokenCollector<'t> {
+    fn slice(&self, start: usize, end: usize) -> &'t str {
+        self.input
+            .get(start..end)
+            .expect("Bad indices for a token; should be impossible due to the logic we use.")
+    }
+
+    /// Adds the text before `start` (if any) and then `token`,
+    /// and continues the text after `end`.
+    fn push(&mut self, start: usize, token: Token<'t>, end: usize) {
+        if self.text_start < start {
+            self.tokens
+                .push(Token::Text(self.slice(self.text_start, start)));
+        }
+        self.tokens.push(token);
+        self.text_start = end;
+    }
+
+    /// Adds a quoted variable start,
+    /// which is prefixed by the escape sequence at `start`,
+    /// and starts itself at `quoted_start`.
+    fn push_quoted(&mut self, start: usize, quoted_start: usize, end: usize) {
+        let quoted = self.slice(quoted_start, end);
+        self.push(start, Token::Quoted(quoted), end);
+    }
+
+    /// Adds a variable, starting with its opening delimiter at `start`,
+    /// and its body spanning from `body_start` to `body_end`.
+    fn push_var(&mut self, start: usize, body_start: usize, body_end: usize, end: usize) {
+        let var = Token::Var {
+            raw: self.slice(start, end),
+            body: self.slice(body_start, body_end),
+        };
+        self.push(start, var, end);
+    }
+
+    /// Adds a command substitution of length `len` at `start`.
+    fn push_command(&mut self, start: usize, len: usize) {
+        let command = Token::Command(self.slice(start + 2, start + len - 1));
+        self.push(start, command, start + len);
+    }
+
+    /// Adds an arithmetic expansion of length `len` at `start`.
+    fn push_arithmetic(&mut self, start: usize, len: usize) {
+        let arithmetic = Token::Arithmetic {
+            raw: self.slice(start, start + len),
+            expr: self.slice(start + 3, start + len - 2),
+        };
+        self.push(start, arithmetic, start + len);
+    }
+
+    fn finish(mut self) -> Vec<Token<'t>> {
+        let len = self.input.len();
+        if self.text_start < len {
+            self.tokens
+                .push(Token::Text(self.slice(self.text_start, len)));
+        }
+        self.tokens
+    }
+}
+
+/// Returns the length of the bare variable (`$KEY`) at the start of `input`,
+/// or `None` if there is none.
+fn bare_var_len(input: &str) -> Option<usize> {
+    let key = input.strip_prefix('$')?;
+    if !key.starts_with(is_bare_key_start) {
+        return None;
+    }
+    let key_len = key.find(|chr| !is_bare_key_char(chr)).unwrap_or(key.len());
+    Some(1 + key_len)
+}
+
+/// Returns the length of the command substitution (`$(command)`)
+/// at the start of `input`,
+/// or `None` if there is none.
 ///
-/// This macro generates builder code,
-/// which uses code auto-generated by `TypedBuilder`
-/// from the external macro `typed_builder`.
+/// Parentheses have to be balanced within the command,
+/// e.g. `$(echo $(date))`.
+fn command_len(input: &str) -> Option<usize> {
+    let command = input.strip_prefix("$(")?;
+    let mut depth = 0_usize;
+    for (idx, chr) in command.char_indices() {
+        match chr {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(2 + idx + 1),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns the length of the arithmetic expansion (`$((expr))`)
+/// at the start of `input`,
+/// or `None` if there is none.
+fn arithmetic_len(input: &str) -> Option<usize> {
+    let expr = input.strip_prefix("$((")?;
+    let mut depth = 0_usize;
+    for (idx, chr) in expr.char_indices() {
+        match chr {
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                return expr
+                    .get(idx + 1..)
+                    .filter(|rest| rest.starts_with(')'))
+                    .map(|_| 3 + idx + 2);
+            }
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Finds the end of a variable body starting at `start`,
+/// which is terminated by the closing delimiter.
+/// Returns the start and end index of that closing delimiter,
+/// or `None` if the variable is unterminated.
 ///
-/// ```rust
-/// # use repvar::replacer::Settings;
-/// # use repvar::settings;
-/// # use std::collections::HashMap;
-/// let mut vars = HashMap::new();
-/// settings! {vars: vars};
-/// // expands to:
-/// # let mut vars = HashMap::new();
-/// Settings::builder().vars(vars).build();
-/// ```
-#[allow(unused_macros)]
-#[macro_export]
-macro_rules! settings {
-    // match-like arm for the macro
-    ($($p:ident:$v:expr),*) => {
-        // the macro expands to this code
+/// Opening and closing delimiters have to be balanced within the body,
+/// which allows for nested variables like `${KEY:-${OTHER}}`.
+///
+/// With equal opening and closing delimiters (e.g. `%KEY%`),
+/// the body can not contain whitespace,
+/// so a lone delimiter, e.g. in `100% %KEY%`,
+/// does not pair with the opening of the next variable.
+fn find_body_end(input: &str, start: usize, syntax: &Syntax) -> Option<(usize, usize)> {
+    let input = if syntax.opening == syntax.closing {
+        let body = input.get(start..)?;
+        let end = body
+            .find(char::is_whitespace)
+            .map_or(input.len(), |ws| start + ws);
+        input.get(..end)?
+    } else {
+        input
+    };
+    let mut depth = 0_usize;
+    let mut idx = start;
+    while let Some(rest) = input.get(idx..) {
+        if let Some(len) = syntax.quoted_len(rest) {
+            idx += len;
+        } else if rest.starts_with(&syntax.closing) {
+            if depth == 0 {
+                return Some((idx, idx + syntax.closing.len()));
+            }
+            depth -= 1;
+            idx += syntax.closing.len();
+        } else if rest.starts_with(&syntax.opening) {
+            depth += 1;
+            idx += syntax.opening.len();
+        } else {
+            let delimiters = [syntax.escape.sequence(), &syntax.closing, &syntax.opening];
+            let firsts = delimiters
+                .into_iter()
+                .filter_map(|delim| delim.bytes().next());
+            idx = find_any(input, idx + rest.chars().next()?.len_utf8(), firsts);
+        }
+    }
+    None
+}
 
-        // This is always there
-        Settings::builder()
-            // This appears as many times as there are arguments
-            $(.$p($v))*
-            // This too is always there
-            .build()
+/// Returns the index of the first occurrence of any of the (up to three) bytes
+/// `needles` in `input` at or after `from`,
+/// or the length of `input` if there is none.
+fn find_any(input: &str, from: usize, needles: impl IntoIterator<Item = u8>) -> usize {
+    let haystack = input.as_bytes().get(from..).unwrap_or_default();
+    let mut needles = needles.into_iter();
+    let Some(first) = needles.next() else {
+        return input.len();
+    };
+    let second = needles.next().unwrap_or(first);
+    let third = needles.next().unwrap_or(second);
+    memchr::memchr3(first, second, third, haystack).map_or(input.len(), |idx| from + idx)
+}
+
+/// Returns the index of the first occurrence of `needle` in `haystack`.
+///
+/// Unlike [`str::find`], this does not set up a searcher
+/// that only pays off for long needles,
+/// which matters for the short delimiters searched for in every piece of text.
+fn find_str(haystack: &str, needle: &str) -> Option<usize> {
+    let Some(&first) = needle.as_bytes().first() else {
+        return Some(0);
+    };
+    memchr::memchr_iter(first, haystack.as_bytes()).find(|&idx| {
+        haystack
+            .as_bytes()
+            .get(idx..)
+            .is_some_and(|rest| rest.starts_with(needle.as_bytes()))
+    })
+}
+
+/// Finds the first occurrence of any of `chars` in `input`,
+/// skipping quoted variable starts and (nested) variables.
+fn find_outside_vars(input: &str, syntax: &Syntax, chars: &[char]) -> Option<usize> {
+    // Only these may start a quoted variable start or a variable
+    let starts = [syntax.escape.sequence(), &syntax.opening].map(|delim| delim.chars().next());
+    let mut idx = 0;
+    while let Some(rest) = input.get(idx..) {
+        if let Some(len) = syntax.quoted_len(rest) {
+            idx += len;
+            continue;
+        }
+        if !syntax.opening.is_empty() && rest.starts_with(&syntax.opening) {
+            if let Some((_, end)) = find_body_end(input, idx + syntax.opening.len(), syntax) {
+                idx = end;
+                continue;
+            }
+        }
+        let chr = rest.chars().next()?;
+        if chars.contains(&chr) {
+            return Some(idx);
+        }
+        idx += rest
+            .char_indices()
+            .skip(1)
+            .find(|&(_, chr)| chars.contains(&chr) || starts.contains(&Some(chr)))
+            .map_or(rest.len(), |(next, _)| next);
     }
+    None
 }
-#[allow(unused_imports)]
-pub use settings;
 
-/// Extracts all occurrences of variables of the form `${KEY}` in a string
-/// in the order and amount they appear in the input.
+/// Splits `input` at the first occurrence of `separator`
+/// that is not within a (nested) variable.
+fn split_once_outside_vars<'t>(
+    input: &'t str,
+    syntax: &Syntax,
+    separator: char,
+) -> (&'t str, Option<&'t str>) {
+    find_outside_vars(input, syntax, &[separator]).map_or((input, None), |idx| {
+        let (before, after) = input.split_at(idx);
+        (before, after.get(separator.len_utf8()..))
+    })
+}
+
+/// Splits `pattern/replacement` (as in `${KEY/pattern/replacement}`)
+/// at the first '/' that is neither quoted with '\\'
+/// nor within a (nested) variable.
+fn split_pattern<'t>(input: &'t str, syntax: &Syntax) -> (&'t str, Option<&'t str>) {
+    let mut start = 0;
+    while let Some(idx) = input
+        .get(start..)
+        .and_then(|rest| find_outside_vars(rest, syntax, &['/']))
+    {
+        let pos = start + idx;
+        if input
+            .get(..pos)
+            .is_some_and(|before| before.ends_with('\\'))
+        {
+            start = pos + 1;
+        } else {
+            return (input.get(..pos).unwrap_or(input), input.get(pos + 1..));
+        }
+    }
+    (input, None)
+}
+
+/// Splits `input` at every occurrence of `separator`,
+/// except for those within (nested) variables.
+fn split_outside_vars<'t>(input: &'t str, syntax: &Syntax, separator: char) -> Vec<&'t str> {
+    let mut parts = vec![];
+    let mut rest = input;
+    while let Some(idx) = find_outside_vars(rest, syntax, &[separator]) {
+        let (part, after) = rest.split_at(idx);
+        parts.push(part);
+        rest = after.get(separator.len_utf8()..).unwrap_or_default();
+    }
+    parts.push(rest);
+    parts
+}
+
+/// Splits the input into pieces of text, quoted variable starts, variables,
+/// arithmetic expansions and command substitutions.
 ///
-/// ```rust
-/// # use repvar::replacer::extract_from_string;
-/// let input = "a ${key_a} $${key_a} b ${key_b} c d ${key_a}e";
-/// let expected = vec!("key_a", "key_b", "key_a");
-/// let actual = extract_from_string(input);
-/// assert_eq!(expected, actual);
-/// ```
+/// Within a variable, the opening and closing delimiters have to be balanced,
+/// which allows for nested variables like `${KEY:-${OTHER}}`.
+/// The opening of an unterminated variable is plain text.
 ///
 /// # Panics
 ///
-/// In the theoretically impossible case of invalid key-name indices.
-#[must_use]
-pub fn extract_from_string(input: &'_ str) -> Vec<&'_ str> {
-    let mut state = ReplState::Text;
-    let mut key_start = 0;
-    let mut keys = vec![];
-    for (idx, chr) in input.char_indices() {
-        match state {
-            ReplState::Text => {
-                if chr == '$' {
-                    state = ReplState::Dollar1;
-                }
+/// In the theoretically impossible case of invalid indices.
+fn tokenize<'t>(input: &'t str, syntax: &Syntax) -> Vec<Token<'t>> {
+    let mut tokens = TokenCollector {
+        input,
+        tokens: vec![],
+        text_start: 0,
+    };
+    let mut idx = 0;
+    while let Some(rest) = input.get(idx..) {
+        if let Some(run) = syntax.escape_run(rest) {
+            let escape_len = syntax.escape.sequence().len();
+            let (literal, quotes) = syntax.escape_runs.read(run);
+            let literal_end = idx + (run - usize::from(quotes)) * escape_len;
+            if literal_end - idx > literal * escape_len {
+                // E.g. each pair of escape sequences stands for one
+                tokens.push_quoted(idx, literal_end - literal * escape_len, literal_end);
             }
-            ReplState::Dollar1 => {
-                if chr == '$' {
-                    state = ReplState::Dollar2;
-                } else if chr == '{' {
-                    state = ReplState::Key;
-                    key_start = idx + 1;
-                } else {
-                    state = ReplState::Text;
-                }
-            }
-            ReplState::Dollar2 => {
-                if chr != '$' {
-                    state = ReplState::Text;
-                }
+            idx = literal_end;
+            if !quotes {
+                continue;
             }
-            ReplState::Key => {
-                if chr == '}' {
-                    keys.push(input.get(key_start..idx).expect("Bad indices for the name part; should be impossilbe due to the logic we use."));
-                    state = ReplState::Text;
+            let quoted_start = idx + escape_len;
+            let Some(len) = input.get(quoted_start..).and_then(|r| syntax.start_len(r)) else {
+                break;
+            };
+            let mut end = quoted_start + len;
+            let quotes_opening = input
+                .get(quoted_start..)
+                .is_some_and(|quoted| quoted.starts_with(&syntax.opening));
+            if quotes_opening && syntax.opening == syntax.closing {
+                // With symmetric delimiters (e.g. `@KEY@`),
+                // the closing delimiter of a quoted variable
+                // would otherwise open a new one,
+                // so we quote the whole variable.
+                if let Some(close) = input.get(end..).and_then(|r| r.find(&syntax.closing)) {
+                    end += close + syntax.closing.len();
                 }
             }
+            // Remove the escape sequence,
+            // so "$${key_" -> "${key_",
+            // for example
+            tokens.push_quoted(idx, quoted_start, end);
+            idx = end;
+        } else if !syntax.opening.is_empty() && rest.starts_with(&syntax.opening) {
+            let body_start = idx + syntax.opening.len();
+            let Some((body_end, end)) = find_body_end(input, body_start, syntax) else {
+                // unterminated variable -> the opening is plain text
+                idx = body_start;
+                continue;
+            };
+            tokens.push_var(idx, body_start, body_end, end);
+            idx = end;
+        } else if let Some(len) = bare_var_len(rest).filter(|_| syntax.bare_vars) {
+            tokens.push_var(idx, idx + 1, idx + len, idx + len);
+            idx += len;
+        } else if let Some(len) = arithmetic_len(rest).filter(|_| syntax.arithmetic) {
+            tokens.push_arithmetic(idx, len);
+            idx += len;
+        } else if let Some(len) = command_len(rest).filter(|_| syntax.commands) {
+            tokens.push_command(idx, len);
+            idx += len;
+        } else if let Some(chr) = rest.chars().next() {
+            idx = syntax.next_start(input, idx + chr.len_utf8());
+        } else {
+            break;
         }
     }
 
-    keys
-}
+    tokens.finish()
+}
+
+/// A directive, e.g. `${if KEY}`, `${for item in LIST}`, `${else}`, `${end}`
+/// or `${include:header.txt}`.
+#[derive(Clone, Copy)]
+enum Directive<'t> {
+    /// Starts a conditional block, e.g. `${if KEY}`
+    If(Condition<&'t str>),
+    /// Starts a loop, e.g. `${for item in LIST}`
+    For(Loop<'t, &'t str>),
+    /// Starts the alternative part of a conditional block
+    Else,
+    /// Ends a conditional block or a loop
+    End,
+    /// Inserts the (replaced) content of a file, e.g. `${include:header.txt}`
+    Include(&'t str),
+}
+
+impl<'t> Directive<'t> {
+    /// Parses the content of a variable as a directive,
+    /// e.g. `if KEY == value`.
+    ///
+    /// `${else}` and `${end}` only count as directives
+    /// within a block (`in_block`),
+    /// so variables with these keys keep working elsewhere.
+    fn parse(body: &'t str, syntax: &Syntax, in_block: bool) -> Option<Self> {
+        let trimmed = body.trim();
+        match trimmed {
+            "else" if in_block => Some(Self::Else),
+            "end" if in_block => Some(Self::End),
+            _ => trimmed
+                .strip_prefix("include:")
+                .map(|path| Self::Include(path.trim()))
+                .or_else(|| {
+                    trimmed
+                        .strip_prefix("if ")
+                        .map(|condition| Self::If(Condition::parse(condition.trim(), syntax)))
+                })
+                .or_else(|| {
+                    trimmed
+                        .strip_prefix("for ")
+                        .and_then(Loop::parse)
+                        .map(Self::For)
+                }),
+        }
+    }
+}
+
+/// The head of a loop, e.g. `${for item in LIST}`
+/// or `${for index, item in LIST}`.
+///
+/// The key of the list is either a `&str`, as found in the input,
+/// or [`Parsed`], ready to be evaluated.
+#[derive(Clone, Copy)]
+struct Loop<'t, W> {
+    /// The name of the loop variable
+    item: &'t str,
+    /// The name of the variable containing the index of the item, starting at 0
+    index: Option<&'t str>,
+    /// The key of the variable containing the list to iterate over
+    list: W,
+}
+
+impl<'t> Loop<'t, &'t str> {
+    fn parse(text: &'t str) -> Option<Self> {
+        let (names, list) = text.split_once(" in ")?;
+        let (index, item) = names
+            .split_once(',')
+            .map_or((None, names), |(index, item)| (Some(index.trim()), item));
+        Some(Self {
+            item: item.trim(),
+            index,
+            list: list.trim(),
+        })
+    }
+
+    /// The names of the loop variables.
+    fn locals(&self) -> impl Iterator<Item = &'t str> {
+        iter::once(self.item).chain(self.index)
+    }
+
+    /// Converts the key of the list, e.g. parses it.
+    fn map<V>(self, convert: impl FnOnce(&'t str) -> V) -> Loop<'t, V> {
+        Loop {
+            item: self.item,
+            index: self.index,
+            list: convert(self.list),
+        }
+    }
+}
+
+impl Loop<'_, Parsed<'_>> {
+    fn evaluate<S: ::std::hash::BuildHasher>(
+        &self,
+        body: &[Node],
+        buff_out: &mut String,
+        settings: &Settings<S>,
+        ctx: &mut Context,
+        escaping: Option<&str>,
+    ) -> io::Result<()> {
+        let key = self.list.expand(settings, ctx)?;
+        let Some(list) = lookup(&key, settings, ctx)? else {
+            return missing::<(), _>(&key, settings).map(|_| ());
+        };
+        for (idx, item) in split_list(&list).into_iter().enumerate() {
+            let locals_len = ctx.locals.len();
+            ctx.locals.push((self.item.to_owned(), item.to_owned()));
+            if let Some(index) = self.index {
+                ctx.locals.push((index.to_owned(), idx.to_string()));
+            }
+            ctx.expanded.clear();
+            let rendered = render(body, buff_out, settings, ctx, escaping);
+            ctx.locals.truncate(locals_len);
+            ctx.expanded.clear();
+            rendered?;
+        }
+        Ok(())
+    }
+}
+
+/// What is checked about a variable in `${if ...}`.
+#[derive(Clone, Copy)]
+enum Test<W> {
+    /// `${if defined KEY}`:
+    /// The variable is set, possibly to the empty string.
+    Defined,
+    /// `${if KEY}`:
+    /// The variable is set to a non-empty value.
+    NonEmpty,
+    /// `${if KEY == value}`:
+    /// The variable is set to `value`.
+    Equals(W),
+}
+
+/// The condition of a conditional block, e.g. the `KEY` in `${if KEY}`.
+///
+/// The words are either `&str`, as found in the input,
+/// or [`Parsed`], ready to be evaluated.
+#[derive(Clone, Copy)]
+struct Condition<W> {
+    key: W,
+    test: Test<W>,
+    /// `${if not KEY}` or `${if KEY != value}`
+    negate: bool,
+}
+
+impl<'t> Condition<&'t str> {
+    fn parse(text: &'t str, syntax: &Syntax) -> Self {
+        let (negate, text) = text
+            .strip_prefix("not ")
+            .map_or((false, text), |rest| (true, rest.trim_start()));
+        if let Some(key) = text.strip_prefix("defined ") {
+            return Self {
+                key: key.trim(),
+                test: Test::Defined,
+                negate,
+            };
+        }
+        let mut start = 0;
+        while let Some(idx) = text
+            .get(start..)
+            .and_then(|rest| find_outside_vars(rest, syntax, &['=', '!']))
+        {
+            let op_start = start + idx;
+            let op = text.get(op_start..op_start + 2);
+            if let Some(equals @ ("==" | "!=")) = op {
+                let key = text.get(..op_start).unwrap_or_default().trim();
+                let value = text.get(op_start + 2..).unwrap_or_default().trim();
+                let value = ['"', '\'']
+                    .iter()
+                    .find_map(|&quote| value.strip_prefix(quote)?.strip_suffix(quote))
+                    .unwrap_or(value);
+                return Self {
+                    key,
+                    test: Test::Equals(value),
+                    negate: negate != (equals == "!="),
+                };
+            }
+            start = op_start + 1;
+        }
+        Self {
+            key: text.trim(),
+            test: Test::NonEmpty,
+            negate,
+        }
+    }
+
+    /// Returns the texts that may contain variables.
+    fn words(&self) -> Vec<&'t str> {
+        match self.test {
+            Test::Defined | Test::NonEmpty => vec![self.key],
+            Test::Equals(value) => vec![self.key, value],
+        }
+    }
+
+    /// Converts the words, e.g. parses them.
+    fn map<V>(self, mut convert: impl FnMut(&'t str) -> V) -> Condition<V> {
+        Condition {
+            key: convert(self.key),
+            test: match self.test {
+                Test::Defined => Test::Defined,
+                Test::NonEmpty => Test::NonEmpty,
+                Test::Equals(value) => Test::Equals(convert(value)),
+            },
+            negate: self.negate,
+        }
+    }
+}
+
+impl Condition<Parsed<'_>> {
+    fn evaluate<S: ::std::hash::BuildHasher>(
+        &self,
+        settings: &Settings<S>,
+        ctx: &mut Context,
+    ) -> io::Result<bool> {
+        let key = self.key.expand(settings, ctx)?;
+        let value = lookup(&key, settings, ctx)?;
+        let result = match self.test {
+            Test::Defined => value.is_some(),
+            Test::NonEmpty => value.is_some_and(|val| !val.is_empty()),
+            Test::Equals(ref expected) => {
+                let expected = expected.expand(settings, ctx)?;
+                value.is_some_and(|val| val == expected)
+            }
+        };
+        Ok(result != self.negate)
+    }
+}
+
+/// A piece of parsed input text.
+enum Node<'t> {
+    /// Text to be copied to the output as-is
+    Text(&'t str),
+    /// A quoted variable start, e.g. `${` from `$${`
+    Quoted(&'t str),
+    /// A variable, e.g. `${KEY:-default}`
+    Var {
+        /// The whole variable, including the delimiters
+        raw: &'t str,
+        var: Var<'t>,
+    },
+    /// A command substitution, e.g. `$(git rev-parse HEAD)`
+    Command(Parsed<'t>),
+    /// An arithmetic expansion, e.g. `$((BASE_PORT + 1))`
+    Arithmetic {
+        /// The whole expansion
+        raw: &'t str,
+        /// The expression, e.g. `BASE_PORT + 1`
+        expr: &'t str,
+        /// The pieces of the expression, which contain no blocks
+        parts: Vec<Self>,
+    },
+    /// A conditional block, e.g. `${if KEY}...${else}...${end}`
+    If {
+        condition: Condition<Parsed<'t>>,
+        then: Vec<Self>,
+        otherwise: Vec<Self>,
+    },
+    /// A loop, e.g. `${for item in LIST}...${end}`
+    For {
+        head: Loop<'t, Parsed<'t>>,
+        body: Vec<Self>,
+    },
+    /// An included file, e.g. `${include:header.txt}`
+    Include(Parsed<'t>),
+}
+
+impl<'t> Node<'t> {
+    /// Parses a token that is not a directive.
+    fn parse(token: Token<'t>, syntax: &Syntax) -> Self {
+        match token {
+            Token::Text(text) => Self::Text(text),
+            Token::Quoted(text) => Self::Quoted(text),
+            Token::Var { raw, body } => Self::Var {
+                raw,
+                var: Var::parse(body, syntax),
+            },
+            Token::Command(command) => Self::Command(Parsed::new(command, syntax)),
+            Token::Arithmetic { raw, expr } => Self::Arithmetic {
+                raw,
+                expr,
+                parts: tokenize(expr, syntax)
+                    .into_iter()
+                    .map(|token| Self::parse(token, syntax))
+                    .collect(),
+            },
+        }
+    }
+}
+
+/// Finds the block directives in `tokens`,
+/// starting within `depth` nested blocks.
+///
+/// Returns the directive (if any) for each token,
+/// and the depth after the last token.
+fn find_directives<'t>(
+    tokens: &[Token<'t>],
+    syntax: &Syntax,
+    depth: usize,
+) -> (Vec<Option<Directive<'t>>>, usize) {
+    let mut depth = depth;
+    let directives = tokens
+        .iter()
+        .map(|token| {
+            let Token::Var { body, .. } = *token else {
+                return None;
+            };
+            let directive = Directive::parse(body, syntax, depth > 0);
+            match directive {
+                Some(Directive::If(_) | Directive::For(_)) => depth += 1,
+                Some(Directive::End) => depth -= 1,
+                Some(Directive::Else | Directive::Include(_)) | None => {}
+            }
+            directive
+        })
+        .collect();
+    (directives, depth)
+}
+
+/// The places at which an input may be split into pieces
+/// that can be replaced independently of each other,
+/// as found by [`split_points`].
+#[derive(Default)]
+struct SplitPoints<'t> {
+    /// The ends of lines (just after the line ending), in order;
+    /// only those before lines with a token (e.g. a placeholder),
+    /// and the last one
+    lines: Vec<usize>,
+    /// The last place, which may be within a line
+    last: Option<usize>,
+    /// Where to continue, once more input was appended
+    resume: Scan,
+    /// The tokens of the input after the start of the scan,
+    /// so the pieces do not have to be tokenized again
+    tokens: Vec<Token<'t>>,
+    /// The directives of `tokens`, see [`find_directives`]
+    directives: Vec<Option<Directive<'t>>>,
+}
+
+/// How far [`split_points`] got in an input,
+/// so it can continue from there once more input was appended,
+/// instead of scanning all of it again.
+#[derive(Clone, Copy, Default)]
+struct Scan {
+    /// Where to continue tokenizing;
+    /// the tokens before can not change anymore
+    start: usize,
+    /// The depth of blocks at `start`
+    depth: usize,
+    /// The last place that may be split at before `start`
+    last: Option<usize>,
+}
+
+impl Scan {
+    /// The state for the input without its first `taken` bytes.
+    fn skip(self, taken: usize) -> Self {
+        if self.start < taken {
+            // The input is only ever split outside of blocks
+            Self::default()
+        } else {
+            Self {
+                start: self.start - taken,
+                depth: self.depth,
+                last: self
+                    .last
+                    .and_then(|last| last.checked_sub(taken))
+                    .filter(|&last| last > 0),
+            }
+        }
+    }
+}
+
+/// Returns the end of `token` within `input`, of which it has to be a slice.
+fn token_end(input: &str, token: &Token) -> usize {
+    match *token {
+        Token::Text(part) | Token::Quoted(part) => offset_of(input, part) + part.len(),
+        Token::Var { raw, .. } | Token::Arithmetic { raw, .. } => offset_of(input, raw) + raw.len(),
+        // The closing ')' is not part of the command
+        Token::Command(command) => offset_of(input, command) + command.len() + 1,
+    }
+}
+
+/// Whether the input may be split just after `chr`,
+/// because it can neither be part of the start of a placeholder
+/// nor of an escape sequence, which might continue after it.
+fn is_plain(chr: char, syntax: &Syntax) -> bool {
+    let special = matches!(chr, '$' | '(' | ')')
+        || (syntax.bare_vars && is_bare_key_char(chr))
+        || syntax.opening.contains(chr)
+        || syntax.escape.sequence().contains(chr);
+    !special
+}
+
+/// Returns the start of the first unterminated placeholder
+/// in a text token (see [`tokenize`]), if any.
+fn unterminated_placeholder(text: &str, syntax: &Syntax) -> Option<usize> {
+    if syntax.opening.is_empty() {
+        None
+    } else if syntax.opening == syntax.closing {
+        // Only whitespace ends the search for the closing delimiter,
+        // see `find_body_end`
+        text.rfind(&syntax.opening).filter(|&idx| {
+            text.get(idx..)
+                .is_some_and(|rest| !rest.contains(char::is_whitespace))
+        })
+    } else {
+        find_str(text, &syntax.opening)
+    }
+}
+
+/// Returns the start of the first unterminated placeholder,
+/// arithmetic expansion or command substitution
+/// in a text token (see [`tokenize`]), if any.
+fn unterminated_start(text: &str, syntax: &Syntax) -> Option<usize> {
+    let placeholder = unterminated_placeholder(text, syntax);
+    let substitution = find_str(text, "$(").filter(|_| syntax.commands || syntax.arithmetic);
+    placeholder.into_iter().chain(substitution).min()
+}
+
+/// Returns the start and the name of the first unterminated placeholder,
+/// arithmetic expansion or command substitution
+/// in a text token (see [`tokenize`]), if any,
+/// considering only the latter two if they are enabled in `syntax`.
+fn unterminated_construct(text: &str, syntax: &Syntax) -> Option<(usize, &'static str)> {
+    let placeholder = unterminated_placeholder(text, syntax).map(|idx| (idx, "placeholder"));
+    let arithmetic = text
+        .find("$((")
+        .filter(|_| syntax.arithmetic)
+        .map(|idx| (idx, "arithmetic expansion"));
+    let command = text
+        .find("$(")
+        .filter(|_| syntax.commands)
+        .map(|idx| (idx, "command substitution"));
+    [placeholder, arithmetic, command]
+        .into_iter()
+        .flatten()
+        .min_by_key(|&(idx, _)| idx)
+}
+
+/// Finds the places at which `input` may be split:
+/// Outside of placeholders and blocks (e.g. `${if KEY}...${end}`),
+/// and - unless the input is `complete` -
+/// before the first unterminated placeholder,
+/// which might get terminated by the input that follows.
+///
+/// Scanning starts where a previous call on a prefix of `input` left off,
+/// as given by `scan`.
+fn split_points<'t>(
+    input: &'t str,
+    syntax: &Syntax,
+    complete: bool,
+    scan: Scan,
+) -> SplitPoints<'t> {
+    let tokens = tokenize(input.get(scan.start..).unwrap_or_default(), syntax);
+    let (directives, _) = find_directives(&tokens, syntax, scan.depth);
+    let bytes = input.as_bytes();
+    // A `\r` at the end might still be followed by a `\n`
+    let is_lone_cr = |end: usize| {
+        end.checked_sub(1).and_then(|idx| bytes.get(idx)) == Some(&b'\r')
+            && bytes.get(end).is_some_and(|&next| next != b'\n')
+    };
+    let mut points = SplitPoints {
+        lines: vec![],
+        last: scan.last,
+        resume: scan,
+        tokens: vec![],
+        directives: vec![],
+    };
+    // The end of the last line,
+    // which only becomes a split point if a line with a token follows,
+    // so lines of plain text are kept together
+    let mut line_end = None;
+    let mut depth = scan.depth;
+    // The tokens after an unterminated placeholder might still change,
+    // as they become part of it, if it gets terminated later on
+    let mut frozen = false;
+    let mut token_start = scan.start;
+    for (&token, &directive) in tokens.iter().zip(&directives) {
+        if !frozen {
+            points.resume = Scan {
+                start: token_start,
+                depth,
+                last: points.last,
+            };
+        }
+        token_start = token_end(input, &token);
+        let Token::Text(text) = token else {
+            if depth == 0 {
+                points.lines.extend(line_end.take());
+            }
+            match directive {
+                Some(Directive::If(_) | Directive::For(_)) => depth += 1,
+                Some(Directive::End) => depth -= 1,
+                Some(Directive::Else | Directive::Include(_)) | None => {}
+            }
+            continue;
+        };
+        let offset = offset_of(input, text);
+        let unterminated = unterminated_start(text, syntax);
+        frozen |= unterminated.is_some();
+        if !frozen {
+            // Plain text can not change anymore,
+            // except for its end, which might start a placeholder
+            let resume = text
+                .char_indices()
+                .rev()
+                .find(|&(_, chr)| chr != '\r' && is_plain(chr, syntax));
+            if let Some((idx, chr)) = resume {
+                let start = offset + idx + chr.len_utf8();
+                points.resume = Scan {
+                    start,
+                    depth,
+                    last: if depth == 0 { Some(start) } else { points.last },
+                };
+            }
+        }
+        if depth > 0 {
+            continue;
+        }
+        let unterminated = unterminated.filter(|_| !complete);
+        let splittable = text
+            .get(..unterminated.unwrap_or(text.len()))
+            .unwrap_or_default();
+        for idx in memchr::memchr2_iter(b'\n', b'\r', splittable.as_bytes()) {
+            let end = offset + idx + 1;
+            if bytes.get(offset + idx) == Some(&b'\n') || is_lone_cr(end) {
+                line_end = Some(end);
+            }
+        }
+        let last_plain = splittable.char_indices().rev().find(|&(idx, chr)| {
+            is_plain(chr, syntax) && (chr != '\r' || is_lone_cr(offset + idx + 1))
+        });
+        if let Some((idx, chr)) = last_plain {
+            points.last = Some(offset + idx + chr.len_utf8());
+        }
+        if unterminated.is_some() {
+            break;
+        }
+    }
+    points.lines.extend(line_end);
+    points.tokens = tokens;
+    points.directives = directives;
+    points
+}
+
+/// Removes the lines that contain nothing but a directive
+/// (and whitespace) from the output,
+/// so directives do not leave behind empty lines.
+fn trim_standalone_directives(tokens: &mut [Token], directives: &[Option<Directive>]) {
+    let len = tokens.len();
+    let mut cut_heads = vec![false; len];
+    let mut cut_tails = vec![false; len];
+    for (idx, _) in directives
+        .iter()
+        .enumerate()
+        .filter(|(_, directive)| directive.is_some())
+    {
+        let before = idx.checked_sub(1);
+        let prev_blank = before.is_none_or(|prev| match tokens.get(prev) {
+            Some(Token::Text(text)) => {
+                let line_start = text.rsplit(['\r', '\n']).next().unwrap_or_default();
+                line_start.trim().is_empty() && (text.contains(['\r', '\n']) || prev == 0)
+            }
+            _ => false,
+        });
+        let next_blank = idx + 1 == len
+            || match tokens.get(idx + 1) {
+                Some(Token::Text(text)) => {
+                    let line_end = text.split(['\r', '\n']).next().unwrap_or_default();
+                    line_end.trim().is_empty() && (text.contains(['\r', '\n']) || idx + 2 == len)
+                }
+                _ => false,
+            };
+        if prev_blank && next_blank {
+            if let Some(cut) = before.and_then(|prev| cut_tails.get_mut(prev)) {
+                *cut = true;
+            }
+            if let Some(cut) = cut_heads.get_mut(idx + 1) {
+                *cut = true;
+            }
+        }
+    }
+    for ((token, cut_head), cut_tail) in tokens.iter_mut().zip(cut_heads).zip(cut_tails) {
+        if let Token::Text(text) = token {
+            let start = if cut_head {
+                first_line_end(text).unwrap_or(text.len())
+            } else {
+                0
+            };
+            let end = if cut_tail {
+                text.rfind(['\r', '\n']).map_or(0, |nl| nl + 1)
+            } else {
+                text.len()
+            };
+            *text = text.get(start..end.max(start)).unwrap_or_default();
+        }
+    }
+}
+
+/// The start of a block, e.g. `${if KEY}`.
+enum BlockStart<'t> {
+    If(Condition<&'t str>),
+    For(Loop<'t, &'t str>),
+}
+
+impl BlockStart<'_> {
+    const fn name(&self) -> &'static str {
+        match self {
+            Self::If(_) => "if",
+            Self::For(_) => "for",
+        }
+    }
+}
+
+/// An open conditional block or loop, while parsing.
+struct OpenBlock<'t> {
+    start: BlockStart<'t>,
+    then: Vec<Node<'t>>,
+    otherwise: Option<Vec<Node<'t>>>,
+}
+
+/// Parses the input into text, variables, conditional blocks, loops and includes.
+///
+/// # Errors
+///
+/// If a block is not terminated with `${end}`,
+/// a conditional block has more than one `${else}`,
+/// or a loop has one.
+fn parse_nodes<'t>(input: &'t str, syntax: &Syntax) -> io::Result<Vec<Node<'t>>> {
+    let tokens = tokenize(input, syntax);
+    let (directives, _) = find_directives(&tokens, syntax, 0);
+    parse_tokens(&tokens, &directives, syntax)
+}
+
+/// Like [`parse_nodes`], for an input that was tokenized already,
+/// with `directives` as found by [`find_directives`].
+///
+/// # Errors
+///
+/// See [`parse_nodes`].
+fn parse_tokens<'t>(
+    tokens: &[Token<'t>],
+    directives: &[Option<Directive<'t>>],
+    syntax: &Syntax,
+) -> io::Result<Vec<Node<'t>>> {
+    let tokens = if directives.iter().all(Option::is_none) {
+        Cow::Borrowed(tokens)
+    } else {
+        let mut trimmed = tokens.to_vec();
+        trim_standalone_directives(&mut trimmed, directives);
+        Cow::Owned(trimmed)
+    };
+
+    let block_error = |msg: &str, block: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{msg} in '{}{block} ...{}' block",
+                syntax.opening, syntax.closing
+            ),
+        )
+    };
+    let parse = |text| Parsed::new(text, syntax);
+    let mut root = vec![];
+    let mut open: Vec<OpenBlock> = vec![];
+    for (&token, &directive) in tokens.iter().zip(directives) {
+        let target = match open.last_mut() {
+            Some(block) => block.otherwise.as_mut().unwrap_or(&mut block.then),
+            None => &mut root,
+        };
+        match directive {
+            None => target.push(Node::parse(token, syntax)),
+            Some(Directive::Include(path)) => target.push(Node::Include(parse(path))),
+            Some(Directive::If(condition)) => open.push(OpenBlock {
+                start: BlockStart::If(condition),
+                then: vec![],
+                otherwise: None,
+            }),
+            Some(Directive::For(head)) => open.push(OpenBlock {
+                start: BlockStart::For(head),
+                then: vec![],
+                otherwise: None,
+            }),
+            Some(Directive::Else) => {
+                let block = open
+                    .last_mut()
+                    .ok_or_else(|| block_error("Stray else", "if"))?;
+                if let BlockStart::For(_) = block.start {
+                    return Err(block_error("Unexpected else", block.start.name()));
+                }
+                if block.otherwise.is_some() {
+                    return Err(block_error("Duplicate else", block.start.name()));
+                }
+                block.otherwise = Some(vec![]);
+            }
+            Some(Directive::End) => {
+                let block = open.pop().ok_or_else(|| block_error("Stray end", "if"))?;
+                let node = match block.start {
+                    BlockStart::If(condition) => Node::If {
+                        condition: condition.map(parse),
+                        then: block.then,
+                        otherwise: block.otherwise.unwrap_or_default(),
+                    },
+                    BlockStart::For(head) => Node::For {
+                        head: head.map(parse),
+                        body: block.then,
+                    },
+                };
+                match open.last_mut() {
+                    Some(parent) => parent.otherwise.as_mut().unwrap_or(&mut parent.then),
+                    None => &mut root,
+                }
+                .push(node);
+            }
+        }
+    }
+    open.first().map_or(Ok(root), |block| {
+        Err(block_error("Missing end", block.start.name()))
+    })
+}
+
+/// A malformed placeholder, as found by [`check_strict`].
+struct Malformed {
+    /// The byte offset of the placeholder in the checked input
+    offset: usize,
+    message: String,
+}
+
+impl Malformed {
+    /// Converts this into an error,
+    /// which states the location of the placeholder,
+    /// e.g. `my_file.txt:12:5: Unterminated placeholder`.
+    ///
+    /// `input` is the checked input, which starts at line `first_line`
+    /// of the `source` (file).
+    fn locate(&self, input: &str, source: Option<&str>, first_line: usize) -> io::Error {
+        let (line, column) = Locator::new(input, first_line).locate(self.offset);
+        let location = source.map_or_else(
+            || format!("line {line}, column {column}"),
+            |source| format!("{source}:{line}:{column}"),
+        );
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{location}: {}", self.message),
+        )
+    }
+}
+
+/// Finds the line and column of byte offsets in a text,
+/// taking time linear to the length of the text
+/// if they are passed in ascending order.
+struct Locator<'t> {
+    text: &'t str,
+    first_line: usize,
+    /// The last located offset
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'t> Locator<'t> {
+    /// `text` starts at line `first_line` of the input (file).
+    const fn new(text: &'t str, first_line: usize) -> Self {
+        Self {
+            text,
+            first_line,
+            offset: 0,
+            line: first_line,
+            column: 1,
+        }
+    }
+
+    /// Returns the line and the column (in characters, starting at 1)
+    /// of the byte `offset` in the text,
+    /// which has to be at a character boundary.
+    fn locate(&mut self, offset: usize) -> (usize, usize) {
+        if offset < self.offset {
+            *self = Self::new(self.text, self.first_line);
+        }
+        let skipped = self.text.get(self.offset..offset).unwrap_or_default();
+        if let Some(end) = skipped.rfind(['\r', '\n']) {
+            self.line += count_line_endings(skipped);
+            self.column = skipped.get(end + 1..).unwrap_or_default().chars().count() + 1;
+        } else {
+            self.column += skipped.chars().count();
+        }
+        self.offset = offset;
+        (self.line, self.column)
+    }
+}
+
+/// Returns the byte offset of `part` within `whole`,
+/// of which it has to be a slice.
+fn offset_of(whole: &str, part: &str) -> usize {
+    (part.as_ptr() as usize).saturating_sub(whole.as_ptr() as usize)
+}
+
+/// Checks whether `key` matches [`Settings::key_pattern`].
+fn is_valid_key<S: ::std::hash::BuildHasher>(key: &str, settings: &Settings<S>) -> bool {
+    settings.key_pattern.is_match(key)
+}
+
+/// Checks that all placeholders in `input` are terminated,
+/// and that their keys match [`Settings::key_pattern`],
+/// as required in [`Settings::strict`] mode.
+///
+/// Keys containing variables, e.g. `DB_URL_${STAGE}`,
+/// are checked when they get resolved; see [`replacement`].
+fn check_strict<S: ::std::hash::BuildHasher>(
+    input: &str,
+    settings: &Settings<S>,
+) -> Result<(), Malformed> {
+    check_strict_in(input, input, settings)
+}
+
+/// See [`check_strict`].
+///
+/// `input` is a slice of `whole`, to which the offsets refer.
+fn check_strict_in<S: ::std::hash::BuildHasher>(
+    whole: &str,
+    input: &str,
+    settings: &Settings<S>,
+) -> Result<(), Malformed> {
+    let tokens = tokenize(input, &settings.syntax);
+    let (directives, _) = find_directives(&tokens, &settings.syntax, 0);
+    check_strict_tokens(whole, &tokens, &directives, settings)
+}
+
+/// Like [`check_strict_in`], for an input that was tokenized already,
+/// with `directives` as found by [`find_directives`].
+fn check_strict_tokens<S: ::std::hash::BuildHasher>(
+    whole: &str,
+    tokens: &[Token],
+    directives: &[Option<Directive>],
+    settings: &Settings<S>,
+) -> Result<(), Malformed> {
+    let syntax = &settings.syntax;
+    for (&token, &directive) in tokens.iter().zip(directives) {
+        let (raw, keys, nested) = match (token, directive) {
+            (Token::Text(text), _) => {
+                if let Some((idx, what)) = unterminated_construct(text, syntax) {
+                    return Err(Malformed {
+                        offset: offset_of(whole, text) + idx,
+                        message: format!("Unterminated {what}"),
+                    });
+                }
+                continue;
+            }
+            (Token::Var { raw, .. }, Some(Directive::If(condition))) => {
+                (raw, vec![condition.key], condition.words())
+            }
+            (Token::Var { raw, .. }, Some(Directive::For(head))) => (
+                raw,
+                head.locals().chain(iter::once(head.list)).collect(),
+                vec![],
+            ),
+            (Token::Var { raw, body }, None) => {
+                let expr = Expression::parse(body, syntax);
+                let mut nested = expr.words();
+                nested.push(expr.key);
+                (raw, vec![expr.key], nested)
+            }
+            _ => continue,
+        };
+        for key in keys {
+            if !key.contains(&syntax.opening) && !is_valid_key(key, settings) {
+                return Err(Malformed {
+                    offset: offset_of(whole, raw),
+                    message: format!("Invalid key '{key}' in placeholder '{raw}'"),
+                });
+            }
+        }
+        for part in nested {
+            check_strict_in(whole, part, settings)?;
+        }
+    }
+    Ok(())
+}
+
+/// Splits a list value into its items,
+/// as iterated over by `${for item in LIST}`.
+///
+/// If the value contains a newline, the items are separated by newlines,
+/// otherwise by commas.
+/// Whitespace around the items is removed, and empty items are skipped.
+///
+/// ```rust
+/// # use repvar::replacer::split_list;
+/// assert_eq!(split_list("a, b,,c"), vec!["a", "b", "c"]);
+/// assert_eq!(split_list("a,1\nb,2\n"), vec!["a,1", "b,2"]);
+/// ```
+#[must_use]
+pub fn split_list(value: &str) -> Vec<&str> {
+    let separator = if value.contains('\n') { '\n' } else { ',' };
+    value
+        .split(separator)
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Creates a list value from `items`,
+/// which can be iterated over with `${for item in LIST}`.
+/// See [`split_list`].
+///
+/// ```rust
+/// # use repvar::replacer::{join_list, split_list};
+/// let list = join_list(["a,1", "b,2"]);
+/// assert_eq!(split_list(&list), vec!["a,1", "b,2"]);
+/// ```
+pub fn join_list<I, T>(items: I) -> String
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<str>,
+{
+    let mut list = String::new();
+    for item in items {
+        list.push_str(item.as_ref());
+        list.push('\n');
+    }
+    list
+}
+
+/// Defines which forms of variables are recognized in the input.
+#[derive(TypedBuilder, Clone, PartialEq, Eq)]
+pub struct Syntax {
+    /// The opening delimiter of a variable, `${` by default.
+    /// Must not be empty.
+    #[builder(default = DEFAULT_OPENING.to_owned(), setter(into))]
+    opening: String,
+    /// The closing delimiter of a variable, `}` by default.
+    /// Must not be empty.
+    /// If it equals the opening delimiter,
+    /// a variable can not contain whitespace.
+    #[builder(default = DEFAULT_CLOSING.to_owned(), setter(into))]
+    closing: String,
+    /// How to escape variables, [`Escape::Dollar`] by default.
+    /// May also be given as the escape sequence, e.g. `"\\"`.
+    #[builder(default, setter(into))]
+    escape: Escape,
+    /// How a run of escape sequences before an opening delimiter is read,
+    /// [`EscapeRuns::Last`] by default.
+    #[builder(default)]
+    escape_runs: EscapeRuns,
+    /// Whether to also recognize variables of the form `$KEY`,
+    /// in addition to `${KEY}`, like GNU `envsubst` does.
+    /// `KEY` then consists of ASCII letters, digits and '_',
+    /// and does not start with a digit.
+    /// `$$KEY` is a quoted `$KEY`.
+    #[builder(default = false)]
+    bare_vars: bool,
+    /// Whether to recognize command substitutions of the form `$(command)`,
+    /// which get replaced with the output of running `command`
+    /// with the system shell (see [`Settings::command_timeout`]).
+    /// Only enable this for trusted input!
+    /// `$$(command)` is a quoted `$(command)`.
+    #[builder(default = false)]
+    commands: bool,
+    /// Whether to recognize arithmetic expansions of the form `$((expr))`,
+    /// e.g. `$((BASE_PORT + 1))` or `$((${BASE_PORT} + 1))`.
+    /// `$$((expr))` is a quoted `$((expr))`.
+    #[builder(default = false)]
+    arithmetic: bool,
+}
+
+pub const DEFAULT_OPENING: &str = "${";
+pub const DEFAULT_CLOSING: &str = "}";
+pub const DEFAULT_ESCAPE: &str = "$";
+
+/// How variables (and bare variables, command substitutions
+/// and arithmetic expansions, if enabled in [`Syntax`])
+/// are escaped, so they get copied to the output as-is.
+///
+/// The escape sequence directly before an opening delimiter
+/// gets removed, and the opening delimiter is copied as-is,
+/// e.g. `$${KEY}` -> `${KEY}`;
+/// see [`EscapeRuns`] for multiple escape sequences before it.
+/// Escape sequences anywhere else are copied as-is, e.g. `$$` or `$5`.
+///
+/// With equal opening and closing delimiters (e.g. `@KEY@`),
+/// the whole quoted variable is copied as-is, e.g. `\@KEY@` -> `@KEY@`.
+///
+/// ```rust
+/// # use repvar::replacer::{replace_in_string, Escape, Settings, Syntax};
+/// # use std::collections::HashMap;
+/// let mut vars = HashMap::new();
+/// vars.insert("KEY".to_string(), "value".to_string());
+/// let replace = |input, escape: Escape| {
+///     let syntax = Syntax::builder().escape(escape).build();
+///     let settings = Settings::builder().vars(vars.clone()).syntax(syntax).build();
+///     replace_in_string(input, &settings).unwrap().into_owned()
+/// };
+/// assert_eq!(replace("$${KEY} $$${KEY}", Escape::Dollar), "${KEY} $${KEY}");
+/// assert_eq!(replace(r"\${KEY} \\${KEY}", Escape::Backslash), r"${KEY} \${KEY}");
+/// assert_eq!(replace("$${KEY}", Escape::Disabled), "$value");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Escape {
+    /// `$${KEY}` -> `${KEY}`, as in Makefiles and Docker Compose files
+    #[default]
+    Dollar,
+    /// `\${KEY}` -> `${KEY}`, as in POSIX shells
+    Backslash,
+    /// No escaping; variables are always replaced
+    Disabled,
+    /// A custom escape sequence, e.g. `%` for `%%KEY%` -> `%KEY%`;
+    /// must not be empty
+    Sequence(String),
+}
+
+impl Escape {
+    /// The escape sequence, e.g. `$`,
+    /// or the empty string if escaping is disabled.
+    #[must_use]
+    pub fn sequence(&self) -> &str {
+        match self {
+            Self::Dollar => "$",
+            Self::Backslash => "\\",
+            Self::Disabled => "",
+            Self::Sequence(sequence) => sequence,
+        }
+    }
+}
+
+/// How a run of multiple escape sequences (see [`Escape`])
+/// directly before an opening delimiter is read.
+///
+/// | input        | [`Self::Last`] | [`Self::Pairs`] |
+/// | ------------ | -------------- | --------------- |
+/// | `${KEY}`     | the value      | the value       |
+/// | `$${KEY}`    | `${KEY}`       | `${KEY}`        |
+/// | `$$${KEY}`   | `$${KEY}`      | `$` + the value |
+/// | `$$$${KEY}`  | `$$${KEY}`     | `$${KEY}`       |
+///
+/// ```rust
+/// # use repvar::replacer::{replace_in_string, EscapeRuns, Settings, Syntax};
+/// # use std::collections::HashMap;
+/// let mut vars = HashMap::new();
+/// vars.insert("KEY".to_string(), "value".to_string());
+/// let replace = |input, escape_runs: EscapeRuns| {
+///     let syntax = Syntax::builder().escape_runs(escape_runs).build();
+///     let settings = Settings::builder().vars(vars.clone()).syntax(syntax).build();
+///     replace_in_string(input, &settings).unwrap().into_owned()
+/// };
+/// assert_eq!(replace("$$${KEY}", EscapeRuns::Last), "$${KEY}");
+/// assert_eq!(replace("$$${KEY}", EscapeRuns::Pairs), "$value");
+/// assert_eq!("pairs".parse(), Ok(EscapeRuns::Pairs));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EscapeRuns {
+    /// Only the last escape sequence quotes the opening delimiter,
+    /// and gets removed; the ones before it are copied as-is.
+    /// This is how repvar always worked.
+    #[default]
+    Last,
+    /// The escape sequences are read in pairs,
+    /// like backslashes in C strings:
+    /// each pair stands for one literal escape sequence,
+    /// and a single remaining one quotes the opening delimiter.
+    /// Thus to get any text through as-is,
+    /// double each run of escape sequences directly before an opening delimiter,
+    /// and add one more.
+    Pairs,
+}
+
+impl EscapeRuns {
+    /// The names of the variants, as parsed by [`str::parse`].
+    pub const NAMES: [&'static str; 2] = ["last", "pairs"];
+
+    /// Reads a run of `run` escape sequences before an opening delimiter.
+    ///
+    /// Returns how many literal escape sequences the run stands for,
+    /// and whether it quotes the opening delimiter.
+    const fn read(self, run: usize) -> (usize, bool) {
+        match self {
+            Self::Last => (run.saturating_sub(1), run > 0),
+            Self::Pairs => (run / 2, run % 2 == 1),
+        }
+    }
+
+    /// The number of escape sequences needed before an opening delimiter
+    /// to quote it, with `run` literal escape sequences before it.
+    const fn quoting(self, run: usize) -> usize {
+        match self {
+            Self::Last => run + 1,
+            Self::Pairs => 2 * run + 1,
+        }
+    }
+}
+
+impl FromStr for EscapeRuns {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "last" => Ok(Self::Last),
+            "pairs" => Ok(Self::Pairs),
+            _ => Err(format!(
+                "Unknown escape runs '{name}', expected one of: {}",
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl From<&str> for Escape {
+    fn from(sequence: &str) -> Self {
+        match sequence {
+            "$" => Self::Dollar,
+            "\\" => Self::Backslash,
+            "" => Self::Disabled,
+            _ => Self::Sequence(sequence.to_owned()),
+        }
+    }
+}
+
+impl From<String> for Escape {
+    fn from(sequence: String) -> Self {
+        Self::from(sequence.as_str())
+    }
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl Syntax {
+    /// Escapes `text`, so that replacing variables in the result
+    /// yields `text` again; see [`Escape`].
+    /// If escaping is disabled, `text` is returned as-is.
+    ///
+    /// ```rust
+    /// # use repvar::replacer::{replace_in_string, Settings, Syntax};
+    /// # use std::collections::HashMap;
+    /// let syntax = Syntax::default();
+    /// let text = "${KEY} $${KEY} $5";
+    /// let quoted = syntax.quote(text);
+    /// assert_eq!(quoted, "$${KEY} $$${KEY} $5");
+    /// let settings = Settings::builder().vars(HashMap::new()).syntax(syntax).build();
+    /// assert_eq!(replace_in_string(&quoted, &settings).unwrap(), text);
+    /// ```
+    #[must_use]
+    pub fn quote(&self, text: &str) -> String {
+        let escape = self.escape.sequence();
+        if escape.is_empty() {
+            return text.to_owned();
+        }
+        let mut quoted = String::with_capacity(text.len() + text.len() / 8);
+        let mut idx = 0;
+        while let Some(rest) = text.get(idx..) {
+            // Like in `tokenize`, the longest run of escape sequences wins
+            let run = self
+                .escape_run(rest)
+                .or_else(|| self.start_len(rest).map(|_| 0));
+            let escapes = run.map_or(0, |run| run * escape.len());
+            let start = rest.get(escapes..).and_then(|start| self.start_len(start));
+            let (Some(run), Some(start_len)) = (run, start) else {
+                let Some(chr) = rest.chars().next() else {
+                    break;
+                };
+                let next = self.next_start(text, idx + chr.len_utf8());
+                quoted.push_str(text.get(idx..next).unwrap_or_default());
+                idx = next;
+                continue;
+            };
+            let mut end = escapes + start_len;
+            let quotes_opening = rest
+                .get(escapes..)
+                .is_some_and(|start| start.starts_with(&self.opening));
+            if quotes_opening && self.opening == self.closing {
+                // The whole variable gets quoted; see `tokenize`
+                if let Some(close) = rest.get(end..).and_then(|r| r.find(&self.closing)) {
+                    end += close + self.closing.len();
+                }
+            }
+            quoted.push_str(&escape.repeat(self.escape_runs.quoting(run)));
+            quoted.push_str(rest.get(escapes..end).unwrap_or_default());
+            idx += end;
+        }
+        quoted
+    }
+
+    /// Returns the first index in `input` at or after `from`
+    /// at which a token (see [`tokenize`]) might start,
+    /// i.e. an escape sequence, an opening delimiter or a `$`,
+    /// or the length of `input` if there is none.
+    /// Everything in between is plain text.
+    fn next_start(&self, input: &str, from: usize) -> usize {
+        let dollar = (self.bare_vars || self.commands || self.arithmetic).then_some(b'$');
+        let firsts = self.escape.sequence().bytes().take(1);
+        find_any(
+            input,
+            from,
+            firsts.chain(self.opening.bytes().take(1)).chain(dollar),
+        )
+    }
+
+    /// Returns the length of the opening delimiter,
+    /// or of the start (`$`) of a bare variable,
+    /// command substitution or arithmetic expansion,
+    /// if `input` starts with one.
+    fn start_len(&self, input: &str) -> Option<usize> {
+        if !self.opening.is_empty() && input.starts_with(&self.opening) {
+            Some(self.opening.len())
+        } else if (self.bare_vars && bare_var_len(input).is_some())
+            || (self.commands && command_len(input).is_some())
+            || (self.arithmetic && arithmetic_len(input).is_some())
+        {
+            Some(1)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of escape sequences at the start of `input`,
+    /// if they are directly followed by a start (see [`Self::start_len`]).
+    /// See [`Escape`].
+    fn escape_run(&self, input: &str) -> Option<usize> {
+        let escape = self.escape.sequence();
+        if escape.is_empty() {
+            return None;
+        }
+        // The last escape sequence may be part of the opening delimiter,
+        // e.g. with `$` and `${`, so we use the longest run
+        let mut run = None;
+        let mut count = 0;
+        let mut rest = input;
+        while let Some(after) = rest.strip_prefix(escape) {
+            rest = after;
+            count += 1;
+            if self.start_len(after).is_some() {
+                run = Some(count);
+            }
+        }
+        run
+    }
+
+    /// Returns the length of the run of escape sequences
+    /// at the start of `input` (see [`Self::escape_run`]),
+    /// plus the length of the start it quotes, if any.
+    fn quoted_len(&self, input: &str) -> Option<usize> {
+        let run = self.escape_run(input)?;
+        let escapes = run * self.escape.sequence().len();
+        if self.escape_runs.read(run).1 {
+            Some(escapes + self.start_len(input.get(escapes..)?)?)
+        } else {
+            Some(escapes)
+        }
+    }
+}
+
+/// How to write line endings to the output.
+///
+/// A line ending is either `\n` (LF), `\r\n` (CRLF) or a lone `\r` (CR).
+///
+/// ```rust
+/// # use repvar::replacer::LineEndings;
+/// let input = "a\r\nb\rc\nd";
+/// assert_eq!(LineEndings::Keep.apply(input), input);
+/// assert_eq!(LineEndings::Lf.apply(input), "a\nb\nc\nd");
+/// assert_eq!(LineEndings::Crlf.apply(input), "a\r\nb\r\nc\r\nd");
+/// assert_eq!("crlf".parse(), Ok(LineEndings::Crlf));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEndings {
+    /// Keep them exactly as they are in the input
+    #[default]
+    Keep,
+    /// Convert them all to `\n`
+    Lf,
+    /// Convert them all to `\r\n`
+    Crlf,
+}
+
+impl LineEndings {
+    /// The names of the variants, as parsed by [`str::parse`].
+    pub const NAMES: [&'static str; 3] = ["keep", "lf", "crlf"];
+
+    /// Converts all the line endings in `text` to this kind.
+    #[must_use]
+    pub fn apply(self, text: &str) -> Cow<'_, str> {
+        let line_ending = match self {
+            Self::Keep => return Cow::Borrowed(text),
+            Self::Lf => "\n",
+            Self::Crlf => "\r\n",
+        };
+        let mut converted = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(end) = first_line_end(rest) {
+            let (line, after) = rest.split_at(end);
+            converted.push_str(line.trim_end_matches(['\r', '\n']));
+            converted.push_str(line_ending);
+            rest = after;
+        }
+        converted.push_str(rest);
+        if converted == text {
+            Cow::Borrowed(text)
+        } else {
+            Cow::Owned(converted)
+        }
+    }
+}
+
+impl FromStr for LineEndings {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "keep" => Ok(Self::Keep),
+            "lf" => Ok(Self::Lf),
+            "crlf" => Ok(Self::Crlf),
+            _ => Err(format!(
+                "Unknown line endings '{name}', expected one of: {}",
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Which files `${include:path}` may insert the content of.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Includes {
+    /// None; an include is an error
+    #[default]
+    Disabled,
+    /// Those within [`Settings::include_paths`] or the directory of the input file
+    /// (the current directory, if there is none),
+    /// given by relative paths without `..`
+    Restricted,
+    /// Any, also given by absolute paths or paths containing `..`
+    Unrestricted,
+}
+
+/// Returns the end of the first line ending in `text`
+/// (see [`LineEndings`]), if any.
+fn first_line_end(text: &str) -> Option<usize> {
+    let idx = text.find(['\r', '\n'])?;
+    let crlf = text.get(idx..).is_some_and(|rest| rest.starts_with("\r\n"));
+    Some(idx + if crlf { 2 } else { 1 })
+}
+
+/// Counts the line endings in `text` (see [`LineEndings`]).
+fn count_line_endings(text: &str) -> usize {
+    let bytes = text.as_bytes();
+    let lone_crs = memchr::memchr_iter(b'\r', bytes)
+        .filter(|&idx| bytes.get(idx + 1) != Some(&b'\n'))
+        .count();
+    memchr::memchr_iter(b'\n', bytes).count() + lone_crs
+}
+
+/// How to replace variables.
+///
+/// Settings are `Send` and `Sync` (if `S` is),
+/// so one instance may be shared by threads
+/// rendering multiple inputs concurrently.
+#[derive(TypedBuilder)]
+pub struct Settings<S: ::std::hash::BuildHasher> {
+    /// The values of the variables.
+    /// Like in POSIX shells, a variable with an empty value
+    /// is different from one that is not set at all (no entry),
+    /// which matters for operators like `${KEY-default}` and `${KEY+alternative}`.
+    vars: HashMap<String, String, S>,
+    #[builder(default = false)]
+    fail_on_missing: bool,
+    #[builder(default)]
+    syntax: Syntax,
+    /// If set, only variables with one of these keys are replaced;
+    /// all others are left as-is,
+    /// like with the SHELL-FORMAT argument of GNU `envsubst`.
+    #[builder(default)]
+    only_keys: Option<HashSet<String>>,
+    /// Whether to replace variables within the values of variables too,
+    /// e.g. `URL=${SCHEME}://${HOST}`.
+    #[builder(default = false)]
+    recursive: bool,
+    /// How deep variables may be nested in values of variables,
+    /// if `recursive` is enabled.
+    #[builder(default = DEFAULT_MAX_DEPTH)]
+    max_depth: usize,
+    /// The maximum size in bytes of the value of a variable
+    /// after replacing the variables within it,
+    /// if `recursive` is enabled.
+    /// This guards against exponential growth,
+    /// e.g. with `A=${B}${B}`, `B=${C}${C}`, ...
+    #[builder(default = DEFAULT_MAX_SIZE)]
+    max_size: usize,
+    /// The filters available in variables, e.g. `${KEY|trim|upper}`;
+    /// the built-in ones by default.
+    #[builder(default)]
+    filters: Filters,
+    /// The name of an escaping filter (e.g. `json`)
+    /// to apply to the values of all variables,
+    /// except those that use an escaping filter themselves,
+    /// e.g. `${KEY|url}` or `${KEY|raw}`.
+    /// It is applied to the final value only,
+    /// not to the values of nested variables.
+    #[builder(default)]
+    escaping: Option<String>,
+    /// Which files `${include:path}` may insert the content of,
+    /// [`Includes::Disabled`] by default.
+    #[builder(default)]
+    includes: Includes,
+    /// The directories to search for included files,
+    /// e.g. `${include:header.txt}`,
+    /// if they are not found relative to the including file.
+    #[builder(default)]
+    include_paths: Vec<PathBuf>,
+    /// How long a command substitution, e.g. `$(date)`, may run,
+    /// if enabled in [`Syntax`].
+    #[builder(default = DEFAULT_COMMAND_TIMEOUT)]
+    command_timeout: Duration,
+    /// Whether to fail on malformed placeholders,
+    /// i.e. unterminated ones like `${KEY`
+    /// (or like `$((` and `$(`, if enabled in the [`Syntax`]),
+    /// and ones whose key does not match `key_pattern`, like `${KEY NAME}`,
+    /// instead of copying them to the output as-is.
+    /// The error message contains the line and column.
+    #[builder(default = false)]
+    strict: bool,
+    /// The pattern keys have to match in `strict` mode,
+    /// POSIX shell variable names by default (see [`DEFAULT_KEY_PATTERN`]).
+    /// It has to match the whole key, so it should start with `^` and end with `$`.
+    #[builder(default = Regex::new(DEFAULT_KEY_PATTERN).expect("Invalid default key pattern"))]
+    key_pattern: Regex,
+    /// About how much of a stream is kept in memory at most, in bytes.
+    /// Placeholders and blocks (e.g. `${if KEY}...${end}`)
+    /// may span multiple lines, but have to fit into it.
+    #[builder(default = DEFAULT_BUFFER_SIZE)]
+    buffer_size: usize,
+    /// How to write line endings to the output;
+    /// by default, they are kept as they are in the input.
+    #[builder(default)]
+    line_endings: LineEndings,
+}
+
+impl<S: ::std::hash::BuildHasher> Settings<S> {
+    /// Adds a filter, replacing any previous one with the same name.
+    /// See [`Filters::register`].
+    ///
+    /// ```rust
+    /// # use repvar::filters::check_args;
+    /// # use repvar::replacer::{replace_in_string, Settings};
+    /// # use std::collections::HashMap;
+    /// let mut vars = HashMap::new();
+    /// vars.insert("key_a".to_string(), "Hello".to_string());
+    /// let mut settings = Settings::builder().vars(vars).build();
+    /// settings.register_filter("reverse", |value, args| {
+    ///     check_args(args, 0)?;
+    ///     Ok(value.chars().rev().collect())
+    /// });
+    /// let actual = replace_in_string("a ${key_a|reverse|upper} b", &settings).unwrap();
+    /// assert_eq!("a OLLEH b", actual);
+    /// ```
+    pub fn register_filter<F>(&mut self, name: impl Into<String>, filter: F) -> &mut Self
+    where
+        F: Fn(&str, &[&str]) -> Result<String, filters::Error> + Send + Sync + 'static,
+    {
+        self.filters.register(name, filter);
+        self
+    }
+
+    /// Replaces the values of the variables,
+    /// e.g. to render a [`Template`] with different ones.
+    pub fn set_vars(&mut self, vars: HashMap<String, String, S>) -> &mut Self {
+        self.vars = vars;
+        self
+    }
+
+    /// See [`Settings::strict`].
+    #[must_use]
+    pub const fn is_strict(&self) -> bool {
+        self.strict
+    }
+}
+
+pub const DEFAULT_MAX_DEPTH: usize = 32;
+pub const DEFAULT_MAX_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+/// The keys allowed in strict mode by default,
+/// which are the valid POSIX shell variable names.
+pub const DEFAULT_KEY_PATTERN: &str = "^[A-Za-z_][A-Za-z0-9_]*$";
+
+/// Settings builder macro.
+///
+/// This macro generates builder code,
+/// which uses code auto-generated by `TypedBuilder`
+/// from the external macro `typed_builder`.
+///
+/// ```rust
+/// # use repvar::replacer::Settings;
+/// # use repvar::settings;
+/// # use std::collections::HashMap;
+/// let mut vars = HashMap::new();
+/// settings! {vars: vars};
+/// // expands to:
+/// # let mut vars = HashMap::new();
+/// Settings::builder().vars(vars).build();
+/// ```
+#[allow(unused_macros)]
+#[macro_export]
+macro_rules! settings {
+    // match-like arm for the macro
+    ($($p:ident:$v:expr),*) => {
+        // the macro expands to this code
+
+        // This is always there
+        Settings::builder()
+            // This appears as many times as there are arguments
+            $(.$p($v))*
+            // This too is always there
+            .build()
+    }
+}
+#[allow(unused_imports)]
+pub use settings;
+
+/// Extracts all occurrences of variables of the form `${KEY}` in a string
+/// in the order and amount they appear in the input,
+/// using the default [`Syntax`];
+/// see [`extract_from_string_with`].
+///
+/// ```rust
+/// # use repvar::replacer::extract_from_string;
+/// let input = "a ${key_a} $${key_a} b ${key_b:-${key_c}} c d ${key_a}e $key_d";
+/// let expected = vec!("key_a", "key_b", "key_c", "key_a");
+/// let actual = extract_from_string(input);
+/// assert_eq!(expected, actual);
+/// ```
+#[must_use]
+pub fn extract_from_string(input: &str) -> Vec<&str> {
+    extract_from_string_with(input, &Syntax::default())
+}
+
+/// Extracts all occurrences of variables of the form `${KEY}`
+/// (and `$KEY`, if enabled in `syntax`) in a string
+/// in the order and amount they appear in the input.
+///
+/// Nested variables are listed after the variable that contains them;
+/// a key containing variables, e.g. `${DB_URL_${STAGE}}`,
+/// is listed as is, i.e. `DB_URL_${STAGE}`, followed by `STAGE`.
+/// For an indirect reference, e.g. `${!KEY}`, `KEY` is listed.
+///
+/// ```rust
+/// # use repvar::replacer::{extract_from_string_with, Syntax};
+/// let input = "a ${key_a} $${key_a} b ${key_b:-${key_c}} c d ${key_a}e $key_d";
+/// let syntax = Syntax::builder().bare_vars(true).build();
+/// let expected = vec!("key_a", "key_b", "key_c", "key_a", "key_d");
+/// let actual = extract_from_string_with(input, &syntax);
+/// assert_eq!(expected, actual);
+/// ```
+///
+/// # Panics
+///
+/// In the theoretically impossible case of invalid token indices.
+#[must_use]
+pub fn extract_from_string_with<'t>(input: &'t str, syntax: &Syntax) -> Vec<&'t str> {
+    extract_refs(input, syntax)
+        .into_iter()
+        .filter_map(|reference| match reference {
+            Ref::Var { key, .. } => Some(key),
+            Ref::Include { .. } => None,
+        })
+        .collect()
+}
+
+/// Extracts all occurrences of variables of the form `${KEY}`
+/// from input that is not necessarily valid UTF-8,
+/// e.g. Latin-1 encoded text;
+/// see [`extract_from_string_with`] and [`replace_in_bytes`].
+///
+/// ```rust
+/// # use repvar::replacer::{extract_from_bytes, Syntax};
+/// let input = b"caf\xe9 ${key_a} ${k\xe9y}";
+/// let expected = vec![b"key_a".to_vec(), b"k\xe9y".to_vec()];
+/// let actual = extract_from_bytes(input, &Syntax::default());
+/// assert_eq!(expected, actual);
+/// ```
+#[must_use]
+pub fn extract_from_bytes(input: &[u8], syntax: &Syntax) -> Vec<Vec<u8>> {
+    let decoded = lossless::decode(input);
+    extract_from_string_with(&decoded, syntax)
+        .into_iter()
+        .map(|key| lossless::encode(key).into_owned())
+        .collect()
+}
+
+/// An occurrence of a variable in the input,
+/// as found by [`extract_placeholders_from_string`],
+/// [`extract_placeholders_from_stream`] and [`extract_placeholders_from_file`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placeholder {
+    /// The key of the variable, e.g. `KEY` in `${KEY:-default}`
+    pub key: String,
+    /// The byte range of the key in the input (file)
+    pub range: Range<usize>,
+    /// The line of the key, starting at 1
+    pub line: usize,
+    /// The column of the key in characters, starting at 1
+    pub column: usize,
+    /// The file containing the key, if known,
+    /// which may be an included one
+    pub file: Option<PathBuf>,
+}
+
+impl Placeholder {
+    /// Locates `key`, which has to be a slice of the text of `locator`,
+    /// which in turn starts at byte `first_byte` of the input.
+    fn locate(key: &str, locator: &mut Locator, first_byte: usize, file: Option<&Path>) -> Self {
+        let offset = offset_of(locator.text, key);
+        let (line, column) = locator.locate(offset);
+        let start = first_byte + offset;
+        Self {
+            key: key.to_owned(),
+            range: start..start + key.len(),
+            line,
+            column,
+            file: file.map(Path::to_path_buf),
+        }
+    }
+}
+
+/// Formats the placeholder as `FILE:LINE:COLUMN: KEY`,
+/// or `LINE:COLUMN: KEY` if the file is not known,
+/// which many editors can jump to.
+impl fmt::Display for Placeholder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        write!(f, "{}:{}: {}", self.line, self.column, self.key)
+    }
+}
+
+/// Extracts all occurrences of variables of the form `${KEY}` from a string,
+/// like [`extract_from_string_with`], together with their locations.
+///
+/// ```rust
+/// # use repvar::replacer::{extract_placeholders_from_string, Syntax};
+/// let input = "a ${key_a}\nb ${key_b:-${key_c}}";
+/// let actual = extract_placeholders_from_string(input, &Syntax::default());
+/// let locations: Vec<_> = actual.iter().map(ToString::to_string).collect();
+/// assert_eq!(locations, ["1:5: key_a", "2:5: key_b", "2:14: key_c"]);
+/// assert_eq!(actual[1].range, 15..20);
+/// ```
+#[must_use]
+pub fn extract_placeholders_from_string(input: &str, syntax: &Syntax) -> Vec<Placeholder> {
+    let mut locator = Locator::new(input, 1);
+    extract_refs(input, syntax)
+        .into_iter()
+        .filter_map(|reference| match reference {
+            Ref::Var { key, .. } => Some(Placeholder::locate(key, &mut locator, 0, None)),
+            Ref::Include { .. } => None,
+        })
+        .collect()
+}
+
+/// Something the input text refers to.
+enum Ref<'t> {
+    /// A variable, which is `indirect` in case of `${!KEY}`;
+    /// see [`Expression::whole`]
+    Var {
+        key: &'t str,
+        whole: Option<&'t str>,
+        indirect: bool,
+    },
+    /// A file to include, e.g. `${include:header.txt}`,
+    /// within the loops with the variables `locals`
+    Include { path: &'t str, locals: Vec<String> },
+}
+
+impl<'t> Ref<'t> {
+    const fn var(key: &'t str) -> Self {
+        Self::Var {
+            key,
+            whole: None,
+            indirect: false,
+        }
+    }
+}
+
+/// See [`extract_from_string_with`].
+///
+/// References to loop variables are not extracted.
+fn extract_refs<'t>(input: &'t str, syntax: &Syntax) -> Vec<Ref<'t>> {
+    // The loop variables of the open blocks, outermost first
+    // (none for conditional blocks)
+    let mut blocks: Vec<Vec<String>> = vec![];
+    let mut refs = vec![];
+    let tokens = tokenize(input, syntax);
+    let (directives, _) = find_directives(&tokens, syntax, 0);
+    for (token, directive) in tokens.into_iter().zip(directives) {
+        let mut token_refs = vec![];
+        match (token, directive) {
+            (_, Some(Directive::If(condition))) => {
+                blocks.push(vec![]);
+                token_refs.push(Ref::var(condition.key));
+                for word in condition.words() {
+                    token_refs.extend(extract_refs(word, syntax));
+                }
+            }
+            (_, Some(Directive::For(head))) => {
+                token_refs.push(Ref::var(head.list));
+                token_refs.extend(extract_refs(head.list, syntax));
+                blocks.push(head.locals().map(str::to_owned).collect());
+            }
+            (_, Some(Directive::End)) => {
+                blocks.pop();
+            }
+            (_, Some(Directive::Include(path))) => {
+                token_refs.extend(extract_refs(path, syntax));
+                token_refs.push(Ref::Include {
+                    path,
+                    locals: blocks.iter().flatten().cloned().collect(),
+                });
+            }
+            (Token::Command(command), _) => {
+                token_refs.extend(extract_refs(command, syntax));
+            }
+            (Token::Arithmetic { expr, .. }, _) => {
+                for token in tokenize(expr, syntax) {
+                    match token {
+                        Token::Text(text) => {
+                            let keys = arithmetic::variables(text);
+                            token_refs.extend(keys.into_iter().map(Ref::var));
+                        }
+                        Token::Var { raw, .. } | Token::Arithmetic { raw, .. } => {
+                            token_refs.extend(extract_refs(raw, syntax));
+                        }
+                        Token::Command(command) => {
+                            token_refs.extend(extract_refs(command, syntax));
+                        }
+                        Token::Quoted(_) => {}
+                    }
+                }
+            }
+            (Token::Var { body, .. }, None) => {
+                let expr = Expression::parse(body, syntax);
+                token_refs.push(Ref::Var {
+                    key: expr.key,
+                    whole: expr.whole,
+                    indirect: expr.indirect,
+                });
+                token_refs.extend(extract_refs(expr.key, syntax));
+                token_refs.extend(extract_refs(expr.filters, syntax));
+                for word in expr.words() {
+                    token_refs.extend(extract_refs(word, syntax));
+                }
+            }
+            _ => {}
+        }
+        refs.extend(token_refs.into_iter().filter(|reference| match *reference {
+            Ref::Var { key, .. } => !blocks.iter().flatten().any(|local| local == key),
+            Ref::Include { .. } => true,
+        }));
+    }
+
+    refs
+}
+
+/// Extracts all occurrences of variables of the form `${KEY}` in a stream
+/// in the order and amount they appear in the input,
+/// using the default [`Settings`];
+/// see [`extract_from_stream_with`].
+///
+/// # Errors
+///
+/// If reading from the `reader` failed.
+///
+/// If an included file is not found or not allowed (see [`Settings::includes`]),
+/// or includes form a cycle.
+pub fn extract_from_stream(reader: &mut impl BufRead) -> io::Result<Vec<String>> {
+    extract_from_stream_with(reader, &default_settings())
+}
+
+/// The settings without any variables,
+/// used by the functions that do not take any.
+fn default_settings() -> Settings<std::collections::hash_map::RandomState> {
+    Settings::builder().vars(HashMap::new()).build()
+}
+
+/// Extracts all occurrences of variables of the form `${KEY}` in a stream
+/// in the order and amount they appear in the input.
+///
+/// For an indirect reference, e.g. `${!KEY}`,
+/// `KEY` is listed, followed by its value,
+/// if `KEY` is set in `settings`.
+///
+/// The variables in included files, e.g. `${include:header.txt}`,
+/// are listed in place of the include;
+/// relative paths are resolved against the current directory
+/// and [`Settings::include_paths`].
+///
+/// The input does not have to be valid UTF-8;
+/// invalid bytes within keys are replaced with `U+FFFD`.
+///
+/// # Errors
+///
+/// If reading from the `reader` failed.
+///
+/// If an included file is not found or not allowed (see [`Settings::includes`]),
+/// or includes form a cycle.
+pub fn extract_from_stream_with<S: ::std::hash::BuildHasher>(
+    reader: &mut impl BufRead,
+    settings: &Settings<S>,
+) -> io::Result<Vec<String>> {
+    Ok(keys(extract_placeholders_from_stream(reader, settings)?))
+}
+
+/// Extracts all occurrences of variables of the form `${KEY}` in a stream,
+/// like [`extract_from_stream_with`], together with their locations.
+///
+/// The value of an indirect reference, e.g. `${!KEY}`,
+/// has the location of `KEY`.
+/// The variables in included files have the locations within those,
+/// with the resolved path as their file.
+///
+/// # Errors
+///
+/// See [`extract_from_stream_with`].
+pub fn extract_placeholders_from_stream<S: ::std::hash::BuildHasher>(
+    reader: &mut impl BufRead,
+    settings: &Settings<S>,
+) -> io::Result<Vec<Placeholder>> {
+    extract_from_included_stream(reader, settings, &mut vec![], &[], None)
+}
+
+/// Returns the keys of `placeholders`.
+fn keys(placeholders: Vec<Placeholder>) -> Vec<String> {
+    placeholders
+        .into_iter()
+        .map(|placeholder| placeholder.key)
+        .collect()
+}
+
+/// See [`extract_placeholders_from_stream`].
+///
+/// `files` are the files currently being processed, outermost first
+/// (see [`Context::files`]),
+/// and `locals` the loop variables of the blocks around the include,
+/// which are not extracted.
+/// `file` is the name of the input (file) used in the placeholders.
+fn extract_from_included_stream<S: ::std::hash::BuildHasher>(
+    reader: &mut impl BufRead,
+    settings: &Settings<S>,
+    files: &mut Vec<PathBuf>,
+    locals: &[String],
+    file: Option<&Path>,
+) -> io::Result<Vec<Placeholder>> {
+    let mut placeholders = vec![];
+    // The byte offset of the segment in the input
+    let mut first_byte = 0;
+
+    // Segments never end within a block
+    Segments::new(reader, settings, None).for_each(|segment| {
+        let Segment { text, line, .. } = segment;
+        // Whether the bytes of the input differ from those of `text`
+        let undecoded = lossless::encode(text);
+        let lossy = matches!(undecoded, Cow::Owned(_));
+        let mut locator = Locator::new(text, line);
+        for reference in extract_refs(text, &settings.syntax) {
+            match reference {
+                Ref::Var { key, .. } if locals.iter().any(|local| local == key) => {}
+                Ref::Var {
+                    key,
+                    whole,
+                    indirect,
+                } => {
+                    let key = whole
+                        .filter(|whole| settings.vars.contains_key(*whole))
+                        .unwrap_or(key);
+                    let mut placeholder = Placeholder::locate(key, &mut locator, first_byte, file);
+                    if lossy {
+                        let before = text.get(..offset_of(text, key)).unwrap_or_default();
+                        let start = first_byte + lossless::encode(before).len();
+                        let encoded_key = lossless::encode(key);
+                        placeholder.key = String::from_utf8_lossy(&encoded_key).into_owned();
+                        placeholder.range = start..start + encoded_key.len();
+                    }
+                    let target =
+                        settings
+                            .vars
+                            .get(key)
+                            .filter(|_| indirect)
+                            .map(|target| Placeholder {
+                                key: target.clone(),
+                                ..placeholder.clone()
+                            });
+                    placeholders.push(placeholder);
+                    placeholders.extend(target);
+                }
+                Ref::Include {
+                    path,
+                    locals: block_locals,
+                } => {
+                    let inner_locals = locals
+                        .iter()
+                        .cloned()
+                        .chain(block_locals)
+                        .collect::<Vec<_>>();
+                    placeholders.extend(extract_from_include(
+                        path,
+                        settings,
+                        files,
+                        &inner_locals,
+                    )?);
+                }
+            }
+        }
+        first_byte += undecoded.len();
+        Ok(())
+    })?;
+
+    Ok(placeholders)
+}
+
+/// Extracts the variables from the file at `path`,
+/// which may contain variables.
+///
+/// See [`extract_from_included_stream`] and [`resolve_include`].
+fn extract_from_include<S: ::std::hash::BuildHasher>(
+    path: &str,
+    settings: &Settings<S>,
+    files: &mut Vec<PathBuf>,
+    locals: &[String],
+) -> io::Result<Vec<Placeholder>> {
+    let path = replace_in_context(path, settings, &mut Context::default())?;
+    let file = resolve_include(&path, settings, files)?;
+    let mut reader = io::BufReader::new(fs::File::open(&file)?);
+    files.push(file.clone());
+    let placeholders =
+        extract_from_included_stream(&mut reader, settings, files, locals, Some(&file));
+    files.pop();
+    placeholders
+}
+
+/// Extracts all occurrences of variables of the form `${KEY}` in a file
+/// in the order and amount they appear in the input,
+/// using the default [`Settings`];
+/// see [`extract_from_file_with`].
+///
+/// # Errors
+///
+/// If reading from the `source` failed.
+///
+/// If an included file is not found or not allowed (see [`Settings::includes`]),
+/// or includes form a cycle.
+pub fn extract_from_file(source: Option<&str>) -> io::Result<Vec<String>> {
+    extract_from_file_with(source, &default_settings())
+}
+
+/// Extracts all occurrences of variables of the form `${KEY}` in a file
+/// in the order and amount they appear in the input.
+///
+/// See [`extract_from_stream_with`];
+/// relative paths of included files are resolved
+/// against the directory of `source`.
+///
+/// # Errors
+///
+/// If reading from the `source` failed.
+///
+/// If an included file is not found or not allowed (see [`Settings::includes`]),
+/// or includes form a cycle.
+pub fn extract_from_file_with<S: ::std::hash::BuildHasher>(
+    source: Option<&str>,
+    settings: &Settings<S>,
+) -> io::Result<Vec<String>> {
+    Ok(keys(extract_placeholders_from_file(source, settings)?))
+}
+
+/// Extracts all occurrences of variables of the form `${KEY}` in a file,
+/// like [`extract_from_file_with`], together with their locations;
+/// see [`extract_placeholders_from_stream`].
+///
+/// # Errors
+///
+/// See [`extract_from_file_with`].
+pub fn extract_placeholders_from_file<S: ::std::hash::BuildHasher>(
+    source: Option<&str>,
+    settings: &Settings<S>,
+) -> io::Result<Vec<Placeholder>> {
+    let mut reader = cli_utils::create_input_reader(source)?;
+    let source_name = source.filter(|src| !cli_utils::denotes_std_stream(Some(src)));
+    let mut files = source_name
+        .and_then(|src| fs::canonicalize(src).ok())
+        .into_iter()
+        .collect();
+
+    extract_from_included_stream(
+        &mut reader,
+        settings,
+        &mut files,
+        &[],
+        source_name.map(Path::new),
+    )
+}
+
+/// Replaces all occurrences of variables of the form `${KEY}` in a string
+/// with their respective values.
+///
+/// Like in POSIX shells, a default value may be supplied
+/// with `${KEY:-default}` (used if `KEY` is unset or empty)
+/// or `${KEY-default}` (used only if `KEY` is unset).
+/// The default value may itself contain variables.
+///
+/// Keys may contain variables as well, e.g. `${DB_URL_${STAGE}}`,
+/// which are replaced before looking up the value.
+///
+/// The value may be transformed with a chain of filters,
+/// e.g. `${KEY|trim|replace:-:_|upper}`;
+/// see [`Filters::builtin`] for the available ones.
+/// Escaping filters like `${KEY|json}` make the value safe
+/// to be inserted into a specific kind of document;
+/// one may be applied to all values with `escaping` in the settings.
+///
+/// A variable may be marked as required with `${KEY:?message}`
+/// or `${KEY?message}`, which fails with the given message
+/// if `KEY` is unset (or empty, with the colon),
+/// even if `fail_on_missing` is `false`.
+///
+/// With `${KEY:+alternative}` or `${KEY+alternative}`,
+/// the alternative value is used if `KEY` is set (and not empty, with the colon),
+/// and the empty string otherwise.
+///
+/// Like in bash, `${#KEY}` is the length of the value,
+/// `${KEY^^}` and `${KEY,,}` convert it to upper- and lower-case
+/// (`${KEY^}` and `${KEY,}` only the first character),
+/// and `${!KEY}` is the value of the variable whose key is the value of `KEY`.
+///
+/// If enabled in [`Syntax`], variables of the form `$KEY` are replaced too.
+///
+/// Conditional blocks include text only if a condition holds:
+/// `${if KEY}...${else}...${end}`, where the `${else}` part is optional.
+/// The condition may be `KEY` (set and not empty), `defined KEY` (set),
+/// `KEY == value` or `KEY != value`, optionally negated with `not`.
+///
+/// Loops repeat text for each item of a list value (see [`split_list`]):
+/// `${for item in LIST}...${end}` or `${for index, item in LIST}...${end}`,
+/// with the loop variables `item` and `index` (starting at 0).
+///
+/// `${include:path/to/partial}` inserts the content of another file,
+/// with its variables replaced in the same way,
+/// if allowed by [`Settings::includes`];
+/// see [`Settings::include_paths`] for where it is searched.
+///
+/// Lines containing nothing but a directive are removed from the output.
+///
+/// If enabled in [`Syntax`], `$((expr))` is replaced with the result
+/// of the integer arithmetic expression `expr`,
+/// e.g. `$((BASE_PORT + 1))` or `$((${BASE_PORT} + 1))`,
+/// with `+`, `-`, `*`, `/`, `%`, comparisons and parentheses.
+/// If a variable in it is missing, it is left as-is,
+/// unless `fail_on_missing` is `true`.
+///
+/// If enabled in [`Syntax`], `$(command)` is replaced with the output
+/// of running `command` with the system shell,
+/// without trailing newlines.
+/// Variables within the command are replaced before running it.
+///
+/// If `recursive` is enabled in the settings,
+/// variables within the values of variables are replaced as well.
+///
+/// ```rust
+/// # use repvar::replacer::{replace_in_string, Settings};
+/// # use std::collections::HashMap;
+/// let mut vars = HashMap::new();
+/// vars.insert("key_a".to_string(), "1".to_string());
+/// vars.insert("key_b".to_string(), "2".to_string());
+/// let input = "a ${key_a} $${key_a} b ${key_b} c ${key_c:-${key_a}}";
+/// let expected = "a 1 ${key_a} b 2 c 1";
+/// let actual =
+///     replace_in_string(input, &Settings::builder().vars(vars).build()).unwrap();
+/// assert_eq!(expected, actual);
+/// ```
+///
+/// # Errors
+///
+/// If a variable key was found in the stream,
+/// but `vars` contains no entry for it,
+/// and `fail_on_missing` is `true`.
+///
+/// If a required variable (`${KEY:?message}`) has no value.
+///
+/// If recursive replacement is enabled,
+/// and the variables reference each other in a cycle,
+/// are nested too deep,
+/// or the values get too large.
+///
+/// If a conditional block or loop is not terminated.
+///
+/// If an included file is not found or not allowed (see [`Settings::includes`]),
+/// or includes form a cycle.
+///
+/// In `strict` mode, if a placeholder is not terminated,
+/// or its key does not match `key_pattern`.
+///
+/// If an arithmetic expression is invalid, divides by zero,
+/// or uses a variable whose value is not an integer.
+///
+/// If a command fails, i.e. exits with a non-zero status,
+/// or does not finish within `command_timeout`.
+pub fn replace_in_string<'t, S: ::std::hash::BuildHasher>(
+    line: &'t str,
+    settings: &Settings<S>,
+) -> io::Result<Cow<'t, str>> {
+    replace_in_text(line, settings, &mut Context::default())
+}
+
+/// See [`replace_in_string`] and [`replace_in_bytes`].
+fn replace_in_text<'t, S: ::std::hash::BuildHasher>(
+    line: &'t str,
+    settings: &Settings<S>,
+    ctx: &mut Context,
+) -> io::Result<Cow<'t, str>> {
+    if settings.strict {
+        check_strict(line, settings).map_err(|malformed| malformed.locate(line, None, 1))?;
+    }
+    let replaced = replace_tokens(line, settings, ctx, settings.escaping.as_deref())?;
+    Ok(match settings.line_endings.apply(&replaced) {
+        Cow::Borrowed(_) => replaced,
+        Cow::Owned(converted) => Cow::Owned(converted),
+    })
+}
+
+/// Replaces all occurrences of variables of the form `${KEY}`
+/// in input that is not necessarily valid UTF-8,
+/// e.g. Latin-1 encoded text or text with embedded binary data;
+/// see [`replace_in_string`].
+///
+/// Bytes that are not valid UTF-8 are passed through untouched.
+/// They can not be part of the variable syntax (e.g. the delimiters),
+/// but they may be part of anything else, e.g. default values.
+///
+/// ```rust
+/// # use repvar::replacer::{replace_in_bytes, Settings};
+/// # use std::collections::HashMap;
+/// let mut vars = HashMap::new();
+/// vars.insert("key_a".to_string(), "1".to_string());
+/// let input = b"caf\xe9 ${key_a} ${key_b:-\xff\xfe}";
+/// let expected = b"caf\xe9 1 \xff\xfe";
+/// let actual = replace_in_bytes(input, &Settings::builder().vars(vars).build()).unwrap();
+/// assert_eq!(expected[..], actual[..]);
+/// ```
+///
+/// # Errors
+///
+/// See [`replace_in_string`].
+pub fn replace_in_bytes<'t, S: ::std::hash::BuildHasher>(
+    input: &'t [u8],
+    settings: &Settings<S>,
+) -> io::Result<Cow<'t, [u8]>> {
+    let decoded = lossless::decode(input);
+    let mut ctx = Context {
+        lossless: true,
+        ..Context::default()
+    };
+    Ok(match replace_in_text(&decoded, settings, &mut ctx)? {
+        // There was no replacement at all
+        Cow::Borrowed(_) => Cow::Borrowed(input),
+        Cow::Owned(replaced) => Cow::Owned(lossless::encode(&replaced).into_owned()),
+    })
+}
+
+/// A text parsed once, to be rendered many times,
+/// e.g. with different variables;
+/// see [`replace_in_string`].
+///
+/// All the variables in it are parsed up front,
+/// including their default values, filters and the like,
+/// so rendering it only evaluates them.
+///
+/// ```rust
+/// # use repvar::replacer::{Settings, Template};
+/// # use std::collections::HashMap;
+/// let mut settings = Settings::builder().vars(HashMap::new()).build();
+/// let template = Template::new("Dear ${name},${if vip} welcome back!${end}", &settings)
+///     .unwrap();
+/// assert_eq!(template.placeholders(), ["name", "vip"]);
+///
+/// let mut mail = String::new();
+/// for (name, vip) in [("Ada", "yes"), ("Bob", "")] {
+///     let mut vars = HashMap::new();
+///     vars.insert("name".to_string(), name.to_string());
+///     vars.insert("vip".to_string(), vip.to_string());
+///     settings.set_vars(vars);
+///     template.render_into(&mut mail, &settings).unwrap();
+///     mail.push('\n');
+/// }
+/// assert_eq!(mail, "Dear Ada, welcome back!\nDear Bob,\n");
+/// ```
+pub struct Template<'t> {
+    text: &'t str,
+    /// The syntax `text` was parsed with
+    syntax: Syntax,
+    nodes: Vec<Node<'t>>,
+    placeholders: Vec<&'t str>,
+}
+
+impl<'t> Template<'t> {
+    /// Parses `text`, which can only be rendered
+    /// with settings using the same [`Settings::syntax`].
+    ///
+    /// In [`Settings::strict`] mode, the placeholders are checked here,
+    /// not when rendering.
+    ///
+    /// # Errors
+    ///
+    /// If a conditional block or loop is not terminated.
+    ///
+    /// In `strict` mode, if a placeholder is not terminated,
+    /// or its key does not match [`Settings::key_pattern`].
+    pub fn new<S: ::std::hash::BuildHasher>(
+        text: &'t str,
+        settings: &Settings<S>,
+    ) -> io::Result<Self> {
+        if settings.strict {
+            check_strict(text, settings).map_err(|malformed| malformed.locate(text, None, 1))?;
+        }
+        let syntax = &settings.syntax;
+        Ok(Self {
+            text,
+            syntax: syntax.clone(),
+            nodes: parse_nodes(text, syntax)?,
+            placeholders: extract_from_string_with(text, syntax),
+        })
+    }
+
+    /// The text this template was parsed from.
+    #[must_use]
+    pub const fn text(&self) -> &'t str {
+        self.text
+    }
+
+    /// The keys of the variables in this template,
+    /// as returned by [`extract_from_string_with`].
+    #[must_use]
+    pub fn placeholders(&self) -> &[&'t str] {
+        &self.placeholders
+    }
+
+    /// Renders this template, like [`replace_in_string`] does its input.
+    ///
+    /// # Errors
+    ///
+    /// If the [`Settings::syntax`] differs from the one
+    /// this template was parsed with.
+    ///
+    /// See [`replace_in_string`].
+    pub fn render<S: ::std::hash::BuildHasher>(
+        &self,
+        settings: &Settings<S>,
+    ) -> io::Result<Cow<'t, str>> {
+        let mut buff_out = String::with_capacity(self.text.len() * 3 / 2);
+        let replaced = if self.render_raw(&mut buff_out, settings)? {
+            Cow::Owned(buff_out)
+        } else {
+            // There was no replacement at all
+            Cow::Borrowed(self.text)
+        };
+        Ok(match settings.line_endings.apply(&replaced) {
+            Cow::Borrowed(_) => replaced,
+            Cow::Owned(converted) => Cow::Owned(converted),
+        })
+    }
+
+    /// Renders this template, appending the result to `buff_out`,
+    /// which allows to reuse its allocated memory.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::render`].
+    pub fn render_into<S: ::std::hash::BuildHasher>(
+        &self,
+        buff_out: &mut String,
+        settings: &Settings<S>,
+    ) -> io::Result<()> {
+        let start = buff_out.len();
+        self.render_raw(buff_out, settings)?;
+        if let Cow::Owned(converted) = settings
+            .line_endings
+            .apply(buff_out.get(start..).unwrap_or_default())
+        {
+            buff_out.truncate(start);
+            buff_out.push_str(&converted);
+        }
+        Ok(())
+    }
+
+    /// Renders this template to `buff_out`,
+    /// without converting line endings.
+    ///
+    /// Returns whether anything was replaced.
+    fn render_raw<S: ::std::hash::BuildHasher>(
+        &self,
+        buff_out: &mut String,
+        settings: &Settings<S>,
+    ) -> io::Result<bool> {
+        if settings.syntax != self.syntax {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The template was parsed with a different syntax than that of the settings",
+            ));
+        }
+        render(
+            &self.nodes,
+            buff_out,
+            settings,
+            &mut Context::default(),
+            settings.escaping.as_deref(),
+        )
+    }
+}
+
+/// See [`replace_in_string`] and [`lookup`].
+fn replace_in_context<'t, S: ::std::hash::BuildHasher>(
+    line: &'t str,
+    settings: &Settings<S>,
+    ctx: &mut Context,
+) -> io::Result<Cow<'t, str>> {
+    replace_tokens(line, settings, ctx, None)
+}
+
+/// See [`replace_in_string`], [`lookup`] and [`replacement`].
+fn replace_tokens<'t, S: ::std::hash::BuildHasher>(
+    line: &'t str,
+    settings: &Settings<S>,
+    ctx: &mut Context,
+    escaping: Option<&str>,
+) -> io::Result<Cow<'t, str>> {
+    let nodes = parse_nodes(line, &settings.syntax)?;
+    let mut buff_out = String::with_capacity(line.len() * 3 / 2);
+    let replaced = render(&nodes, &mut buff_out, settings, ctx, escaping)?;
+
+    if replaced {
+        Ok(Cow::Owned(buff_out))
+    } else {
+        // There was no replacement at all
+        // -> return the input
+        Ok(Cow::Borrowed(line))
+    }
+}
+
+/// Writes the evaluated `nodes` to `buff_out`.
+///
+/// Returns whether anything was replaced,
+/// i.e. whether the output differs from the input.
+fn render<S: ::std::hash::BuildHasher>(
+    nodes: &[Node],
+    buff_out: &mut String,
+    settings: &Settings<S>,
+    ctx: &mut Context,
+    escaping: Option<&str>,
+) -> io::Result<bool> {
+    let mut replaced = false;
+    for node in nodes {
+        match *node {
+            Node::Text(text) => buff_out.push_str(text),
+            Node::Quoted(text) => {
+                buff_out.push_str(text);
+                replaced = true;
+            }
+            Node::Var { raw, ref var } => {
+                if let Some(value) = replacement(var, settings, ctx, escaping)? {
+                    buff_out.push_str(&value);
+                    replaced = true;
+                } else {
+                    buff_out.push_str(raw);
+                }
+            }
+            Node::Command(ref command) => {
+                let output = substitute_command(command, settings, ctx)?;
+                buff_out.push_str(&apply_filters(
+                    Cow::Owned(output),
+                    &[],
+                    command.raw,
+                    settings,
+                    ctx,
+                    escaping,
+                )?);
+                replaced = true;
+            }
+            Node::Arithmetic {
+                raw,
+                expr,
+                ref parts,
+            } => {
+                if let Some(value) = expand_arithmetic(parts, settings, ctx)? {
+                    buff_out.push_str(&apply_filters(
+                        Cow::Owned(value.to_string()),
+                        &[],
+                        expr,
+                        settings,
+                        ctx,
+                        escaping,
+                    )?);
+                    replaced = true;
+                } else {
+                    buff_out.push_str(raw);
+                }
+            }
+            Node::If {
+                ref condition,
+                ref then,
+                ref otherwise,
+            } => {
+                let branch = if condition.evaluate(settings, ctx)? {
+                    then
+                } else {
+                    otherwise
+                };
+                render(branch, buff_out, settings, ctx, escaping)?;
+                replaced = true;
+            }
+            Node::For { ref head, ref body } => {
+                head.evaluate(body, buff_out, settings, ctx, escaping)?;
+                replaced = true;
+            }
+            Node::Include(ref path) => {
+                include(path, buff_out, settings, ctx, escaping)?;
+                replaced = true;
+            }
+        }
+    }
+    Ok(replaced)
+}
+
+/// Runs `command` (after replacing the variables in it),
+/// and returns its output.
+/// See [`command::run`].
+fn substitute_command<S: ::std::hash::BuildHasher>(
+    command: &Parsed,
+    settings: &Settings<S>,
+    ctx: &mut Context,
+) -> io::Result<String> {
+    let command = command.expand(settings, ctx)?;
+    let output = command::run(&command, settings.command_timeout)?;
+    Ok(match ctx.outside(&output) {
+        Cow::Borrowed(_) => output,
+        Cow::Owned(decoded) => decoded,
+    })
+}
+
+/// Evaluates the arithmetic expression made up of `parts`,
+/// after replacing the variables (e.g. `${KEY}`)
+/// and command substitutions in it.
+///
+/// Returns `None` if the expansion is to be left as-is in the output,
+/// because a variable is missing.
+fn expand_arithmetic<S: ::std::hash::BuildHasher>(
+    parts: &[Node],
+    settings: &Settings<S>,
+    ctx: &mut Context,
+) -> io::Result<Option<i64>> {
+    let mut expanded = String::new();
+    for part in parts {
+        match *part {
+            Node::Text(text) | Node::Quoted(text) => expanded.push_str(text),
+            Node::Var { ref var, .. } => {
+                let Some(value) = replacement(var, settings, ctx, None)? else {
+                    return Ok(None);
+                };
+                expanded.push_str(&value);
+            }
+            Node::Command(ref command) => {
+                expanded.push_str(&substitute_command(command, settings, ctx)?);
+            }
+            Node::Arithmetic {
+                parts: ref inner, ..
+            } => {
+                let Some(value) = expand_arithmetic(inner, settings, ctx)? else {
+                    return Ok(None);
+                };
+                expanded.push_str(&value.to_string());
+            }
+            // Blocks are not recognized within arithmetic expansions
+            Node::If { .. } | Node::For { .. } | Node::Include(_) => {}
+        }
+    }
+    let result = arithmetic::evaluate(&expanded, |key| {
+        Ok(lookup(key, settings, ctx)?.map(Cow::into_owned))
+    });
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(arithmetic::Error::Missing(key)) => missing(&key, settings),
+        Err(arithmetic::Error::Lookup(err)) => Err(err),
+        Err(err) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Arithmetic expression '{expanded}': {err}"),
+        )),
+    }
+}
+
+/// Finds the file to include for `path`,
+/// which may be relative to the directory of the including file
+/// (the last one in `files`, or the current directory if there is none),
+/// or relative to one of [`Settings::include_paths`],
+/// and - with [`Includes::Unrestricted`] only - absolute.
+///
+/// Returns the canonical path of the file.
+///
+/// # Errors
+///
+/// If [`Settings::includes`] is [`Includes::Disabled`].
+///
+/// If `path` is absolute or contains `..`,
+/// or the file is outside of the allowed directories,
+/// and [`Settings::includes`] is [`Includes::Restricted`].
+///
+/// If no such file exists,
+/// or if it is one of `files`, i.e. if includes form a cycle.
+fn resolve_include<S: ::std::hash::BuildHasher>(
+    path: &str,
+    settings: &Settings<S>,
+    files: &[PathBuf],
+) -> io::Result<PathBuf> {
+    let denied = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Can not include '{path}': {reason}"),
+        )
+    };
+    let restricted = match settings.includes {
+        Includes::Disabled => return Err(denied("Includes are disabled")),
+        Includes::Restricted => true,
+        Includes::Unrestricted => false,
+    };
+    let relative = Path::new(path);
+    let escapes = relative
+        .components()
+        .any(|comp| !matches!(comp, Component::Normal(_) | Component::CurDir));
+    if escapes && restricted {
+        return Err(denied("Absolute paths and '..' are not allowed"));
+    }
+    let base = files
+        .last()
+        .and_then(|file| file.parent())
+        .map_or_else(PathBuf::new, Path::to_path_buf);
+    let found = iter::once(base)
+        .chain(settings.include_paths.iter().cloned())
+        .map(|dir| dir.join(relative))
+        .find(|candidate| candidate.is_file())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Included file '{path}' not found"),
+            )
+        })?;
+    let file = fs::canonicalize(found)?;
+    if restricted && !is_in_include_dirs(&file, settings, files)? {
+        return Err(denied("It is outside of the include directories"));
+    }
+    if let Some(start) = files.iter().position(|outer| *outer == file) {
+        let cycle = files
+            .iter()
+            .skip(start)
+            .chain(iter::once(&file))
+            .map(|member| member.display().to_string())
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Cyclic include: {cycle}"),
+        ));
+    }
+    Ok(file)
+}
+
+/// Whether the canonical `file` is within one of [`Settings::include_paths`]
+/// or the directory of the input file
+/// (the first one in `files`, or the current directory if there is none),
+/// e.g. not reached through a symbolic link pointing outside of them.
+fn is_in_include_dirs<S: ::std::hash::BuildHasher>(
+    file: &Path,
+    settings: &Settings<S>,
+    files: &[PathBuf],
+) -> io::Result<bool> {
+    let input_dir = match files.first().and_then(|input| input.parent()) {
+        Some(dir) => dir.to_path_buf(),
+        None => env::current_dir()?,
+    };
+    Ok(iter::once(input_dir)
+        .chain(settings.include_paths.iter().cloned())
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .any(|dir| file.starts_with(dir)))
+}
+
+/// Writes the replaced content of the file at `path`
+/// (which may contain variables) to `buff_out`.
+///
+/// See [`resolve_include`].
+fn include<S: ::std::hash::BuildHasher>(
+    path: &Parsed,
+    buff_out: &mut String,
+    settings: &Settings<S>,
+    ctx: &mut Context,
+    escaping: Option<&str>,
+) -> io::Result<()> {
+    let path = path.expand(settings, ctx)?;
+    let file = resolve_include(&path, settings, &ctx.files)?;
+    let source = file.display().to_string();
+    let mut reader = io::BufReader::new(fs::File::open(&file)?);
+    let mut included = vec![];
+    ctx.files.push(file);
+    ctx.expanded.clear();
+    let result = replace_segments(
+        &mut reader,
+        &mut included,
+        settings,
+        ctx,
+        escaping,
+        Some(&source),
+    );
+    ctx.files.pop();
+    ctx.expanded.clear();
+    result?;
+    buff_out.push_str(&lossless::decode(&included));
+    Ok(())
+}
+
+/// Replaces all occurrences of variables of the form `${KEY}` in a input stream
+/// with their respective values.
+///
+/// Variables, conditional blocks and loops may span multiple lines;
+/// see [`replace_in_string`].
+///
+/// Bytes that are not valid UTF-8 are passed through untouched;
+/// see [`replace_in_bytes`].
+/// So are line endings, i.e. `\n`, `\r\n` and lone `\r`,
+/// including a missing one at the end of the input,
+/// unless they are converted with [`Settings::line_endings`].
+///
+/// The input is read in chunks,
+/// and no more than about [`Settings::buffer_size`] bytes of it
+/// are kept in memory, no matter how long its lines are.
+/// A variable or block has to fit into that buffer;
+/// an unterminated variable that does not is copied to the output as-is
+/// (except in `strict` mode).
+///
+/// # Errors
+///
+/// If a variable key was found in the stream,
+/// but `vars` contains no entry for it,
+/// and `fail_on_missing` is `true`.
+///
+/// If a required variable (`${KEY:?message}`) has no value;
+/// the error message contains the line number.
+///
+/// If a conditional block is not terminated.
+///
+/// If reading from the `reader` failed.
+///
+/// If writing to the `writer` failed.
+pub fn replace_in_stream<S: ::std::hash::BuildHasher>(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    settings: &Settings<S>,
+) -> io::Result<()> {
+    replace_in_named_stream(reader, writer, settings, None)
+}
+
+/// Adds the location of the variable that caused an error
+/// to the error message,
+/// e.g. `my_file.txt:12: Undefined variable 'KEY'`.
+fn locate_error(err: &io::Error, source: Option<&str>, line_num: usize) -> io::Error {
+    let location = source.map_or_else(
+        || format!("line {line_num}"),
+        |source| format!("{source}:{line_num}"),
+    );
+    io::Error::new(err.kind(), format!("{location}: {err}"))
+}
+
+/// A piece of a stream, as read by [`Segments`],
+/// along with its tokens (see [`tokenize`]),
+/// which were found while looking for the places to split the stream at.
+struct Segment<'t> {
+    text: &'t str,
+    /// The number of the line the text starts in
+    line: usize,
+    tokens: &'t [Token<'t>],
+    /// The directives of `tokens`, see [`find_directives`]
+    directives: &'t [Option<Directive<'t>>],
+}
+
+/// Reads a stream in pieces that can be replaced independently of each other,
+/// keeping no more than about [`Settings::buffer_size`] bytes of it in memory,
+/// no matter how long its lines are.
+///
+/// Bytes that are not valid UTF-8 are represented by special characters,
+/// so they can be passed through; see [`lossless`].
+///
+/// The pieces end at line ends where possible,
+/// but never within a placeholder or a block,
+/// so these may span multiple lines.
+struct Segments<'a, R> {
+    reader: &'a mut R,
+    syntax: &'a Syntax,
+    buffer_size: usize,
+    strict: bool,
+    source: Option<&'a str>,
+    /// The input read so far, minus the segments already passed on
+    pending: String,
+    /// An incomplete UTF-8 sequence at the end of the input read so far
+    /// (see [`lossless`])
+    undecoded: Vec<u8>,
+    /// The number of the line `pending` starts in
+    line: usize,
+    /// How far `pending` was scanned for split points already
+    scan: Scan,
+    eof: bool,
+}
+
+impl<'a, R: BufRead> Segments<'a, R> {
+    const fn new<S: ::std::hash::BuildHasher>(
+        reader: &'a mut R,
+        settings: &'a Settings<S>,
+        source: Option<&'a str>,
+    ) -> Self {
+        Self {
+            reader,
+            syntax: &settings.syntax,
+            buffer_size: settings.buffer_size,
+            strict: settings.strict,
+            source,
+            pending: String::new(),
+            undecoded: Vec::new(),
+            line: 1,
+            scan: Scan {
+                start: 0,
+                depth: 0,
+                last: None,
+            },
+            eof: false,
+        }
+    }
+
+    /// Reads the whole stream, passing the segments to `process` in order,
+    /// each one as soon as it is complete.
+    ///
+    /// # Errors
+    ///
+    /// If reading failed,
+    /// the stream could not be split (see [`Self::split`]),
+    /// or `process` failed.
+    fn for_each(mut self, mut process: impl FnMut(Segment) -> io::Result<()>) -> io::Result<()> {
+        while !self.eof {
+            if self.read()? || self.pending.len() > self.buffer_size {
+                let taken = self.split(&mut process)?;
+                self.pending.drain(..taken);
+                self.scan = self.scan.skip(taken);
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the next chunk of input to `pending`.
+    ///
+    /// Returns whether it contains a line end (including a lone `\r`),
+    /// or is the end of the input.
+    fn read(&mut self) -> io::Result<bool> {
+        let chunk = match self.reader.fill_buf() {
+            Ok(chunk) => chunk,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        // Whether a `\r` at the end of the previous chunk was a lone one
+        // is only known now
+        let after_cr = self.pending.ends_with('\r');
+        let appended = self.pending.len();
+        if chunk.is_empty() {
+            self.eof = true;
+            self.pending.push_str(&lossless::decode(&self.undecoded));
+            self.undecoded.clear();
+        } else {
+            let len = chunk.len();
+            let bytes = if self.undecoded.is_empty() {
+                Cow::Borrowed(chunk)
+            } else {
+                let mut bytes = mem::take(&mut self.undecoded);
+                bytes.extend_from_slice(chunk);
+                Cow::Owned(bytes)
+            };
+            // The rest of the last character might be in the next chunk
+            let complete = bytes.len() - lossless::incomplete_len(&bytes);
+            let (decodable, rest) = bytes.split_at(complete);
+            self.pending.push_str(&lossless::decode(decodable));
+            self.undecoded = rest.to_vec();
+            self.reader.consume(len);
+        }
+        let text = self.pending.get(appended..).unwrap_or_default();
+        Ok(self.eof || after_cr || memchr::memchr2(b'\n', b'\r', text.as_bytes()).is_some())
+    }
+
+    /// Splits as much of `pending` into segments as possible,
+    /// and passes them to `process`.
+    ///
+    /// Returns the length of these segments.
+    ///
+    /// # Errors
+    ///
+    /// If `pending` is larger than `buffer_size`,
+    /// but can not be split,
+    /// e.g. because it is within a block,
+    /// or if `process` failed.
+    fn split(&mut self, process: &mut impl FnMut(Segment) -> io::Result<()>) -> io::Result<usize> {
+        let scan = self.scan;
+        let pending = self.pending.as_str();
+        let points = split_points(pending, self.syntax, self.eof, scan);
+        self.scan = points.resume;
+        let mut ends = points.lines;
+        if self.eof {
+            ends.push(pending.len());
+        }
+        if ends.is_empty() && pending.len() > self.buffer_size {
+            // An unterminated placeholder that does not fit into the buffer
+            // is treated as text, like at the end of the input,
+            // except in strict mode
+            let end = points
+                .last
+                .or_else(|| {
+                    if self.strict {
+                        return None;
+                    }
+                    let points = split_points(pending, self.syntax, true, scan);
+                    points.lines.last().copied().or(points.last)
+                })
+                .ok_or_else(|| {
+                    let err = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Placeholder or block larger than the buffer size of {} bytes",
+                            self.buffer_size
+                        ),
+                    );
+                    locate_error(&err, self.source, self.line)
+                })?;
+            ends.push(end);
+        }
+        // The end of the input may also be the end of the last line
+        ends.dedup();
+        ends.retain(|&end| end > 0);
+        if ends.is_empty() {
+            return Ok(0);
+        }
+
+        // The tokens before the start of the scan were found by earlier calls already;
+        // as they can not change anymore, tokenizing them on their own
+        // gives the same ones
+        let mut tokens = tokenize(pending.get(..scan.start).unwrap_or_default(), self.syntax);
+        let (mut directives, _) = find_directives(&tokens, self.syntax, 0);
+        let mut scanned = points.tokens.into_iter().zip(points.directives).peekable();
+        if let (Some(Token::Text(head)), Some(&(Token::Text(tail), _))) =
+            (tokens.last_mut(), scanned.peek())
+        {
+            // The scan started within a piece of text
+            *head = pending
+                .get(offset_of(pending, head)..token_end(pending, &Token::Text(tail)))
+                .unwrap_or_default();
+            scanned.next();
+        }
+        for (token, directive) in scanned {
+            tokens.push(token);
+            directives.push(directive);
+        }
+
+        // Pieces of text that segments end within are split,
+        // so each segment gets a slice of the tokens
+        let mut segment_ends = Vec::with_capacity(ends.len());
+        let mut split_tokens = Vec::with_capacity(tokens.len() + ends.len());
+        let mut split_directives = Vec::with_capacity(tokens.len() + ends.len());
+        let mut tokens = tokens.into_iter().zip(directives).peekable();
+        for &end in &ends {
+            while let Some((token, directive)) =
+                tokens.next_if(|(token, _)| token_end(pending, token) <= end)
+            {
+                split_tokens.push(token);
+                split_directives.push(directive);
+            }
+            if let Some((Token::Text(text), _)) = tokens.peek_mut() {
+                let split = end.saturating_sub(offset_of(pending, text));
+                if split > 0 {
+                    split_tokens.push(Token::Text(text.get(..split).unwrap_or_default()));
+                    split_directives.push(None);
+                    *text = text.get(split..).unwrap_or_default();
+                }
+            }
+            segment_ends.push(split_tokens.len());
+        }
+
+        let mut start = 0;
+        let mut first_token = 0;
+        for (end, last_token) in ends.into_iter().zip(segment_ends) {
+            let text = pending.get(start..end).unwrap_or_default();
+            let line = self.line;
+            self.line += count_line_endings(text);
+            process(Segment {
+                text,
+                line,
+                tokens: split_tokens
+                    .get(first_token..last_token)
+                    .unwrap_or_default(),
+                directives: split_directives
+                    .get(first_token..last_token)
+                    .unwrap_or_default(),
+            })?;
+            start = end;
+            first_token = last_token;
+        }
+        Ok(start)
+    }
+}
+
+/// Like [`replace_in_stream`],
+/// with `source` being the name of the input (file),
+/// which is used in error messages,
+/// and to find files included relative to it.
+///
+/// # Errors
+///
+/// See [`replace_in_stream`].
+pub fn replace_in_named_stream<S: ::std::hash::BuildHasher>(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    settings: &Settings<S>,
+    source: Option<&str>,
+) -> io::Result<()> {
+    if tracing::enabled!(tracing::Level::DEBUG) {
+        for (key, value) in &settings.vars {
+            tracing::debug!("VARIABLE: {key}={value}");
+        }
+    }
+
+    let mut ctx = Context {
+        files: source
+            .and_then(|src| fs::canonicalize(src).ok())
+            .into_iter()
+            .collect(),
+        lossless: true,
+        ..Context::default()
+    };
+    replace_segments(
+        reader,
+        writer,
+        settings,
+        &mut ctx,
+        settings.escaping.as_deref(),
+        source,
+    )
+}
+
+/// See [`replace_in_named_stream`] and [`replace_tokens`].
+fn replace_segments<S: ::std::hash::BuildHasher>(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    settings: &Settings<S>,
+    ctx: &mut Context,
+    escaping: Option<&str>,
+    source: Option<&str>,
+) -> io::Result<()> {
+    let mut buff_out = String::new();
+    Segments::new(reader, settings, source).for_each(|segment| {
+        let Segment {
+            text,
+            line,
+            tokens,
+            directives,
+        } = segment;
+        if settings.strict {
+            check_strict_tokens(text, tokens, directives, settings)
+                .map_err(|malformed| malformed.locate(text, source, line))?;
+        }
+        // The buffer is reused for all segments
+        buff_out.clear();
+        let replaced = parse_tokens(tokens, directives, &settings.syntax)
+            .and_then(|nodes| render(&nodes, &mut buff_out, settings, ctx, escaping))
+            .map_err(|err| locate_error(&err, source, line))?;
+        let replaced = if replaced { buff_out.as_str() } else { text };
+        let converted = settings.line_endings.apply(replaced);
+        writer.write_all(&lossless::encode(&converted))
+    })
+}
+
+/// Replaces all occurrences of variables of the form `${KEY}` in a input stream
+/// with their respective values.
+///
+/// # Errors
+///
+/// If a variable key was found in the stream,
+/// but `vars` contains no entry for it,
+/// and `fail_on_missing` is `true`.
+///
+/// If a required variable (`${KEY:?message}`) has no value;
+/// the error message contains the file name and line number.
+///
+/// If reading from the `source` failed.
+///
+/// If writing to the `destination` failed.
+///
+/// If [`Settings::strict`] is enabled and any of the above happened,
+/// a `destination` file is left untouched (see [`StagedFile`]).
+pub fn replace_in_file<S: ::std::hash::BuildHasher>(
+    source: Option<&str>,
+    destination: Option<&str>,
+    settings: &Settings<S>,
+) -> io::Result<()> {
+    if tracing::enabled!(tracing::Level::DEBUG) {
+        if let Some(in_file) = source {
+            tracing::debug!("INPUT: {}", &in_file);
+        }
+        if let Some(out_file) = destination {
+            tracing::debug!("OUTPUT: {}", &out_file);
+        }
+    }
+
+    let mut reader = cli_utils::create_input_reader(source)?;
+    let source_name = source.filter(|src| !cli_utils::denotes_std_stream(Some(src)));
+    let out_file = destination.filter(|dst| !cli_utils::denotes_std_stream(Some(dst)));
+    if let (true, Some(path)) = (settings.strict, out_file) {
+        let mut writer = StagedFile::create(path)?;
+        replace_in_named_stream(&mut reader, &mut writer, settings, source_name)?;
+        return writer.commit();
+    }
+
+    if let Some(path) = out_file {
+        // Dense inputs are written in many small pieces
+        let mut writer = io::BufWriter::new(fs::File::create(path)?);
+        replace_in_named_stream(&mut reader, &mut writer, settings, source_name)?;
+        return writer.flush();
+    }
+
+    // Each piece is written as soon as it is replaced,
+    // which matters when following a growing input
+    let mut writer = cli_utils::create_output_writer(destination)?;
+    replace_in_named_stream(&mut reader, &mut writer, settings, source_name)
+}
+
+#[cfg(test)]
+// Our variable syntax looks a lot like rust formatting arguments
+#[allow(clippy::literal_string_with_formatting_args)]
+mod tests {
+    // Note this useful idiom:
+    // importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_replace_in_string_no_vars() {
+        let vars = HashMap::new();
+        let input = "a ${key_a} $${key_a} b ${key_b} c";
+        let expected = "a ${key_a} ${key_a} b ${key_b} c";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_one_var() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let input = "a ${key_a} $${key_a} b ${key_b} c";
+        let expected = "a 1 ${key_a} b ${key_b} c";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_two_vars() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        vars.insert("key_b".to_string(), "2".to_string());
+        let input = "a ${key_a} $${key_a} b ${key_b} c";
+        let expected = "a 1 ${key_a} b 2 c";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_case_sensitive() {
+        let mut vars = HashMap::new();
+        vars.insert("Key_A".to_string(), "1".to_string());
+        vars.insert("key_b".to_string(), "2".to_string());
+        let input = "a ${key_a} $${key_a} b ${key_b} c";
+        let expected = "a ${key_a} ${key_a} b 2 c";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_missing_closing_bracket() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let input = "a ${key_a";
+        let expected = "a ${key_a";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_missing_closing_bracket_and_key() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let input = "a ${";
+        let expected = "a ${";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_missing_closing_bracket_quoted() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let input = "a $${key_a";
+        // The escape sequence quotes the opening delimiter,
+        // no matter whether the variable is terminated
+        let expected = "a ${key_a";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+    #[test]
+    fn test_replace_in_string_missing_closing_bracket_and_key_quoted() {
+        let vars = HashMap::new();
+        let input = "a $${";
+        let expected = "a ${";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_with_var_with_dollar_value() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1$2".to_string());
+        let input = "a ${key_a} $${key_a} b ${key_b} c";
+        let expected = "a 1$2 ${key_a} b ${key_b} c";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_default() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        vars.insert("key_e".to_string(), String::new());
+        let input = "a ${key_a:-x} b ${key_b:-x} c ${key_b-x} d ${key_e:-x} e ${key_e-x}.";
+        let expected = "a 1 b x c x d x e .";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_default_nested() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let input = "a ${key_b:-<${key_a}>} b ${key_b:-${key_c:-${key_a}}} c ${key_b:-$${key_a}}";
+        let expected = "a <1> b 1 c ${key_a}";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_default_fail_on_missing() {
+        let vars = HashMap::new();
+        let input = "a ${key_a:-x} b";
+        let expected = "a x b";
+        let actual =
+            replace_in_string(input, &settings! {vars: vars, fail_on_missing: true}).unwrap();
+        assert_eq!(expected, actual);
+
+        let vars = HashMap::new();
+        let input = "a ${key_a:-${key_b}} b";
+        let actual = replace_in_string(input, &settings! {vars: vars, fail_on_missing: true});
+        assert!(actual.is_err());
+    }
+
+    #[test]
+    fn test_extract_from_string_default() {
+        let input = "a ${key_a:-x} b ${key_b-${key_c:-${key_d}}} c";
+        let expected = vec!["key_a", "key_b", "key_c", "key_d"];
+        let actual = extract_from_string(input);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_required() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        vars.insert("key_e".to_string(), String::new());
+        let input = "a ${key_a:?x} b ${key_e?x} c";
+        let expected = "a 1 b  c";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_required_missing() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        vars.insert("key_e".to_string(), String::new());
+        let settings = settings! {vars: vars};
+        let err = replace_in_string("a ${key_b:?set key_b to ${key_a}!} b", &settings).unwrap_err();
+        assert_eq!(err.to_string(), "key_b: set key_b to 1!");
+        let err = replace_in_string("a ${key_e:?} b", &settings).unwrap_err();
+        assert_eq!(err.to_string(), "key_e: parameter null or not set");
+        let err = replace_in_string("a ${key_b?} b", &settings).unwrap_err();
+        assert_eq!(err.to_string(), "key_b: parameter not set");
+    }
+
+    #[test]
+    fn test_replace_in_stream_required_missing_location() {
+        let vars = HashMap::new();
+        let mut input = "line 1\nline ${key_a} 2\nline ${key_b:?describe key_b} 3\n".as_bytes();
+        let mut output = vec![];
+        let err = replace_in_stream(&mut input, &mut output, &settings! {vars: vars}).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: key_b: describe key_b");
+    }
+
+    #[test]
+    fn test_replace_in_string_alternative() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        vars.insert("key_e".to_string(), String::new());
+        let input = "a${key_a:+ x} b${key_b:+ x} c${key_b+ x} d${key_e:+ x} e${key_e+ x}.";
+        let expected = "a x b c d e x.";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_non_identifier_keys() {
+        let mut vars = HashMap::new();
+        vars.insert("config.db-host".to_string(), "db".to_string());
+        vars.insert("a.b/c".to_string(), "1".to_string());
+        vars.insert("my".to_string(), "2".to_string());
+        let input = "${config.db-host} ${a.b/c} ${config.db-port:-5432} ${other.x-y} ${my-key}";
+        // `my-key` is not set, and `my` is an identifier,
+        // so like in POSIX shells, `-key` is the default value
+        let expected = "db 1 5432 ${other.x-y} 2";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_set_hyphenated_key() {
+        let mut vars = HashMap::new();
+        vars.insert("my".to_string(), "2".to_string());
+        vars.insert("my-key".to_string(), "1".to_string());
+        vars.insert("a#b".to_string(), "3".to_string());
+        let input = "${my-key} ${my-key|upper} ${my:-key} ${a#b} ${my+x}";
+        let expected = "1 1 2 3 x";
+        let settings = settings! {vars: vars};
+        let actual = replace_in_string(input, &settings).unwrap();
+        assert_eq!(expected, actual);
+        let mut reader = &b"${my-key} ${my-other}"[..];
+        let keys = extract_from_stream_with(&mut reader, &settings).unwrap();
+        assert_eq!(keys, ["my-key", "my"]);
+    }
+
+    #[test]
+    fn test_replace_in_string_alternative_nested() {
+        let mut vars = HashMap::new();
+        vars.insert("proxy".to_string(), "http://proxy:3128".to_string());
+        let input = "cmd${proxy:+ --proxy=${proxy}}${no_proxy:+ --no-proxy=${no_proxy}}";
+        let expected = "cmd --proxy=http://proxy:3128";
+        let actual =
+            replace_in_string(input, &settings! {vars: vars, fail_on_missing: true}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_bare() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        vars.insert("k".to_string(), "2".to_string());
+        let syntax = Syntax::builder().bare_vars(true).build();
+        let input = "a $key_a $$key_a b $key_b c $k$k-$k.x $ ${key_a:-$k} $1 $";
+        let expected = "a 1 $key_a b $key_b c 22-2.x $ 1 $1 $";
+        let actual = replace_in_string(input, &settings! {vars: vars, syntax: syntax}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_bare_disabled() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let input = "a $key_a $$key_a b";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(input, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_only_keys() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        vars.insert("key_b".to_string(), "2".to_string());
+        let syntax = Syntax::builder().bare_vars(true).build();
+        let only_keys = HashSet::from(["key_a".to_string(), "key_c".to_string()]);
+        let input = "a $key_a ${key_a} b $key_b ${key_b} c ${key_c:-3}";
+        let expected = "a 1 1 b $key_b ${key_b} c 3";
+        let actual = replace_in_string(
+            input,
+            &settings! {vars: vars, syntax: syntax, only_keys: Some(only_keys)},
+        )
+        .unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_custom_delimiters() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let syntax = Syntax::builder().opening("{{").closing("}}").build();
+        let input = "a {{key_a}} ${key_a} ${{key_a}} b {{key_b:-{{key_a}}}} c {{key_b:-${key_a}}}";
+        let expected = "a 1 ${key_a} {{key_a}} b 1 c ${key_a}";
+        let actual = replace_in_string(input, &settings! {vars: vars, syntax: syntax}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_same_delimiters() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let syntax = Syntax::builder()
+            .opening("@")
+            .closing("@")
+            .escape("\\")
+            .build();
+        let input = "a @key_a@ \\@key_a@ b @key_b:-x@ c@";
+        let expected = "a 1 @key_a@ b x c@";
+        let settings = settings! {vars: vars, syntax: syntax};
+        let actual = replace_in_string(input, &settings).unwrap();
+        assert_eq!(expected, actual);
+        let actual = replace_in_string("mail x@y.com @key_a@", &settings).unwrap();
+        assert_eq!("mail x@y.com 1", actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_same_delimiters_lone() {
+        let mut vars = HashMap::new();
+        vars.insert("A".to_string(), "1".to_string());
+        let syntax = Syntax::builder().opening("%").closing("%").build();
+        let settings = settings! {vars: vars, syntax: syntax};
+        let actual = replace_in_string("100% %A%", &settings).unwrap();
+        assert_eq!("100% 1", actual);
+        let actual = replace_in_string("100%\n%A% %B%", &settings).unwrap();
+        assert_eq!("100%\n1 %B%", actual);
+        let mut vars = HashMap::new();
+        vars.insert("B".to_string(), "2".to_string());
+        let syntax = Syntax::builder().opening("@").closing("@").build();
+        let settings = settings! {vars: vars, syntax: syntax};
+        let actual = replace_in_string("x@y.com @B@", &settings).unwrap();
+        assert_eq!("x@y.com 2", actual);
+        let mut output = vec![];
+        replace_in_stream(&mut &b"x@y.com\n@B@\n"[..], &mut output, &settings).unwrap();
+        assert_eq!(output, b"x@y.com\n2\n");
+        let keys = extract_from_string_with("a@b c @B@", &settings.syntax);
+        assert_eq!(keys, ["B"]);
+    }
+
+    #[test]
+    fn test_replace_in_string_no_escape() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let syntax = Syntax::builder().escape("").build();
+        let input = "a ${key_a} $${key_a} b";
+        let expected = "a 1 $1 b";
+        let actual = replace_in_string(input, &settings! {vars: vars, syntax: syntax}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_extract_from_string_custom_delimiters() {
+        let syntax = Syntax::builder()
+            .opening("%")
+            .closing("%")
+            .escape("%")
+            .build();
+        let input = "a %key_a% %%key_b% b %key_c:-x% ${key_d}";
+        let expected = vec!["key_a", "key_c"];
+        let actual = extract_from_string_with(input, &syntax);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_recursive() {
+        let mut vars = HashMap::new();
+        vars.insert("scheme".to_string(), "https".to_string());
+        vars.insert("host".to_string(), "${name}.example.com".to_string());
+        vars.insert("name".to_string(), "www".to_string());
+        vars.insert(
+            "url".to_string(),
+            "${scheme}://${host}/$${path}".to_string(),
+        );
+        let input = "url: ${url}";
+        let expected = "url: https://www.example.com/${path}";
+        let actual = replace_in_string(input, &settings! {vars: vars, recursive: true}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_recursive_cycle() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "x${key_b}".to_string());
+        vars.insert("key_b".to_string(), "${key_c:-${key_a}}".to_string());
+        let input = "a ${key_a} b";
+        let err = replace_in_string(input, &settings! {vars: vars, recursive: true}).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cyclic variable reference: key_a -> key_b -> key_a"
+        );
+    }
+
+    #[test]
+    fn test_replace_in_string_recursive_limits() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "${key_b}${key_b}".to_string());
+        vars.insert("key_b".to_string(), "${key_c}${key_c}".to_string());
+        vars.insert("key_c".to_string(), "1234".to_string());
+        let input = "a ${key_a} b";
+        let settings = settings! {vars: vars.clone(), recursive: true, max_depth: 2};
+        let err = replace_in_string(input, &settings).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Variable references nested more than 2 levels deep: key_a -> key_b -> key_c"
+        );
+        let settings = settings! {vars: vars, recursive: true, max_size: 10};
+        let err = replace_in_string(input, &settings).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Value of variable 'key_a' is larger than 10 bytes after expansion"
+        );
+    }
+
+    #[test]
+    fn test_replace_in_string_recursive_fan_out() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "${key_b}".repeat(100));
+        vars.insert("key_b".to_string(), "${key_c}".repeat(100));
+        vars.insert("key_c".to_string(), "${key_d}".repeat(100));
+        vars.insert("key_d".to_string(), String::new());
+        let settings = settings! {vars: vars, recursive: true};
+        let start = std::time::Instant::now();
+        assert_eq!(
+            replace_in_string("a ${key_a} b", &settings).unwrap(),
+            "a  b"
+        );
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_replace_in_string_recursive_loop_variable() {
+        let mut vars = HashMap::new();
+        vars.insert("list".to_string(), "x,y".to_string());
+        vars.insert("line".to_string(), "<${item}>".to_string());
+        let input = "${for item in list}${line}${line}${end}";
+        let actual = replace_in_string(input, &settings! {vars: vars, recursive: true}).unwrap();
+        assert_eq!(actual, "<x><x><y><y>");
+    }
+
+    #[test]
+    fn test_replace_in_string_nested_key() {
+        let mut vars = HashMap::new();
+        vars.insert("stage".to_string(), "prod".to_string());
+        vars.insert("url_prod".to_string(), "https://example.com".to_string());
+        vars.insert("url_dev".to_string(), "http://localhost".to_string());
+        let input = "a ${url_${stage}} b ${url_${other:-dev}} c ${url_${other}} d ${x_${stage}:-y}";
+        let expected = "a https://example.com b http://localhost c ${url_${other}} d y";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_nested_key_fail_on_missing() {
+        let mut vars = HashMap::new();
+        vars.insert("url_prod".to_string(), "https://example.com".to_string());
+        let input = "a ${url_${stage}} b";
+        let err =
+            replace_in_string(input, &settings! {vars: vars, fail_on_missing: true}).unwrap_err();
+        assert_eq!(err.to_string(), "Undefined variable 'stage'");
+    }
+
+    #[test]
+    fn test_extract_from_string_nested_key() {
+        let input = "a ${url_${stage:-${default_stage}}} b ${x_${y}:-${z}}";
+        let expected = vec![
+            "url_${stage:-${default_stage}}",
+            "stage",
+            "default_stage",
+            "x_${y}",
+            "y",
+            "z",
+        ];
+        let actual = extract_from_string(input);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_filters() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), " Hello World ".to_string());
+        vars.insert("sep".to_string(), "_".to_string());
+        let input =
+            "a ${key_a|trim|upper} b ${key_a|trim|replace: :${sep}|lower} c ${key_b:-x|upper}";
+        let expected = "a HELLO WORLD b hello_world c X";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_filters_missing() {
+        let vars = HashMap::new();
+        let input = "a ${key_a|upper} b";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(input, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_filters_errors() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let settings = settings! {vars: vars};
+        let err = replace_in_string("a ${key_a|no_such_filter} b", &settings).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Variable 'key_a', filter 'no_such_filter': Unknown filter 'no_such_filter'"
+        );
+        let err = replace_in_string("a ${key_a|upper:x} b", &settings).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Variable 'key_a', filter 'upper': Expected 0 argument(s), but got 1"
+        );
+    }
+
+    #[test]
+    fn test_extract_from_string_filters() {
+        let input = "a ${key_a|replace:x:${key_b}} b";
+        let expected = vec!["key_a", "key_b"];
+        let actual = extract_from_string(input);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_escaping() {
+        let mut vars = HashMap::new();
+        vars.insert("pass".to_string(), "se\"cr\\et".to_string());
+        vars.insert("path".to_string(), "/my files/it's".to_string());
+        let input = r#"{"pass": "${pass|json}"} ls ${path|shell} ?q=${path|url}"#;
+        let expected = r#"{"pass": "se\"cr\\et"} ls '/my files/it'\''s' ?q=%2Fmy%20files%2Fit%27s"#;
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_escaping_default() {
+        let mut vars = HashMap::new();
+        vars.insert("name".to_string(), "<b>".to_string());
+        vars.insert("key_<b>".to_string(), "Tom & Jerry".to_string());
+        vars.insert("ref".to_string(), "${name}".to_string());
+        let input =
+            "a ${name} b ${name|raw} c ${name|url} d ${key_${name}} e ${ref} f ${x:-${name}}";
+        let expected = "a &lt;b&gt; b <b> c %3Cb%3E d Tom &amp; Jerry e &lt;b&gt; f &lt;b&gt;";
+        let settings = settings! {vars: vars, recursive: true, escaping: Some("html".to_owned())};
+        let actual = replace_in_string(input, &settings).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_transform_pattern() {
+        let mut vars = HashMap::new();
+        vars.insert("version".to_string(), "v1.2.3".to_string());
+        vars.insert("file".to_string(), "/src/lib.tar.gz".to_string());
+        vars.insert("sep".to_string(), ".".to_string());
+        let input = "${version#v} ${file##*/} ${file#*/} ${file%.*} ${file%%${sep}*} ${version#x}";
+        let expected = "1.2.3 lib.tar.gz src/lib.tar.gz /src/lib.tar /src/lib v1.2.3";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_transform_replace() {
+        let mut vars = HashMap::new();
+        vars.insert("path".to_string(), "a/b/c".to_string());
+        let input = "${path/\\//::} ${path//\\//::} ${path//[ab]} ${path/?/${path#a/}}";
+        let expected = "a::b/c a::b::c //c b/c/b/c";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_transform_substring() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "0123456789".to_string());
+        vars.insert("two".to_string(), "2".to_string());
+        let input = "${key_a:7} ${key_a:2:3} ${key_a: -3} ${key_a:(-3):2} ${key_a:2:-5} ${key_a:${two}:${two}} ${key_a:20}.";
+        let expected = "789 234 789 78 234 23 .";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let err = replace_in_string("${key_a:1:x}", &settings! {vars: vars}).unwrap_err();
+        assert_eq!(err.to_string(), "Variable 'key_a': Invalid length 'x'");
+    }
+
+    #[test]
+    fn test_extract_from_string_transform() {
+        let input = "a ${key_a#${key_b}} b ${key_c/${key_d}/${key_e}} c ${key_f:${key_g}:1}";
+        let expected = vec![
+            "key_a", "key_b", "key_c", "key_d", "key_e", "key_f", "key_g",
+        ];
+        let actual = extract_from_string(input);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_length_and_case() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "hello wörld".to_string());
+        vars.insert("key_b".to_string(), "HELLO".to_string());
+        vars.insert("key_e".to_string(), String::new());
+        let input = "${#key_a} ${#key_e} ${key_a^} ${key_a^^} ${key_b,} ${key_b,,} ${key_a^^[lo]} ${#key_c}";
+        let expected = "11 0 Hello wörld HELLO WÖRLD hELLO hello heLLO wörLd ${#key_c}";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_indirect() {
+        let mut vars = HashMap::new();
+        vars.insert("stage".to_string(), "url_prod".to_string());
+        vars.insert("url_prod".to_string(), "https://example.com".to_string());
+        vars.insert("dangling".to_string(), "url_dev".to_string());
+        let input =
+            "a ${!stage} b ${!stage#https://} c ${!dangling:-none} d ${!dangling} e ${!missing}";
+        let expected = "a https://example.com b example.com c none d ${!dangling} e ${!missing}";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_extract_from_stream_indirect() {
+        let mut vars = HashMap::new();
+        vars.insert("stage".to_string(), "url_prod".to_string());
+        let mut input = "a ${!stage} b ${!other} c ${#key_a} d ${key_b^^}".as_bytes();
+        let expected = vec!["stage", "url_prod", "other", "key_a", "key_b"];
+        let actual = extract_from_stream_with(&mut input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_if() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        vars.insert("key_e".to_string(), String::new());
+        let input = "a${if key_a} A${end} b${if key_e} E${else} no E${end} \
+            c${if defined key_e} E${end} d${if not key_b} no B${end} \
+            e${if key_a == 1} 1${end}${if key_a != ${key_a}} !1${end} \
+            f${if key_a} ${if key_e == \"\"}nested${end}${end} ${end}.";
+        let expected = "a A b no E c E d no B e 1 f nested ${end}.";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_if_standalone_lines() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let input =
+            "a\n  ${if key_a}\nA ${key_a}\n  ${else}\nno A\n${end}\n${if key_b}B${end}\nb\n";
+        let expected = "a\nA 1\n\nb\n";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_if_unterminated() {
+        let vars = HashMap::new();
+        let settings = settings! {vars: vars};
+        let err = replace_in_string("a ${if key_a} b", &settings).unwrap_err();
+        assert_eq!(err.to_string(), "Missing end in '${if ...}' block");
+        let err = replace_in_string("${if a}${else}${else}${end}", &settings).unwrap_err();
+        assert_eq!(err.to_string(), "Duplicate else in '${if ...}' block");
+    }
+
+    #[test]
+    fn test_replace_in_stream_if() {
+        let mut vars = HashMap::new();
+        vars.insert("feature".to_string(), "on".to_string());
+        let mut input =
+            "a\n${if feature == on}\nfeature: ${feature}\n${else}\nno feature\n${end}\nb\n"
+                .as_bytes();
+        let mut output = vec![];
+        replace_in_stream(&mut input, &mut output, &settings! {vars: vars}).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "a\nfeature: on\nb\n");
+
+        let vars = HashMap::new();
+        let mut input = &b"a\nb\n${if feature}\nc\n"[..];
+        let mut output = vec![];
+        let err = replace_in_stream(&mut input, &mut output, &settings! {vars: vars}).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: Missing end in '${if ...}' block");
+    }
+
+    #[test]
+    fn test_extract_from_string_if() {
+        let input = "${end} ${if key_a}${key_b}${else}${if key_c == ${key_d}}${end}${end} ${else}";
+        let expected = vec!["end", "key_a", "key_b", "key_c", "key_d", "else"];
+        let actual = extract_from_string(input);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_for() {
+        let mut vars = HashMap::new();
+        vars.insert(
+            "hosts".to_string(),
+            "a.example.com, b.example.com".to_string(),
+        );
+        vars.insert("ports".to_string(), "80\n443\n".to_string());
+        vars.insert("item".to_string(), "outer".to_string());
+        vars.insert("empty".to_string(), String::new());
+        let input = "${for host in hosts}[${host}]${end} \
+            ${for i, port in ports}${i}:${port};${end} \
+            ${for item in hosts}${for port in ports}${item#*.}:${port} ${end}${end}\
+            ${item} ${for item in empty}x${end}${for item in missing}x${end}.";
+        let expected = "[a.example.com][b.example.com] 0:80;1:443; \
+            example.com:80 example.com:443 example.com:80 example.com:443 outer .";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_for_errors() {
+        let vars = HashMap::new();
+        let settings = settings! {vars: vars, fail_on_missing: true};
+        let err = replace_in_string("${for x in list}${x}${end}", &settings).unwrap_err();
+        assert_eq!(err.to_string(), "Undefined variable 'list'");
+        let err = replace_in_string("${for x in list}${else}${end}", &settings).unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected else in '${for ...}' block");
+        let err = replace_in_string("${for x in list}${x}", &settings).unwrap_err();
+        assert_eq!(err.to_string(), "Missing end in '${for ...}' block");
+    }
+
+    #[test]
+    fn test_replace_in_stream_for() {
+        let mut vars = HashMap::new();
+        vars.insert("users".to_string(), join_list(["alice", "bob"]));
+        let mut input =
+            &b"users:\n${for idx, user in users}\n  - ${user} (${idx})\n${end}\nend\n"[..];
+        let mut output = vec![];
+        replace_in_stream(&mut input, &mut output, &settings! {vars: vars}).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "users:\n  - alice (0)\n  - bob (1)\nend\n"
+        );
+    }
+
+    #[test]
+    fn test_extract_from_stream_for() {
+        let vars = HashMap::new();
+        let mut input =
+            &b"${for i, host in hosts}\n${host}:${port_${i}}\n${end}\n${host} ${for x in ${list}}${x}${end}\n"[..];
+        let expected = vec!["hosts", "port_${i}", "host", "${list}", "list"];
+        let actual = extract_from_stream_with(&mut input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_file_include() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("parts")).unwrap();
+        fs::write(
+            dir.path().join("main.txt"),
+            "${include:parts/header.txt}\nbody\n${include:${part}}\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("parts/header.txt"),
+            "Hello ${name}!\n${include:line.txt}\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("parts/line.txt"), "---\n").unwrap();
+        fs::write(shared.path().join("footer.txt"), "Bye ${name}!\n").unwrap();
+        let main = dir.path().join("main.txt");
+        let output = dir.path().join("output.txt");
+
+        let mut vars = HashMap::new();
+        vars.insert("name".to_string(), "World".to_string());
+        vars.insert("part".to_string(), "footer.txt".to_string());
+        let settings = settings! {
+            vars: vars,
+            includes: Includes::Restricted,
+            include_paths: vec![shared.path().to_path_buf()]
+        };
+        replace_in_file(main.to_str(), output.to_str(), &settings).unwrap();
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "Hello World!\n---\nbody\nBye World!\n"
+        );
+
+        let settings = settings! {vars: HashMap::new(), includes: Includes::Restricted};
+        let err = replace_in_file(main.to_str(), output.to_str(), &settings).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err
+            .to_string()
+            .contains("main.txt:3: Included file '${part}' not found"));
+    }
+
+    #[test]
+    fn test_replace_in_file_include_restricted() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("parts")).unwrap();
+        fs::write(outside.path().join("secret.txt"), "secret\n").unwrap();
+        let secret = outside.path().join("secret.txt");
+        let source = dir.path().join("parts/main.txt");
+        let output = dir.path().join("output.txt");
+
+        let render = |include: &str, settings: &Settings<_>| {
+            fs::write(&source, format!("${{include:{include}}}")).unwrap();
+            replace_in_file(source.to_str(), output.to_str(), settings)
+                .map(|()| fs::read_to_string(&output).unwrap())
+                .map_err(|err| err.to_string())
+        };
+        let secret_path = secret.to_str().unwrap();
+        let err = render(secret_path, &settings! {vars: HashMap::new()}).unwrap_err();
+        assert!(err.ends_with(": Includes are disabled"));
+        let settings = settings! {vars: HashMap::new(), includes: Includes::Restricted};
+        for include in [secret_path, "../output.txt", "./../parts/main.txt"] {
+            let err = render(include, &settings).unwrap_err();
+            assert!(err.ends_with(": Absolute paths and '..' are not allowed"));
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&secret, dir.path().join("parts/link.txt")).unwrap();
+            let err = render("link.txt", &settings).unwrap_err();
+            assert!(err.ends_with(": It is outside of the include directories"));
+            let settings = settings! {
+                vars: HashMap::new(),
+                includes: Includes::Restricted,
+                include_paths: vec![outside.path().to_path_buf()]
+            };
+            assert_eq!(render("link.txt", &settings).unwrap(), "secret\n");
+        }
+        let settings = settings! {
+            vars: HashMap::new(),
+            includes: Includes::Unrestricted
+        };
+        assert_eq!(render(secret_path, &settings).unwrap(), "secret\n");
+    }
+
+    #[test]
+    fn test_replace_in_file_include_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a\n${include:b.txt}\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "b\n${include:a.txt}\n").unwrap();
+        let source = dir.path().join("a.txt");
+
+        let output = dir.path().join("output.txt");
+
+        let settings = settings! {vars: HashMap::new(), includes: Includes::Restricted};
+        let err = replace_in_file(source.to_str(), output.to_str(), &settings).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Cyclic include: "));
+        assert!(err.to_string().contains("a.txt -> "));
+        let err = extract_from_file_with(source.to_str(), &settings).unwrap_err();
+        assert!(err.to_string().contains("Cyclic include: "));
+    }
+
+    #[test]
+    fn test_extract_from_file_include() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("main.txt"),
+            "${title}\n${for host in hosts}\n${include:host.txt}\n${end}\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("host.txt"), "${host}:${port}\n").unwrap();
+        let source = dir.path().join("main.txt");
 
-/// Extracts all occurrences of variables of the form `${KEY}` in a stream
-/// in the order and amount they appear in the input.
-///
-/// # Errors
-///
-/// If reading from the `reader` failed.
-pub fn extract_from_stream(reader: &mut impl BufRead) -> io::Result<Vec<String>> {
-    let mut keys = vec![];
+        let expected = vec!["title", "hosts", "port"];
+        let settings = settings! {vars: HashMap::new(), includes: Includes::Restricted};
+        let actual = extract_from_file_with(source.to_str(), &settings).unwrap();
+        assert_eq!(expected, actual);
+    }
 
-    for line in cli_utils::lines_iterator(reader, false) {
-        extract_from_string(&line?)
-            .into_iter()
-            .map(str::to_owned)
-            .for_each(|key| keys.push(key));
+    #[test]
+    fn test_extract_placeholders_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("main.txt"),
+            b"${title}\r\n${for host in hosts}\n  ${include:host.txt}\n${end}\n\xe9 ${!ref}\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("host.txt"), "${host}:${port}\n").unwrap();
+        let source = dir.path().join("main.txt");
+        let mut vars = HashMap::new();
+        vars.insert("ref".to_string(), "target".to_string());
+
+        let settings = settings! {vars: vars, includes: Includes::Restricted};
+        let actual = extract_placeholders_from_file(source.to_str(), &settings).unwrap();
+        let located: Vec<_> = actual
+            .iter()
+            .map(|placeholder| {
+                (
+                    placeholder.key.as_str(),
+                    placeholder.line,
+                    placeholder.column,
+                    placeholder.file.as_deref().and_then(Path::file_name),
+                )
+            })
+            .collect();
+        let main = Some(OsStr::new("main.txt"));
+        let host = Some(OsStr::new("host.txt"));
+        assert_eq!(
+            located,
+            [
+                ("title", 1, 3, main),
+                ("hosts", 2, 15, main),
+                ("port", 1, 11, host),
+                ("ref", 5, 6, main),
+                ("target", 5, 6, main),
+            ]
+        );
+        let ranges: Vec<_> = actual
+            .iter()
+            .map(|placeholder| placeholder.range.clone())
+            .collect();
+        // The Latin-1 'é' is a single byte
+        assert_eq!(ranges, [2..7, 24..29, 10..14, 65..68, 65..68]);
+        assert_eq!(
+            actual.get(3).map(ToString::to_string),
+            Some(format!("{}:5:6: ref", source.display()))
+        );
     }
 
-    Ok(keys)
-}
+    #[test]
+    fn test_locator() {
+        let mut locator = Locator::new("ab\r\ncä\rd\n\ne", 3);
+        assert_eq!(locator.locate(1), (3, 2));
+        assert_eq!(locator.locate(4), (4, 1));
+        assert_eq!(locator.locate(7), (4, 3));
+        assert_eq!(locator.locate(8), (5, 1));
+        assert_eq!(locator.locate(11), (7, 1));
+        assert_eq!(locator.locate(0), (3, 1));
+    }
 
-/// Extracts all occurrences of variables of the form `${KEY}` in a file
-/// in the order and amount they appear in the input.
-///
-/// # Errors
-///
-/// If reading from the `source` failed.
-pub fn extract_from_file(source: Option<&str>) -> io::Result<Vec<String>> {
-    let mut reader = cli_utils::create_input_reader(source)?;
+    #[test]
+    #[cfg(unix)]
+    fn test_replace_in_string_commands() {
+        let mut vars = HashMap::new();
+        vars.insert("name".to_string(), "a  b".to_string());
+        let input = r#"x $(printf '%s\n\n' "${name}") $$(true) $(echo $(echo nested))"#;
+        let settings = settings! {vars: vars.clone()};
+        assert_eq!(
+            replace_in_string("$(echo a) $$(b)", &settings).unwrap(),
+            "$(echo a) $$(b)"
+        );
+        let syntax = Syntax::builder().commands(true).build();
+        let settings = settings! {vars: vars.clone(), syntax: syntax.clone()};
+        assert_eq!(
+            replace_in_string(input, &settings).unwrap(),
+            "x a  b $(true) nested"
+        );
+        let settings = settings! {
+            vars: vars.clone(),
+            syntax: syntax.clone(),
+            escaping: Some("json".to_string())
+        };
+        assert_eq!(
+            replace_in_string(r#"$(echo '"${name}"')"#, &settings).unwrap(),
+            r#"\"a  b\""#
+        );
 
-    extract_from_stream(&mut reader)
-}
+        let settings = settings! {vars: vars.clone(), syntax: syntax.clone()};
+        let err = replace_in_string("a $(echo failed >&2; exit 2) b", &settings).unwrap_err();
+        assert!(err.to_string().contains("exit status: 2: failed"));
+        let settings = settings! {
+            vars: vars,
+            syntax: syntax,
+            command_timeout: Duration::from_millis(100)
+        };
+        let err = replace_in_string("$(sleep 5)", &settings).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
 
-/// Replaces all occurrences of variables of the form `${KEY}` in a string
-/// with their respective values.
-///
-/// ```rust
-/// # use repvar::replacer::{replace_in_string, Settings};
-/// # use std::collections::HashMap;
-/// let mut vars = HashMap::new();
-/// vars.insert("key_a".to_string(), "1".to_string());
-/// vars.insert("key_b".to_string(), "2".to_string());
-/// let input = "a ${key_a} $${key_a} b ${key_b} c";
-/// let expected = "a 1 ${key_a} b 2 c";
-/// let actual =
-///     replace_in_string(input, &Settings::builder().vars(vars).build()).unwrap();
-/// assert_eq!(expected, actual);
-/// ```
-///
-/// # Errors
-///
-/// If a variable key was found in the stream,
-/// but `vars` contains no entry for it,
-/// and `fail_on_missing` is `true`.
-pub fn replace_in_string<'t, S: ::std::hash::BuildHasher>(
-    line: &'t str,
-    settings: &Settings<S>,
-) -> io::Result<Cow<'t, str>> {
-    let mut state = ReplState::Text;
-    let mut key = String::with_capacity(64);
-    let mut buff_special = String::with_capacity(5);
-    let mut buff_out = String::with_capacity(line.len() * 3 / 2);
-    let mut replaced = false;
-    for chr in line.chars() {
-        match state {
-            ReplState::Text => {
-                if chr == '$' {
-                    state = ReplState::Dollar1;
-                    buff_special.push(chr);
-                } else {
-                    buff_out.push(chr);
-                }
-            }
-            ReplState::Dollar1 => {
-                if chr == '$' {
-                    state = ReplState::Dollar2;
-                    buff_special.push(chr);
-                } else if chr == '{' {
-                    state = ReplState::Key;
-                    buff_special.clear();
-                } else {
-                    state = ReplState::Text;
-                    buff_out.push_str(&buff_special);
-                    buff_special.clear();
-                }
-            }
-            ReplState::Dollar2 => {
-                buff_special.push(chr);
-                if chr != '$' {
-                    if chr == '{' {
-                        // Remove one of the '$'s,
-                        // so "$$${key_" -> "$${key_",
-                        // for example
-                        buff_special.remove(0);
-                        replaced = true;
-                    }
-                    state = ReplState::Text;
-                    buff_out.push_str(&buff_special);
-                    buff_special.clear();
-                }
-            }
-            ReplState::Key => {
-                if chr == '}' {
-                    let repl = replacement(&key, settings)?;
-                    replaced = replaced || repl.0;
-                    buff_out.push_str(&repl.1);
-                    key.clear();
-                    state = ReplState::Text;
-                } else {
-                    key.push(chr);
-                }
+    #[test]
+    fn test_extract_from_string_commands() {
+        let input = "$(git log -1 ${ref}) ${key_a}";
+        let syntax = Syntax::builder().commands(true).build();
+        assert_eq!(
+            extract_from_string_with(input, &syntax),
+            vec!["ref", "key_a"]
+        );
+    }
+
+    #[test]
+    fn test_replace_in_string_arithmetic() {
+        let mut vars = HashMap::new();
+        vars.insert("base_port".to_string(), "8080".to_string());
+        vars.insert("name".to_string(), "web".to_string());
+        vars.insert("zero".to_string(), "0".to_string());
+        let syntax = Syntax::builder().arithmetic(true).build();
+        let settings = settings! {vars: vars.clone(), syntax: syntax.clone()};
+        let input = "$((base_port + 1)) $((${base_port} * 2 - 1)) $((1 < 2)) $$((1))";
+        assert_eq!(
+            replace_in_string(input, &settings).unwrap(),
+            "8081 16159 1 $((1))"
+        );
+        assert_eq!(
+            replace_in_string("$((other + 1)) $((${other} + 1))", &settings).unwrap(),
+            "$((other + 1)) $((${other} + 1))"
+        );
+        assert_eq!(
+            replace_in_string("$((base_port + 1))", &settings! {vars: vars.clone()}).unwrap(),
+            "$((base_port + 1))"
+        );
+
+        let err = replace_in_string("$((name + 1))", &settings).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Arithmetic expression 'name + 1': Value 'web' of variable 'name' is not an integer"
+        );
+        let err = replace_in_string("$((1 / ${zero}))", &settings).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Arithmetic expression '1 / 0': Division by zero"
+        );
+        let settings = settings! {vars: vars, syntax: syntax, fail_on_missing: true};
+        let err = replace_in_string("$((other + 1))", &settings).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "Undefined variable 'other'");
+    }
+
+    #[test]
+    fn test_extract_from_string_arithmetic() {
+        let input = "$((a + ${b} * (c - $((d)))))";
+        let syntax = Syntax::builder().arithmetic(true).build();
+        assert_eq!(
+            extract_from_string_with(input, &syntax),
+            vec!["a", "b", "c", "d"]
+        );
+    }
+
+    #[test]
+    fn test_replace_in_string_escape_runs_baseline() {
+        // As in all versions before the escape policy was introduced
+        let mut vars = HashMap::new();
+        vars.insert("k".to_string(), "value".to_string());
+        let input = "${k} $${k} $$${k} $$$${k} $$ $$$ $${k";
+        let expected = "value ${k} $${k} $$${k} $$ $$$ ${k";
+        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_replace_in_string_escape_runs() {
+        let mut vars = HashMap::new();
+        vars.insert("K".to_string(), "v".to_string());
+        let replace = |input: &str, escape: Escape, escape_runs: EscapeRuns, bare_vars: bool| {
+            let syntax = Syntax::builder()
+                .escape(escape)
+                .escape_runs(escape_runs)
+                .bare_vars(bare_vars)
+                .build();
+            replace_in_string(input, &settings! {vars: vars.clone(), syntax: syntax})
+                .unwrap()
+                .into_owned()
+        };
+        let dollar = [
+            ("${K}", "v", "v"),
+            ("$${K}", "${K}", "${K}"),
+            ("$$${K}", "$${K}", "$v"),
+            ("$$$${K}", "$$${K}", "$${K}"),
+            ("$$$$${K}", "$$$${K}", "$$v"),
+            ("$$$$$${K}", "$$$$${K}", "$$${K}"),
+            ("$", "$", "$"),
+            ("$$", "$$", "$$"),
+            ("$$$", "$$$", "$$$"),
+            ("$$ $${K", "$$ ${K", "$$ ${K"),
+            ("$$K $$$K", "$$K $$$K", "$$K $$$K"),
+            // A quoted opening delimiter needs no closing one
+            ("${X:-$${K} ${X:-$$${K}}", "${K $${K}", "${K $v"),
+        ];
+        for (input, last, pairs) in dollar {
+            let actual = replace(input, Escape::Dollar, EscapeRuns::Last, false);
+            assert_eq!(actual, last, "{input}");
+            let actual = replace(input, Escape::Dollar, EscapeRuns::Pairs, false);
+            assert_eq!(actual, pairs, "{input}");
+        }
+        let dollar_bare = [
+            ("$K", "v", "v"),
+            ("$$K", "$K", "$K"),
+            ("$$$K", "$$K", "$v"),
+            ("$$$$K", "$$$K", "$$K"),
+            ("$$${K}", "$${K}", "$v"),
+            ("$$ $1", "$$ $1", "$$ $1"),
+        ];
+        for (input, last, pairs) in dollar_bare {
+            let actual = replace(input, Escape::Dollar, EscapeRuns::Last, true);
+            assert_eq!(actual, last, "{input}");
+            let actual = replace(input, Escape::Dollar, EscapeRuns::Pairs, true);
+            assert_eq!(actual, pairs, "{input}");
+        }
+        let backslash = [
+            (r"\${K}", "${K}", "${K}"),
+            (r"\\${K}", r"\${K}", r"\v"),
+            (r"\\\${K}", r"\\${K}", r"\${K}"),
+            (r"\\\\${K}", r"\\\${K}", r"\\v"),
+            (r"$${K}", "$v", "$v"),
+            (r"\\ \n", r"\\ \n", r"\\ \n"),
+        ];
+        for (input, last, pairs) in backslash {
+            let actual = replace(input, Escape::Backslash, EscapeRuns::Last, false);
+            assert_eq!(actual, last, "{input}");
+            let actual = replace(input, Escape::Backslash, EscapeRuns::Pairs, false);
+            assert_eq!(actual, pairs, "{input}");
+        }
+        let disabled = [("$${K}", "$v"), (r"\${K}", r"\v"), ("$$$${K}", "$$$v")];
+        for (input, expected) in disabled {
+            for escape_runs in [EscapeRuns::Last, EscapeRuns::Pairs] {
+                let actual = replace(input, Escape::Disabled, escape_runs, false);
+                assert_eq!(actual, expected, "{input}");
             }
         }
     }
 
-    if replaced {
-        buff_out.push_str(&buff_special);
-        if matches!(state, ReplState::Key) {
-            buff_out.push_str("${");
+    #[test]
+    fn test_quote_round_trip() {
+        let syntaxes = [
+            Syntax::builder().build(),
+            Syntax::builder().escape_runs(EscapeRuns::Pairs).build(),
+            Syntax::builder().bare_vars(true).arithmetic(true).build(),
+            Syntax::builder()
+                .bare_vars(true)
+                .arithmetic(true)
+                .escape_runs(EscapeRuns::Pairs)
+                .build(),
+            Syntax::builder().escape(Escape::Backslash).build(),
+            Syntax::builder()
+                .opening("@")
+                .closing("@")
+                .escape(Escape::Backslash)
+                .build(),
+            Syntax::builder()
+                .opening("%")
+                .closing("%")
+                .escape("%")
+                .build(),
+        ];
+        let alphabet = ['$', '{', '}', '\\', '@', '%', '(', ')', 'K'];
+        let mut texts = vec![String::new()];
+        for _ in 0..5 {
+            let longer = texts
+                .iter()
+                .flat_map(|text| alphabet.iter().map(move |chr| format!("{text}{chr}")))
+                .collect::<Vec<_>>();
+            texts.extend(longer);
+        }
+        texts.sort();
+        texts.dedup();
+        for syntax in syntaxes {
+            let settings = settings! {vars: HashMap::new(), syntax: syntax, fail_on_missing: true};
+            for text in &texts {
+                let quoted = settings.syntax.quote(text);
+                let actual = replace_in_string(&quoted, &settings).unwrap();
+                assert_eq!(actual, text.as_str(), "quoted: {quoted}");
+            }
         }
-        buff_out.push_str(&key);
-
-        Ok(Cow::Owned(buff_out))
-    } else {
-        // There was no replacement at all
-        // -> return the input
-        Ok(Cow::Borrowed(line))
     }
-}
 
-/// Replaces all occurrences of variables of the form `${KEY}` in a input stream
-/// with their respective values.
-///
-/// # Errors
-///
-/// If a variable key was found in the stream,
-/// but `vars` contains no entry for it,
-/// and `fail_on_missing` is `true`.
-///
-/// If reading from the `reader` failed.
-///
-/// If writing to the `writer` failed.
-pub fn replace_in_stream<S: ::std::hash::BuildHasher>(
-    reader: &mut impl BufRead,
-    writer: &mut impl Write,
-    settings: &Settings<S>,
-) -> io::Result<()> {
-    if tracing::enabled!(tracing::Level::DEBUG) {
-        for (key, value) in &settings.vars {
-            tracing::debug!("VARIABLE: {key}={value}");
-        }
+    #[test]
+    fn test_replace_in_string_strict() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        vars.insert("stage".to_string(), "dev test".to_string());
+        let settings = settings! {vars: vars.clone(), strict: true};
+        let replace = |input| {
+            replace_in_string(input, &settings)
+                .map_err(|err| err.to_string())
+                .map(Cow::into_owned)
+        };
+        assert_eq!(
+            replace("a ${key_a} $${b c} ${x:-y z}"),
+            Ok("a 1 ${b c} y z".to_owned())
+        );
+        assert_eq!(
+            replace("a ${key_a} ${key_b"),
+            Err("line 1, column 12: Unterminated placeholder".to_owned())
+        );
+        assert_eq!(
+            replace("a\nb ${key a}"),
+            Err("line 2, column 3: Invalid key 'key a' in placeholder '${key a}'".to_owned())
+        );
+        assert_eq!(
+            replace("${key_a:-${}}"),
+            Err("line 1, column 10: Invalid key '' in placeholder '${}'".to_owned())
+        );
+        assert_eq!(
+            replace("${for a-b in key_a}${end}"),
+            Err(
+                "line 1, column 1: Invalid key 'a-b' in placeholder '${for a-b in key_a}'"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            replace("${url_${stage}}"),
+            Err("Invalid key 'url_dev test'".to_owned())
+        );
+        assert_eq!(
+            replace("${if key_a}x${end} ${key_a|upper}"),
+            Ok("x 1".to_owned())
+        );
+
+        let settings = settings! {
+            vars: vars,
+            strict: true,
+            key_pattern: Regex::new("^[a-z.]+$").unwrap()
+        };
+        assert_eq!(
+            replace_in_string("${key_a}", &settings)
+                .unwrap_err()
+                .to_string(),
+            "line 1, column 1: Invalid key 'key_a' in placeholder '${key_a}'"
+        );
     }
 
-    for line in cli_utils::lines_iterator(reader, false) {
-        writer.write_all(replace_in_string(&line?, settings)?.as_bytes())?;
+    #[test]
+    fn test_replace_in_stream_strict() {
+        let mut input = &b"ok\n${if x}\nfine\n${end}\n  ${x\n"[..];
+        let mut output = vec![];
+        let settings = settings! {vars: HashMap::new(), strict: true};
+        let err = replace_in_stream(&mut input, &mut output, &settings).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 5, column 3: Unterminated placeholder"
+        );
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_replace_in_stream_strict_substitutions() {
+        let syntax = Syntax::builder().arithmetic(true).commands(true).build();
+        let settings = settings! {vars: HashMap::new(), strict: true, syntax: syntax};
+        for (input, expected) in [
+            (
+                "a\n b $((1 + 2)\n",
+                "line 2, column 4: Unterminated arithmetic expansion",
+            ),
+            (
+                "a $(echo x)\n$(echo (x)\n",
+                "line 2, column 1: Unterminated command substitution",
+            ),
+            (
+                "${x $((1)) $((",
+                "line 1, column 1: Unterminated placeholder",
+            ),
+        ] {
+            let mut output = vec![];
+            let err = replace_in_stream(&mut input.as_bytes(), &mut output, &settings).unwrap_err();
+            assert_eq!(err.to_string(), expected);
+        }
+        let syntax = Syntax::builder().arithmetic(true).build();
+        let settings = settings! {vars: HashMap::new(), strict: true, syntax: syntax};
+        let err = replace_in_string("$(x $((x", &settings).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1, column 5: Unterminated arithmetic expansion"
+        );
+        assert_eq!(replace_in_string("$(x", &settings).unwrap(), "$(x");
+    }
 
-/// Replaces all occurrences of variables of the form `${KEY}` in a input stream
-/// with their respective values.
-///
-/// # Errors
-///
-/// If a variable key was found in the stream,
-/// but `vars` contains no entry for it,
-/// and `fail_on_missing` is `true`.
-///
-/// If reading from the `source` failed.
-///
-/// If writing to the `destination` failed.
-pub fn replace_in_file<S: ::std::hash::BuildHasher>(
-    source: Option<&str>,
-    destination: Option<&str>,
-    settings: &Settings<S>,
-) -> io::Result<()> {
-    if tracing::enabled!(tracing::Level::DEBUG) {
-        if let Some(in_file) = source {
-            tracing::debug!("INPUT: {}", &in_file);
+    #[test]
+    fn test_replace_in_stream_byte_chars_in_values() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "\u{10FF80}\u{10FFFF}".to_string());
+        vars.insert("key_b".to_string(), "\u{10FFE9}${key_a}".to_string());
+        vars.insert("list".to_string(), "\u{10FFE9},x".to_string());
+        let input = b"caf\xe9 ${key_a} ${key_c:-\xff${key_a}} ${key_a|upper}\n\
+            ${for item in list}${item}\xe9${end} ${if key_a == \xf4\x8f\xbe\x80\xf4\x8f\xbf\xbf}same${end}\n";
+        let expected =
+            "caf\u{FFFD} \u{10FF80}\u{10FFFF} \u{FFFD}\u{10FF80}\u{10FFFF} \u{10FF80}\u{10FFFF}\n\
+            \u{10FFE9}\u{FFFD}x\u{FFFD} same\n";
+        for recursive in [false, true] {
+            let settings = settings! {vars: vars.clone(), recursive: recursive};
+            let mut output = vec![];
+            replace_in_stream(&mut &input[..], &mut output, &settings).unwrap();
+            assert_eq!(String::from_utf8_lossy(&output), expected);
+            let replaced = replace_in_bytes(input, &settings).unwrap();
+            assert_eq!(String::from_utf8_lossy(&replaced), expected);
         }
-        if let Some(out_file) = destination {
-            tracing::debug!("OUTPUT: {}", &out_file);
+        let settings = settings! {vars: vars, recursive: true};
+        let replaced = replace_in_bytes(b"${key_b}", &settings).unwrap();
+        assert_eq!(&replaced[..], "\u{10FFE9}\u{10FF80}\u{10FFFF}".as_bytes());
+    }
+
+    #[test]
+    fn test_replace_in_stream_chunks() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "äö".to_string());
+        let input = "ä ${key_a} $${key_a} $$$${key_a} ${key_b:-x\ny}\n\
+            ${if key_a}\nÖ ${key_a}\n${end}\n\
+            plain text\n ${if k} \nb\n ${else}\nc\n${end}\n\
+            a long line, ${key_a}, ${key_a}, ${key_a}, ${key_a}, ${key_a}, ${key_a} ${ z\n";
+        let expected = replace_in_string(input, &settings! {vars: vars.clone()}).unwrap();
+        assert!(expected.starts_with("ä äö ${key_a} $$${key_a} x\ny\nÖ äö\nplain text\nc\n"));
+        for capacity in [1, 2, 3, 5, 8, 64] {
+            for buffer_size in [32, DEFAULT_BUFFER_SIZE] {
+                let mut reader = io::BufReader::with_capacity(capacity, input.as_bytes());
+                let mut output = vec![];
+                let settings = settings! {vars: vars.clone(), buffer_size: buffer_size};
+                replace_in_stream(&mut reader, &mut output, &settings).unwrap();
+                assert_eq!(expected, String::from_utf8(output).unwrap());
+            }
         }
     }
 
-    let mut reader = cli_utils::create_input_reader(source)?;
-    let mut writer = cli_utils::create_output_writer(destination)?;
+    #[test]
+    fn test_replace_in_stream_buffer_size() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "1".to_string());
+        let unterminated = format!("a ${{b{}\n${{key_a}}}}\n", "c".repeat(100));
+        let mut reader = io::BufReader::with_capacity(4, unterminated.as_bytes());
+        let mut output = vec![];
+        let settings = settings! {vars: vars.clone(), buffer_size: 16};
+        replace_in_stream(&mut reader, &mut output, &settings).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("a ${{b{}\n1}}\n", "c".repeat(100))
+        );
 
-    replace_in_stream(&mut reader, &mut writer, settings)
-}
+        let settings = settings! {vars: vars.clone(), buffer_size: 16, strict: true};
+        let mut reader = io::BufReader::with_capacity(4, unterminated.as_bytes());
+        let err = replace_in_stream(&mut reader, &mut vec![], &settings).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: Placeholder or block larger than the buffer size of 16 bytes"
+        );
 
-#[cfg(test)]
-mod tests {
-    // Note this useful idiom:
-    // importing names from outer (for mod tests) scope.
-    use super::*;
+        let input = &b"x\n${if key_a}\n0123456789\n${end}\n"[..];
+        let mut reader = io::BufReader::with_capacity(4, input);
+        let settings = settings! {vars: vars, buffer_size: 16};
+        let err = replace_in_stream(&mut reader, &mut vec![], &settings).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: Placeholder or block larger than the buffer size of 16 bytes"
+        );
+    }
 
     #[test]
-    fn test_replace_in_string_no_vars() {
+    fn test_replace_in_stream_multi_line_location() {
         let vars = HashMap::new();
-        let input = "a ${key_a} $${key_a} b ${key_b} c";
-        let expected = "a ${key_a} ${key_a} b ${key_b} c";
-        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
-        assert_eq!(expected, actual);
+        let mut input = &b"a ${key_a:-\n${key_b:-b}\n}\nc ${key_c:?required}\n"[..];
+        let mut output = vec![];
+        let err = replace_in_stream(&mut input, &mut output, &settings! {vars: vars}).unwrap_err();
+        assert_eq!(err.to_string(), "line 4: key_c: required");
+        assert_eq!(String::from_utf8(output).unwrap(), "a \nb\n\n");
     }
 
     #[test]
-    fn test_replace_in_string_one_var() {
-        let mut vars = HashMap::new();
-        vars.insert("key_a".to_string(), "1".to_string());
-        let input = "a ${key_a} $${key_a} b ${key_b} c";
-        let expected = "a 1 ${key_a} b ${key_b} c";
-        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
-        assert_eq!(expected, actual);
+    fn test_segments_bounded() {
+        let settings = settings! {vars: HashMap::new(), buffer_size: 1000};
+        let line = io::Read::take(io::repeat(b'x'), 100_000);
+        let mut reader = io::Read::chain(io::BufReader::with_capacity(100, line), &b"\n"[..]);
+        let mut total = 0;
+        Segments::new(&mut reader, &settings, None)
+            .for_each(|segment| {
+                let len = segment.text.len();
+                assert!(len <= 1100);
+                total += len;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(total, 100_001);
     }
 
     #[test]
-    fn test_replace_in_string_two_vars() {
+    fn test_split_points_resume() {
+        let input = "a\n${if x}\nb ${y}\n${z\n}\n${end}\nc $${d} $$e\r\nf ${g:-\n} $h\ri\n${j";
+        for syntax in [Syntax::default(), Syntax::builder().bare_vars(true).build()] {
+            let full = split_points(input, &syntax, false, Scan::default());
+            for (mid, _) in input.char_indices() {
+                let prefix = input.get(..mid).unwrap();
+                let first = split_points(prefix, &syntax, false, Scan::default());
+                let resume = first.resume;
+                let resumed = split_points(input, &syntax, false, resume);
+                let lines_after = full
+                    .lines
+                    .iter()
+                    .copied()
+                    .filter(|&end| end > resume.start)
+                    .collect::<Vec<_>>();
+                assert_eq!(resumed.lines, lines_after, "resumed at {}", resume.start);
+                assert_eq!(resumed.last, full.last, "resumed at {}", resume.start);
+            }
+        }
+    }
+
+    #[test]
+    fn test_replace_in_stream_long_block() {
         let mut vars = HashMap::new();
         vars.insert("key_a".to_string(), "1".to_string());
-        vars.insert("key_b".to_string(), "2".to_string());
-        let input = "a ${key_a} $${key_a} b ${key_b} c";
-        let expected = "a 1 ${key_a} b 2 c";
-        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
-        assert_eq!(expected, actual);
+        let lines = "some text within a block\n".repeat(20_000);
+        let input = format!("${{if key_a}}\n{lines}${{key_a}}\n${{end}}\n");
+        let mut reader = io::BufReader::with_capacity(16, input.as_bytes());
+        let mut output = vec![];
+        replace_in_stream(&mut reader, &mut output, &settings! {vars: vars}).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), format!("{lines}1\n"));
     }
 
     #[test]
-    fn test_replace_in_string_case_sensitive() {
+    fn test_segments_lone_cr() {
+        struct Failing;
+        impl io::Read for Failing {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            }
+        }
+        let settings = settings! {vars: HashMap::new()};
+        let input = io::Read::chain(&b"x\ry\r"[..], Failing);
+        let mut reader = io::BufReader::with_capacity(2, input);
+        let mut texts = vec![];
+        let result = Segments::new(&mut reader, &settings, None).for_each(|segment| {
+            texts.push(segment.text.to_owned());
+            Ok(())
+        });
+        // The lines are split before the rest of the input is read
+        assert_eq!(texts, ["x\r"]);
+        assert!(result.is_err_and(|err| err.to_string() == "broken"));
+    }
+
+    #[test]
+    fn test_replace_in_stream_non_utf8() {
         let mut vars = HashMap::new();
-        vars.insert("Key_A".to_string(), "1".to_string());
-        vars.insert("key_b".to_string(), "2".to_string());
-        let input = "a ${key_a} $${key_a} b ${key_b} c";
-        let expected = "a ${key_a} ${key_a} b 2 c";
-        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
+        vars.insert("key_a".to_string(), "ä".to_string());
+        let input = b"caf\xe9 ${key_a} \xc3\xa4\n${key_b:-\xff} \xf4\x8f\xbe\x80 \xc3";
+        let expected = b"caf\xe9 \xc3\xa4 \xc3\xa4\n\xff \xf4\x8f\xbe\x80 \xc3";
+        for capacity in [1, 2, 3, 64] {
+            let mut reader = io::BufReader::with_capacity(capacity, &input[..]);
+            let mut output = vec![];
+            replace_in_stream(&mut reader, &mut output, &settings! {vars: vars.clone()}).unwrap();
+            assert_eq!(expected[..], output);
+        }
+    }
+
+    #[test]
+    fn test_extract_from_stream_non_utf8() {
+        let vars = HashMap::new();
+        let mut input = &b"caf\xe9 ${key_a} ${k\xe9y}\n"[..];
+        let expected = vec!["key_a", "k\u{FFFD}y"];
+        let actual = extract_from_stream_with(&mut input, &settings! {vars: vars}).unwrap();
         assert_eq!(expected, actual);
     }
 
     #[test]
-    fn test_replace_in_string_missing_closing_bracket() {
+    fn test_replace_in_stream_line_endings() {
         let mut vars = HashMap::new();
         vars.insert("key_a".to_string(), "1".to_string());
-        let input = "a ${key_a";
-        let expected = "a ${key_a";
-        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
-        assert_eq!(expected, actual);
+        let replace = |input: &str, line_endings: LineEndings, capacity: usize| {
+            let mut reader = io::BufReader::with_capacity(capacity, input.as_bytes());
+            let mut output = vec![];
+            let settings = settings! {vars: vars.clone(), line_endings: line_endings};
+            replace_in_stream(&mut reader, &mut output, &settings).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let cases = [
+            ("", "", "", ""),
+            ("a ${key_a}", "a 1", "a 1", "a 1"),
+            (
+                "a ${key_a}\r\nb\r\n",
+                "a 1\r\nb\r\n",
+                "a 1\nb\n",
+                "a 1\r\nb\r\n",
+            ),
+            ("a ${key_a}\rb\r", "a 1\rb\r", "a 1\nb\n", "a 1\r\nb\r\n"),
+            (
+                "a\n\r\n${key_a}\r",
+                "a\n\r\n1\r",
+                "a\n\n1\n",
+                "a\r\n\r\n1\r\n",
+            ),
+            (
+                "${if key_a}\r\nx\r\n${end}\r\ny",
+                "x\r\ny",
+                "x\ny",
+                "x\r\ny",
+            ),
+            ("${if key_a}\rx\r${end}\ry", "x\ry", "x\ny", "x\r\ny"),
+        ];
+        for (input, kept, lf, crlf) in cases {
+            for capacity in [1, 2, 3, 64] {
+                assert_eq!(replace(input, LineEndings::Keep, capacity), kept);
+                assert_eq!(replace(input, LineEndings::Lf, capacity), lf);
+                assert_eq!(replace(input, LineEndings::Crlf, capacity), crlf);
+            }
+        }
     }
 
     #[test]
-    fn test_replace_in_string_missing_closing_bracket_and_key() {
+    fn test_replace_in_stream_cr_location() {
+        let vars = HashMap::new();
+        let mut input = &b"a\rb\r\n${key_b:?required}\r"[..];
+        let err = replace_in_stream(&mut input, &mut vec![], &settings! {vars: vars}).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: key_b: required");
+    }
+
+    #[test]
+    fn test_template() {
+        let input = "a ${key_a}\n${for item in list}- ${item|upper}\n${end}b $${key_a}\n";
+        let mut settings = settings! {vars: HashMap::new()};
+        let template = Template::new(input, &settings).unwrap();
+        assert_eq!(template.text(), input);
+        assert_eq!(template.placeholders(), ["key_a", "list"]);
+
         let mut vars = HashMap::new();
         vars.insert("key_a".to_string(), "1".to_string());
-        let input = "a ${";
-        let expected = "a ${";
-        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
-        assert_eq!(expected, actual);
+        vars.insert("list".to_string(), "x, y".to_string());
+        settings.set_vars(vars);
+        assert_eq!(
+            template.render(&settings).unwrap(),
+            "a 1\n- X\n- Y\nb ${key_a}\n"
+        );
+
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "2".to_string());
+        vars.insert("list".to_string(), "z".to_string());
+        let mut buff_out = String::from("> ");
+        template
+            .render_into(
+                &mut buff_out,
+                &settings! {vars: vars, line_endings: LineEndings::Crlf},
+            )
+            .unwrap();
+        assert_eq!(buff_out, "> a 2\r\n- Z\r\nb ${key_a}\r\n");
+
+        let plain = Template::new("no variables", &settings).unwrap();
+        assert!(matches!(
+            plain.render(&settings).unwrap(),
+            Cow::Borrowed("no variables")
+        ));
+        assert!(Template::new("${if key_a}a", &settings).is_err());
+
+        let strict = settings! {vars: HashMap::new(), strict: true};
+        let err = Template::new("a\n ${key_a", &strict).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "line 2, column 2: Unterminated placeholder"
+        );
     }
 
     #[test]
-    fn test_replace_in_string_missing_closing_bracket_quoted() {
+    fn test_template_parsed_once() {
+        let input = "${key_a:-${key_b}|replace:${key_c}:x|upper} ${#key_a} ${key_${key_b}} \
+            ${key_a:1:${key_d}} ${key_a//?/${key_c}} ${key_d:+$((1 + ${key_d}))}\n\
+            ${if key_a == ${key_b}}${for item in key_${key_b}}[${item:0:1}]${end}${end}";
         let mut vars = HashMap::new();
-        vars.insert("key_a".to_string(), "1".to_string());
-        let input = "a $${key_a";
-        let expected = "a ${key_a"; // NOTE Do we really want it this way, or should there still be two $$? this way is easy to implement, the other way seems more correct
-        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
-        assert_eq!(expected, actual);
+        vars.insert("key_a".to_string(), "abc".to_string());
+        vars.insert("key_b".to_string(), "abc".to_string());
+        vars.insert("key_c".to_string(), "b".to_string());
+        vars.insert("key_d".to_string(), "1".to_string());
+        vars.insert("key_abc".to_string(), "x, y".to_string());
+        let syntax = Syntax::builder().arithmetic(true).build();
+        let mut settings = settings! {vars: vars.clone(), syntax: syntax, strict: true};
+        let template = Template::new(input, &settings).unwrap();
+        for _ in 0..3 {
+            assert_eq!(
+                template.render(&settings).unwrap(),
+                "AXC 3 x, y b bbb 2\n[x][y]"
+            );
+        }
+        // The same template, rendered with other variables
+        vars.insert("key_a".to_string(), "xyz".to_string());
+        vars.insert("key_b".to_string(), "xyz".to_string());
+        vars.insert("key_c".to_string(), "y".to_string());
+        vars.insert("key_d".to_string(), "2".to_string());
+        vars.insert("key_xyz".to_string(), "u, v, w".to_string());
+        settings.set_vars(vars.clone());
+        assert_eq!(
+            template.render(&settings).unwrap(),
+            "XXZ 3 u, v, w yz yyy 3\n[u][v][w]"
+        );
+        vars.remove("key_a");
+        vars.remove("key_d");
+        settings.set_vars(vars);
+        assert_eq!(
+            template.render(&settings).unwrap(),
+            "XXZ ${#key_a} u, v, w ${key_a:1:${key_d}} ${key_a//?/${key_c}} \n"
+        );
+
+        let other = settings! {vars: HashMap::new()};
+        let err = template.render(&other).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let mut buff_out = String::new();
+        assert!(template.render_into(&mut buff_out, &other).is_err());
     }
+
     #[test]
-    fn test_replace_in_string_missing_closing_bracket_and_key_quoted() {
-        let vars = HashMap::new();
-        let input = "a $${";
-        let expected = "a ${"; // NOTE Do we really want it this way, or should there still be two $$? this way is easy to implement, the other way seems more correct
-        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
-        assert_eq!(expected, actual);
+    fn test_replacement_borrowed_if_unchanged() {
+        let mut vars = HashMap::new();
+        vars.insert("key_a".to_string(), "abc".to_string());
+        let settings = settings! {vars: vars};
+        for (input, expected, borrowed) in [
+            ("${key_a}", "abc", true),
+            ("${key_a#x}", "abc", true),
+            ("${key_a#a}", "bc", true),
+            ("${key_a:1:1}", "b", true),
+            ("${key_a^^[x]}", "abc", true),
+            ("${key_a|trim}", "abc", true),
+            ("${key_a:+}", "", true),
+            ("${key_a|upper}", "ABC", false),
+            ("${#key_a}", "3", false),
+        ] {
+            let nodes = parse_nodes(input, &settings.syntax).unwrap();
+            let [Node::Var { ref var, .. }] = nodes[..] else {
+                panic!("Not a single variable: {input}");
+            };
+            let value = replacement(var, &settings, &mut Context::default(), None)
+                .unwrap()
+                .unwrap();
+            assert_eq!(value, expected);
+            assert_eq!(matches!(value, Cow::Borrowed(_)), borrowed, "{input}");
+        }
     }
 
     #[test]
-    fn test_replace_in_string_with_var_with_dollar_value() {
+    fn test_settings_shared_by_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Settings<std::collections::hash_map::RandomState>>();
+
         let mut vars = HashMap::new();
-        vars.insert("key_a".to_string(), "1$2".to_string());
-        let input = "a ${key_a} $${key_a} b ${key_b} c";
-        let expected = "a 1$2 ${key_a} b ${key_b} c";
-        let actual = replace_in_string(input, &settings! {vars: vars}).unwrap();
-        assert_eq!(expected, actual);
+        vars.insert("key_a".to_string(), "1".to_string());
+        let settings = settings! {vars: vars};
+        let settings = &settings;
+        let outputs = std::thread::scope(|scope| {
+            [0, 1, 2, 3]
+                .map(|idx| {
+                    scope.spawn(move || {
+                        replace_in_string(&format!("{idx}:${{key_a}}"), settings)
+                            .map(Cow::into_owned)
+                    })
+                })
+                .map(|handle| handle.join().unwrap().unwrap())
+        });
+        assert_eq!(outputs, ["0:1", "1:1", "2:1", "3:1"]);
     }
 }
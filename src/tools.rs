@@ -4,7 +4,11 @@
 
 use std::collections::HashMap;
 use std::env;
+use std::ffi::OsString;
+use std::fs;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
 pub fn append_env<S: ::std::hash::BuildHasher>(vars: &mut HashMap<String, String, S>) {
     for env_var in env::vars() {
@@ -151,3 +155,73 @@ pub fn write_to_file(lines: Vec<String>, destination: Option<&str>) -> io::Resul
 
     Ok(())
 }
+
+/// A file that replaces its destination only once it is complete.
+///
+/// It is written under a temporary name in the same directory,
+/// and gets renamed to the destination on [`Self::commit`] only,
+/// so the destination never ends up with partial content.
+/// If it is dropped without being committed, it is removed.
+pub struct StagedFile {
+    destination: PathBuf,
+    temp: PathBuf,
+    writer: io::BufWriter<fs::File>,
+    committed: bool,
+}
+
+impl StagedFile {
+    /// Creates the temporary file for the destination `path`.
+    ///
+    /// # Errors
+    ///
+    /// If the temporary file could not be created.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let destination = path.as_ref().to_owned();
+        let mut name = destination.file_name().unwrap_or_default().to_owned();
+        name.push(format!(".{}.tmp", process::id()));
+        let mut temp_name = OsString::from(".");
+        temp_name.push(name);
+        let temp = destination.with_file_name(temp_name);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        Ok(Self {
+            destination,
+            temp,
+            writer: io::BufWriter::new(file),
+            committed: false,
+        })
+    }
+
+    /// Replaces the destination with what was written so far.
+    ///
+    /// # Errors
+    ///
+    /// If writing or renaming the temporary file failed.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        fs::rename(&self.temp, &self.destination)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for StagedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            // There is nothing left to do if this fails
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
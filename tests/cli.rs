@@ -2,6 +2,9 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+// Our variable syntax looks a lot like rust formatting arguments
+#![allow(clippy::literal_string_with_formatting_args)]
+
 mod cli_api;
 
 use cli_api::write_to_file;
@@ -85,6 +88,625 @@ fn quoting() -> Result<(), Box<dyn std::error::Error>> {
         .run_test()
 }
 
+#[test]
+fn default_value() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .arg("-DKEY=value")
+        .stdin("Values: ${KEY:-a}, ${OTHER:-b}, ${OTHER:-${KEY}}.")
+        .stdout("Values: value, b, value.")
+        .run_test()
+}
+
+#[test]
+fn required_value() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .arg("-DKEY=value")
+        .stdin("Values: ${KEY:?not printed}, ${OTHER:?set OTHER to something nice}.")
+        .stderr("line 1: OTHER: set OTHER to something nice")
+        .run_test()
+}
+
+#[test]
+fn required_value_file() -> Result<(), Box<dyn std::error::Error>> {
+    let file = NamedTempFile::new()?;
+    write_to_file(file.path(), "first line\nValue: ${OTHER:?set OTHER}\n");
+    let file_path_string = file.path().as_os_str().to_str().ok_or("Non UTF-8 string")?;
+
+    Tester::new(CMD)
+        .arg("-i")
+        .arg(file_path_string)
+        .stderr(&format!("{file_path_string}:2: OTHER: set OTHER"))
+        .run_test()
+}
+
+#[test]
+fn alternative_value() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .arg("-DPROXY=http://proxy")
+        .arg("-DNO_PROXY=")
+        .stdin("cmd${PROXY:+ --proxy=${PROXY}}${NO_PROXY:+ --no-proxy=${NO_PROXY}}${X+ x}")
+        .stdout("cmd --proxy=http://proxy")
+        .run_test()
+}
+
+#[test]
+fn empty_value() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .arg("-DKEY=")
+        .stdin("a${KEY}b${KEY-x}c${KEY:-x}d${KEY+y}e${KEY:+y}f")
+        .stdout("abcxdyef")
+        .run_test()
+}
+
+#[test]
+fn bare() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .arg("-DKEY=value")
+        .arg("--bare")
+        .stdin("Values: $KEY, ${KEY}, $$KEY, $OTHER.")
+        .stdout("Values: value, value, $KEY, $OTHER.")
+        .run_test()
+}
+
+#[test]
+fn bare_disabled() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .arg("-DKEY=value")
+        .stdin("Values: $KEY, ${KEY}.")
+        .stdout("Values: $KEY, value.")
+        .run_test()
+}
+
+#[test]
+fn shell_format() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .env("KEY_A", "a")
+        .env("KEY_B", "b")
+        .env("KEY_C", "c")
+        .args(&["--env", "-b", "$KEY_A ${KEY_C}"])
+        .stdin("Values: $KEY_A, ${KEY_B}, $KEY_C.")
+        .stdout("Values: a, ${KEY_B}, c.")
+        .run_test()
+}
+
+#[test]
+fn custom_delimiters() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&["-DKEY=value", "--opening", "{{", "--closing", "}}"])
+        .stdin("echo \"${HOME}\" {{KEY}} ${{KEY}}")
+        .stdout("echo \"${HOME}\" value {{KEY}}")
+        .run_test()
+}
+
+#[test]
+fn custom_delimiters_list() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&["--list", "--opening=@", "--closing=@", "--escape=\\"])
+        .stdin("@KEY_A@ ${KEY_B} \\@KEY_C@ @KEY_D:-x@\n")
+        .stdout("KEY_A\nKEY_D\n")
+        .run_test()
+}
+
+#[test]
+fn escape_runs() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .arg("-DKEY=value")
+        .stdin("$KEY $${KEY} $$${KEY} $$$${KEY} $$")
+        .stdout("$KEY ${KEY} $${KEY} $$${KEY} $$")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["-DKEY=value", "--escape-runs", "pairs"])
+        .stdin("$KEY $${KEY} $$${KEY} $$$${KEY} $$")
+        .stdout("$KEY ${KEY} $value $${KEY} $$")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["-DKEY=value", "--escape", "\\", "--escape-runs=pairs"])
+        .stdin("\\${KEY} \\\\${KEY} $${KEY}")
+        .stdout("${KEY} \\value $value")
+        .run_test()
+}
+
+#[test]
+fn strict() -> Result<(), Box<dyn std::error::Error>> {
+    let file = NamedTempFile::new()?;
+    write_to_file(file.path(), "host: ${HOST}\nport: ${PORT\n");
+    let file_path_string = file.path().as_os_str().to_str().ok_or("Non UTF-8 string")?;
+
+    Tester::new(CMD)
+        .args(&["--strict", "-DHOST=example.com", "-i", file_path_string])
+        .stderr(&format!("{file_path_string}:2:7: Unterminated placeholder"))
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["--strict", "-DHOST=example.com"])
+        .stdin("${HOST NAME}")
+        .stderr("line 1, column 1: Invalid key 'HOST NAME'")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&[
+            "--strict",
+            "--key-pattern",
+            "[a-z.]+",
+            "-Dhost.name=example.com",
+        ])
+        .stdin("${host.name}\n")
+        .stdout("example.com\n")
+        .run_test()
+}
+
+#[test]
+fn strict_keeps_output_file() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let output = dir.path().join("out.conf");
+    std::fs::write(&output, "old\n")?;
+    let output_string = output.to_str().ok_or("Non UTF-8 string")?;
+
+    Tester::new(CMD)
+        .args(&["--strict", "-o", output_string])
+        .stdin("a\nb\n${X\n")
+        .stderr("line 3, column 1: Unterminated placeholder")
+        .run_test()?;
+    assert_eq!(std::fs::read_to_string(&output)?, "old\n");
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+
+    Tester::new(CMD)
+        .args(&["--strict", "-DX=x", "-o", output_string])
+        .stdin("a\nb\n${X}\n")
+        .stdout("")
+        .run_test()?;
+    assert_eq!(std::fs::read_to_string(&output)?, "a\nb\nx\n");
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+    Ok(())
+}
+
+#[test]
+fn buffer_size() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .stdin("args: ${ARGS:-\n  --verbose\n  --color}\n")
+        .stdout("args: \n  --verbose\n  --color\n")
+        .run_test()?;
+    let block = format!("${{if A}}\n{}\n${{end}}\n", "x".repeat(10_000));
+    Tester::new(CMD)
+        .args(&["-DA=1"])
+        .stdin(&block)
+        .stdout(&format!("{}\n", "x".repeat(10_000)))
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["-DA=1", "--buffer-size", "1024"])
+        .stdin(&block)
+        .stderr("line 1: Placeholder or block larger than the buffer size of 1024 bytes")
+        .run_test()
+}
+
+#[test]
+fn non_utf8() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let input = dir.path().join("latin1.txt");
+    let output = dir.path().join("output.txt");
+    std::fs::write(&input, b"Caf\xe9 ${NAME:-\xe0 la carte}, \x00\xff\n")?;
+
+    Tester::new(CMD)
+        .args(&[
+            "-DNAME=Bar",
+            "-i",
+            input.to_str().ok_or("Non UTF-8 path")?,
+            "-o",
+            output.to_str().ok_or("Non UTF-8 path")?,
+        ])
+        .stdout("")
+        .run_test()?;
+    assert_eq!(std::fs::read(&output)?, b"Caf\xe9 Bar, \x00\xff\n");
+    Tester::new(CMD)
+        .args(&[
+            "-i",
+            input.to_str().ok_or("Non UTF-8 path")?,
+            "-o",
+            output.to_str().ok_or("Non UTF-8 path")?,
+        ])
+        .stdout("")
+        .run_test()?;
+    assert_eq!(
+        std::fs::read(&output)?,
+        b"Caf\xe9 \xe0 la carte, \x00\xff\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn multiple_inputs() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let dir_string = dir.path().to_str().ok_or("Non UTF-8 path")?;
+    std::fs::create_dir(dir.path().join("conf"))?;
+    write_to_file(&dir.path().join("conf/a.txt"), "a: ${KEY}\n");
+    write_to_file(&dir.path().join("b.txt"), "b: ${KEY}\n");
+    write_to_file(&dir.path().join("bad.txt"), "bad: ${OTHER:?required}\n");
+
+    Tester::new(CMD)
+        .cwd(dir_string)
+        .args(&["-DKEY=value", "-j2", "-i", "conf/a.txt", "-i", "b.txt"])
+        .stdout("a: value\nb: value\n")
+        .run_test()?;
+    Tester::new(CMD)
+        .cwd(dir_string)
+        .args(&["--list", "-i", "b.txt", "-i", "conf/a.txt"])
+        .stdout("KEY\nKEY\n")
+        .run_test()?;
+    Tester::new(CMD)
+        .cwd(dir_string)
+        .args(&[
+            "-DKEY=value",
+            "--output-dir",
+            "out",
+            "-i",
+            "conf/a.txt",
+            "-i",
+            "b.txt",
+        ])
+        .stdout("")
+        .run_test()?;
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("out/conf/a.txt"))?,
+        "a: value\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("out/b.txt"))?,
+        "b: value\n"
+    );
+    Tester::new(CMD)
+        .cwd(dir_string)
+        .args(&[
+            "-DKEY=value",
+            "-i",
+            "missing.txt",
+            "-i",
+            "b.txt",
+            "-i",
+            "bad.txt",
+        ])
+        .stderr("Failed to render 2 of 3 input files")
+        .run_test()?;
+    Ok(())
+}
+
+#[test]
+fn multiple_inputs_order() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let dir_string = dir.path().to_str().ok_or("Non UTF-8 path")?;
+    let mut args = vec!["-DKEY=value".to_owned(), "-j4".to_owned()];
+    let mut expected = String::new();
+    for idx in 0..12 {
+        let name = format!("{idx}.txt");
+        if idx % 5 == 3 {
+            write_to_file(&dir.path().join(&name), "${OTHER:?required}\n");
+        } else {
+            // Earlier inputs are larger, so they take longer to render
+            let lines = format!("{idx}: ${{KEY}}\n").repeat((12 - idx) * 1000);
+            write_to_file(&dir.path().join(&name), &lines);
+            expected.push_str(&lines.replace("${KEY}", "value"));
+        }
+        args.extend(["-i".to_owned(), name]);
+    }
+    let arg_refs = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    Tester::new(CMD)
+        .cwd(dir_string)
+        .args(&arg_refs)
+        .stdout(&expected)
+        .stderr("Failed to render 2 of 12 input files")
+        .run_test()?;
+    Ok(())
+}
+
+#[test]
+fn list_locations() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let dir_string = dir.path().to_str().ok_or("Non UTF-8 path")?;
+    write_to_file(&dir.path().join("a.txt"), "a: ${KEY}\n  ${OTHER:-x}\n");
+    write_to_file(&dir.path().join("b.txt"), "b: ${KEY}\n");
+
+    Tester::new(CMD)
+        .cwd(dir_string)
+        .args(&["--list", "--locations", "-i", "a.txt", "-i", "b.txt"])
+        .stdout("a.txt:1:6: KEY\na.txt:2:5: OTHER\nb.txt:1:6: KEY\n")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["--list", "--locations"])
+        .stdin("x\n ${KEY}")
+        .stdout("2:4: KEY\n")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["--locations"])
+        .stdin("${KEY}")
+        .stderr("--list")
+        .run_test()
+}
+
+#[test]
+fn line_endings() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&["-DKEY=value"])
+        .stdin("a ${KEY}\r\nb\rc")
+        .stdout("a value\r\nb\rc")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["-DKEY=value", "--line-endings", "lf"])
+        .stdin("a ${KEY}\r\nb\rc")
+        .stdout("a value\nb\nc")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["-DKEY=value", "--line-endings", "crlf"])
+        .stdin("a ${KEY}\nb\n")
+        .stdout("a value\r\nb\r\n")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["--line-endings", "cr"])
+        .stdin("a\n")
+        .stderr("invalid value 'cr'")
+        .run_test()
+}
+
+#[test]
+fn recursive() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&[
+            "-DURL=${SCHEME}://${HOST}",
+            "-DSCHEME=https",
+            "-DHOST=example.com",
+        ])
+        .arg("--recursive")
+        .stdin("url: ${URL}")
+        .stdout("url: https://example.com")
+        .run_test()
+}
+
+#[test]
+fn recursive_disabled() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&[
+            "-DURL=${SCHEME}://${HOST}",
+            "-DSCHEME=https",
+            "-DHOST=example.com",
+        ])
+        .stdin("url: ${URL}")
+        .stdout("url: ${SCHEME}://${HOST}")
+        .run_test()
+}
+
+#[test]
+fn recursive_cycle() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&["-DA=${B}", "-DB=${A}", "-r"])
+        .stdin("value: ${A}")
+        .stderr("Cyclic variable reference: A -> B -> A")
+        .run_test()
+}
+
+#[test]
+fn nested_key() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&["-DSTAGE=prod", "-DDB_URL_prod=db.example.com"])
+        .stdin("db: ${DB_URL_${STAGE}}")
+        .stdout("db: db.example.com")
+        .run_test()
+}
+
+#[test]
+fn nested_key_list() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .arg("--list")
+        .stdin("db: ${DB_URL_${STAGE}}\n")
+        .stdout("DB_URL_${STAGE}\nSTAGE\n")
+        .run_test()
+}
+
+#[test]
+fn filters() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .arg("-DKEY= my value ")
+        .stdin("Values: ${KEY|trim|upper}, ${KEY|trim|replace: :_}.")
+        .stdout("Values: MY VALUE, my_value.")
+        .run_test()
+}
+
+#[test]
+fn filters_unknown() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .arg("-DKEY=value")
+        .stdin("Value: ${KEY|no_such_filter}.")
+        .stderr("Unknown filter 'no_such_filter'")
+        .run_test()
+}
+
+#[test]
+fn escaping() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&["-DPASS=se\"cret", "-DDIR=my dir"])
+        .stdin(r#"{"pass": "${PASS|json}", "cmd": "ls ${DIR|shell}"}"#)
+        .stdout(r#"{"pass": "se\"cret", "cmd": "ls 'my dir'"}"#)
+        .run_test()
+}
+
+#[test]
+fn escaping_default() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&["-DPASS=se\"cret", "-DDIR=my dir", "--escaping", "json"])
+        .stdin(r#"{"pass": "${PASS}", "url": "file:///${DIR|url}"}"#)
+        .stdout(r#"{"pass": "se\"cret", "url": "file:///my%20dir"}"#)
+        .run_test()
+}
+
+#[test]
+fn transform() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&[
+            "-DVERSION=v1.2.3",
+            "-DIMAGE=registry.example.com/app:latest",
+        ])
+        .stdin("${VERSION#v} ${IMAGE##*/} ${IMAGE%:*} ${VERSION//./-} ${VERSION:1:3}")
+        .stdout("1.2.3 app:latest registry.example.com/app v1-2-3 1.2")
+        .run_test()
+}
+
+#[test]
+fn length_case_indirect() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&[
+            "-DNAME=repvar",
+            "-DSTAGE=URL_PROD",
+            "-DURL_PROD=https://example.com",
+        ])
+        .stdin("${NAME^^} (${#NAME}): ${!STAGE}")
+        .stdout("REPVAR (6): https://example.com")
+        .run_test()
+}
+
+#[test]
+fn indirect_list() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&["--list", "-DSTAGE=URL_PROD"])
+        .stdin("${!STAGE} ${!OTHER}")
+        .stdout("STAGE\nURL_PROD\nOTHER\n")
+        .run_test()
+}
+
+#[test]
+fn conditional() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&["-DTLS=yes", "-DPORT=443"])
+        .stdin("server {\n${if TLS == yes}\n  listen ${PORT} ssl;\n${else}\n  listen ${PORT};\n${end}\n}\n")
+        .stdout("server {\n  listen 443 ssl;\n}\n")
+        .run_test()
+}
+
+#[test]
+fn conditional_unterminated() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .stdin("a\n${if TLS}\nb\n")
+        .stderr("line 2: Missing end in '${if ...}' block")
+        .run_test()
+}
+
+#[test]
+fn loop_over_list() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .arg("--env")
+        .env("HOSTS", "a.example.com,b.example.com")
+        .stdin("${for i, host in HOSTS}\nserver${i} ${host}\n${end}\n")
+        .stdout("server0 a.example.com\nserver1 b.example.com\n")
+        .run_test()
+}
+
+#[test]
+fn loop_over_list_list() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .arg("--list")
+        .stdin("${for i, host in HOSTS}\nserver${i} ${host}:${PORT}\n${end}\n")
+        .stdout("HOSTS\nPORT\n")
+        .run_test()
+}
+
+#[test]
+fn include() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    let shared = tempfile::tempdir()?;
+    let main = dir.path().join("main.txt");
+    write_to_file(
+        &main,
+        "${include:header.txt}\nbody\n${include:footer.txt}\n",
+    );
+    write_to_file(&dir.path().join("header.txt"), "Hello ${NAME}!\n");
+    write_to_file(&shared.path().join("footer.txt"), "Bye ${NAME}!\n");
+    let main_string = main.to_str().ok_or("Non UTF-8 string")?;
+    let shared_string = shared.path().to_str().ok_or("Non UTF-8 string")?;
+
+    Tester::new(CMD)
+        .args(&[
+            "-DNAME=World",
+            "--includes",
+            "--include-path",
+            shared_string,
+            "-i",
+            main_string,
+        ])
+        .stdout("Hello World!\nbody\nBye World!\n")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&[
+            "--list",
+            "--includes",
+            "--include-path",
+            shared_string,
+            "-i",
+            main_string,
+        ])
+        .stdout("NAME\nNAME\n")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["-DNAME=World", "--includes", "-i", main_string])
+        .stderr("Included file 'footer.txt' not found")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["-DNAME=World", "-i", main_string])
+        .stderr("Can not include 'header.txt': Includes are disabled")
+        .run_test()?;
+
+    let footer = shared.path().join("footer.txt");
+    let footer_string = footer.to_str().ok_or("Non UTF-8 string")?;
+    let include = format!("${{include:{footer_string}}}");
+    Tester::new(CMD)
+        .args(&["-DNAME=World", "--includes"])
+        .stdin(&include)
+        .stderr("Absolute paths and '..' are not allowed")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["-DNAME=World", "--includes", "--unrestricted-includes"])
+        .stdin(&include)
+        .stdout("Bye World!\n")
+        .run_test()
+}
+
+#[test]
+fn arithmetic() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&["--arithmetic", "-DBASE_PORT=8080"])
+        .stdin("web: ${BASE_PORT}\nsidecar: $((BASE_PORT + 1))\n")
+        .stdout("web: 8080\nsidecar: 8081\n")
+        .run_test()?;
+    Tester::new(CMD)
+        .args(&["--arithmetic", "-DBASE_PORT=8080"])
+        .stdin("$((BASE_PORT / (1 - 1)))\n")
+        .stderr("line 1: Arithmetic expression 'BASE_PORT / (1 - 1)': Division by zero")
+        .run_test()
+}
+
+#[test]
+#[cfg(unix)]
+fn commands() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&["--commands", "-DNAME=World"])
+        .stdin("Hello $(echo ${NAME} | tr a-z A-Z)!\n")
+        .stdout("Hello WORLD!\n")
+        .run_test()?;
+    Tester::new(CMD)
+        .stdin("Hello $(echo World)!\n")
+        .stdout("Hello $(echo World)!\n")
+        .run_test()?;
+    Tester::new(CMD)
+        .arg("--commands")
+        .stdin("a\n$(exit 1)\n")
+        .stderr("line 2: Command 'exit 1' failed with exit status: 1")
+        .run_test()
+}
+
+#[test]
+#[cfg(unix)]
+fn commands_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    Tester::new(CMD)
+        .args(&["--commands", "--command-timeout", "0"])
+        .stdin("$(sleep 5)\n")
+        .stderr("Command 'sleep 5' timed out after 0 seconds")
+        .run_test()
+}
+
 #[test]
 fn file_does_not_exist() -> Result<(), Box<dyn std::error::Error>> {
     Tester::new(CMD)
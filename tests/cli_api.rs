@@ -2,6 +2,9 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+// Not every test binary uses all of the helpers
+#![allow(dead_code)]
+
 use assert_cmd::Command; // Add methods on commands
 use predicates::prelude::*; // Used for writing assertions
 use std::{
@@ -21,8 +24,9 @@ pub struct Tester<'a, S = RandomState> {
 }
 
 impl<'a> Tester<'a> {
-    pub fn new(cmd: &'a str) -> Tester<'a> {
-        Tester {
+    #[must_use]
+    pub fn new(cmd: &'a str) -> Self {
+        Self {
             cmd,
             cwd: None,
             stdin: None,
@@ -34,49 +38,57 @@ impl<'a> Tester<'a> {
     }
 
     /// Set the working directory for the child process.
-    pub fn cwd(&'a mut self, dir: &'a str) -> &'a mut Tester<'a> {
+    pub const fn cwd(&'a mut self, dir: &'a str) -> &'a mut Self {
         self.cwd = Some(dir);
         self
     }
 
-    pub fn stdin(&'a mut self, text: &'a str) -> &'a mut Tester<'a> {
+    pub const fn stdin(&'a mut self, text: &'a str) -> &'a mut Self {
         self.stdin = Some(text);
         self
     }
 
     /// Add an argument to pass to the program.
-    pub fn env(&'a mut self, key: &'a str, value: &'a str) -> &'a mut Tester<'a> {
+    pub fn env(&'a mut self, key: &'a str, value: &'a str) -> &'a mut Self {
         self.env_vars.insert(key, value);
         self
     }
 
     /// Add an argument to pass to the program.
-    pub fn arg(&'a mut self, arg: &'a str) -> &'a mut Tester<'a> {
+    pub fn arg(&'a mut self, arg: &'a str) -> &'a mut Self {
         self.args.push(arg);
         self
     }
 
     /// Add multiple arguments to pass to the program.
-    pub fn args(&'a mut self, args: &[&'a str]) -> &'a mut Tester<'a> {
+    pub fn args(&'a mut self, args: &[&'a str]) -> &'a mut Self {
         self.args.extend_from_slice(args);
         self
     }
 
-    pub fn stdout(&'a mut self, text: &'a str) -> &'a mut Tester<'a> {
+    pub const fn stdout(&'a mut self, text: &'a str) -> &'a mut Self {
         self.stdout = Some(text);
         self
     }
 
-    pub fn stderr(&'a mut self, text: &'a str) -> &'a mut Tester<'a> {
+    pub const fn stderr(&'a mut self, text: &'a str) -> &'a mut Self {
         self.stderr = Some(text);
         self
     }
 
+    /// Runs the command and checks its output.
+    ///
+    /// # Errors
+    ///
+    /// If the command binary could not be found.
     pub fn run_test(&self) -> Result<(), Box<dyn std::error::Error>> {
         let mut cmd = Command::cargo_bin(self.cmd)?;
         cmd.env_clear();
 
         // Prepares the command
+        if let Some(cwd) = self.cwd {
+            cmd.current_dir(cwd);
+        }
         for (key, value) in &self.env_vars {
             cmd.env(key, value);
         }
@@ -104,6 +116,11 @@ impl<'a> Tester<'a> {
     }
 }
 
+/// Writes `text` into `file`.
+///
+/// # Panics
+///
+/// If writing failed.
 pub fn write_to_file(file: &Path, text: &str) {
     fs::write(file, text).expect("Unable to write file");
 }